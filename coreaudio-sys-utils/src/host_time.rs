@@ -0,0 +1,13 @@
+use coreaudio_sys::*;
+
+pub fn get_current_host_time() -> u64 {
+    unsafe { AudioGetCurrentHostTime() }
+}
+
+pub fn convert_host_time_to_nanos(host_time: u64) -> u64 {
+    unsafe { AudioConvertHostTimeToNanos(host_time) }
+}
+
+pub fn convert_nanos_to_host_time(nanos: u64) -> u64 {
+    unsafe { AudioConvertNanosToHostTime(nanos) }
+}
@@ -6,6 +6,7 @@ pub mod audio_object;
 pub mod audio_unit;
 pub mod cf_mutable_dict;
 pub mod dispatch;
+pub mod host_time;
 pub mod string;
 
 pub mod sys {
@@ -515,76 +515,33 @@ impl AggregateDevice {
         assert_ne!(output_id, kAudioObjectUnknown);
         assert_ne!(input_id, output_id);
 
-        let mut input_device_info = ffi::cubeb_device_info::default();
-        audiounit_create_device_from_hwdev(&mut input_device_info, input_id, DeviceType::INPUT);
-
-        let mut output_device_info = ffi::cubeb_device_info::default();
-        audiounit_create_device_from_hwdev(&mut output_device_info, output_id, DeviceType::OUTPUT);
-
-        let input_name_str = unsafe {
-            CString::from_raw(input_device_info.friendly_name as *mut c_char)
-                .into_string()
-                .expect("Fail to convert input name from CString into String")
-        };
-        input_device_info.friendly_name = ptr::null();
-
-        let output_name_str = unsafe {
-            CString::from_raw(output_device_info.friendly_name as *mut c_char)
-                .into_string()
-                .expect("Fail to convert output name from CString into String")
-        };
-        output_device_info.friendly_name = ptr::null();
-
-        let _teardown = finally(|| {
-            // Retrieve the rest lost memory.
-            // No need to retrieve the memory of {input,output}_device_info.friendly_name
-            // since they are already retrieved/retaken above.
-            assert!(input_device_info.friendly_name.is_null());
-            audiounit_device_destroy(&mut input_device_info);
-            assert!(output_device_info.friendly_name.is_null());
-            audiounit_device_destroy(&mut output_device_info);
-        });
+        let input_name_str =
+            get_device_label(input_id, kAudioDevicePropertyScopeInput).unwrap_or_default();
+        let output_name_str =
+            get_device_label(output_id, kAudioDevicePropertyScopeOutput).unwrap_or_default();
 
         if input_name_str.contains("AirPods") && output_name_str.contains("AirPods") {
-            let mut input_min_rate = 0;
-            let mut input_max_rate = 0;
-            let mut input_nominal_rate = 0;
-            audiounit_get_available_samplerate(
-                input_id,
-                kAudioObjectPropertyScopeGlobal,
-                &mut input_min_rate,
-                &mut input_max_rate,
-                &mut input_nominal_rate,
-            );
+            let input_rates = get_device_sample_rates(input_id, kAudioObjectPropertyScopeGlobal)
+                .unwrap_or_default();
             cubeb_log!(
-                "Input device {}, name: {}, min: {}, max: {}, nominal rate: {}",
+                "Input device {}, name: {}, rates: {:?}, nominal rate: {}",
                 input_id,
                 input_name_str,
-                input_min_rate,
-                input_max_rate,
-                input_nominal_rate
+                input_rates.ranges,
+                input_rates.default
             );
 
-            let mut output_min_rate = 0;
-            let mut output_max_rate = 0;
-            let mut output_nominal_rate = 0;
-            audiounit_get_available_samplerate(
-                output_id,
-                kAudioObjectPropertyScopeGlobal,
-                &mut output_min_rate,
-                &mut output_max_rate,
-                &mut output_nominal_rate,
-            );
+            let output_rates = get_device_sample_rates(output_id, kAudioObjectPropertyScopeGlobal)
+                .unwrap_or_default();
             cubeb_log!(
-                "Output device {}, name: {}, min: {}, max: {}, nominal rate: {}",
+                "Output device {}, name: {}, rates: {:?}, nominal rate: {}",
                 output_id,
                 output_name_str,
-                output_min_rate,
-                output_max_rate,
-                output_nominal_rate
+                output_rates.ranges,
+                output_rates.default
             );
 
-            let rate = f64::from(input_nominal_rate);
+            let rate = f64::from(input_rates.default);
             let addr = AudioObjectPropertyAddress {
                 mSelector: kAudioDevicePropertyNominalSampleRate,
                 mScope: kAudioObjectPropertyScopeGlobal,
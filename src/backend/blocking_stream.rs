@@ -0,0 +1,499 @@
+use super::coreaudio_sys_utils::sys::{dispatch_async_f, dispatch_queue_t};
+use super::ring_buffer::*;
+use super::utils::cubeb_sample_size;
+use super::{AudioUnitContext, AudioUnitStream, StreamCallbacks, StreamOptions};
+use cubeb_backend::{
+    DeviceId, Error, Result, SampleFormat, State, StreamOps, StreamParams, StreamParamsRef,
+};
+use std::mem;
+use std::os::raw::c_void;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// The longest a blocking read or write waits for the callbacks before checking the stream again.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+// The sample types that can be read from or written to a stream.
+pub trait Sample: Copy + Default + Send + 'static {
+    // Whether the samples in `format` can be accessed as this type on this platform.
+    fn is_compatible(format: SampleFormat) -> bool;
+}
+
+impl Sample for i16 {
+    fn is_compatible(format: SampleFormat) -> bool {
+        match format {
+            SampleFormat::S16NE => true,
+            SampleFormat::S16LE => cfg!(target_endian = "little"),
+            SampleFormat::S16BE => cfg!(target_endian = "big"),
+            _ => false,
+        }
+    }
+}
+
+impl Sample for f32 {
+    fn is_compatible(format: SampleFormat) -> bool {
+        match format {
+            SampleFormat::Float32NE => true,
+            SampleFormat::Float32LE => cfg!(target_endian = "little"),
+            SampleFormat::Float32BE => cfg!(target_endian = "big"),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockingOptions {
+    // The number of frames buffered between the application and the device, in each direction.
+    pub buffer_frames: u32,
+    // How long a read or a write waits for the data or the space in the buffer. None waits
+    // until the whole slice is transferred.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for BlockingOptions {
+    fn default() -> Self {
+        Self {
+            buffer_frames: 4096,
+            timeout: None,
+        }
+    }
+}
+
+// The states shared by the application thread and the callback threads.
+#[derive(Debug)]
+struct BlockingStreamStates {
+    error: AtomicBool,
+    // Input frames dropped because the application doesn't read fast enough.
+    dropped_input_frames: AtomicUsize,
+    // Output frames filled with silence because the application doesn't write fast enough.
+    missing_output_frames: AtomicUsize,
+    // The application thread waits here for the callbacks to move the data. It's notified from
+    // the serial queue, so the audio thread never wakes it by itself.
+    progress: Mutex<()>,
+    condvar: Condvar,
+    waiting: AtomicBool,
+    notify_scheduled: AtomicBool,
+    serial_queue: dispatch_queue_t,
+}
+
+// The serial queue can be used from any thread.
+unsafe impl Send for BlockingStreamStates {}
+unsafe impl Sync for BlockingStreamStates {}
+
+impl BlockingStreamStates {
+    fn new(serial_queue: dispatch_queue_t) -> Self {
+        Self {
+            error: AtomicBool::new(false),
+            dropped_input_frames: AtomicUsize::new(0),
+            missing_output_frames: AtomicUsize::new(0),
+            progress: Mutex::new(()),
+            condvar: Condvar::new(),
+            waiting: AtomicBool::new(false),
+            notify_scheduled: AtomicBool::new(false),
+            serial_queue,
+        }
+    }
+
+    // Wake the application thread if it waits. This neither blocks nor allocates, so it can be
+    // called on the audio thread.
+    fn signal(states: &Arc<Self>) {
+        if !states.waiting.load(Ordering::SeqCst)
+            || states.notify_scheduled.swap(true, Ordering::SeqCst)
+        {
+            return;
+        }
+        // Hand a reference to the serial queue without boxing a closure.
+        let ptr = Arc::into_raw(states.clone()) as *mut c_void;
+        unsafe {
+            dispatch_async_f(states.serial_queue, ptr, Some(notify_on_serial_queue));
+        }
+    }
+}
+
+extern "C" fn notify_on_serial_queue(ptr: *mut c_void) {
+    let states = unsafe { Arc::from_raw(ptr as *const BlockingStreamStates) };
+    states.notify_scheduled.store(false, Ordering::SeqCst);
+    let _guard = states.progress.lock().unwrap();
+    states.condvar.notify_all();
+}
+
+// Owned by the callbacks of the stream.
+#[derive(Debug)]
+struct BlockingCallbackData {
+    input: Option<(Producer<u8>, usize)>,
+    output: Option<(Consumer<u8>, usize)>,
+    states: Arc<BlockingStreamStates>,
+}
+
+impl BlockingCallbackData {
+    fn into_callbacks(mut self) -> StreamCallbacks {
+        let states = self.states.clone();
+        StreamCallbacks::new(
+            move |input, output, frames| Ok(self.process(input, output, frames)),
+            move |state| {
+                if state == State::Error {
+                    states.error.store(true, Ordering::SeqCst);
+                    BlockingStreamStates::signal(&states);
+                }
+            },
+        )
+    }
+
+    fn process(&mut self, input: &[u8], output: &mut [u8], frames: usize) -> usize {
+        if let (Some((producer, frame_size)), false) = (self.input.as_mut(), input.is_empty()) {
+            // Drop the input that doesn't fit, but never a partial frame.
+            let bytes = producer.available() / *frame_size * *frame_size;
+            let pushed = producer.push(&input[..bytes.min(input.len())]);
+            self.states
+                .dropped_input_frames
+                .fetch_add(frames - pushed / *frame_size, Ordering::SeqCst);
+        }
+
+        if let (Some((consumer, frame_size)), false) = (self.output.as_mut(), output.is_empty()) {
+            let popped = consumer.pop(output);
+            // Play silence when the application is running behind.
+            for byte in output[popped..].iter_mut() {
+                *byte = 0;
+            }
+            self.states
+                .missing_output_frames
+                .fetch_add(frames - popped / *frame_size, Ordering::SeqCst);
+        }
+
+        BlockingStreamStates::signal(&self.states);
+        frames
+    }
+}
+
+#[derive(Debug)]
+struct BlockingSide<T> {
+    buffer: T,
+    params: StreamParams,
+    frame_size: usize,
+}
+
+// A stream driven by the application, which reads the input and writes the output from its own
+// thread, instead of being called back by the device. The device callbacks exchange the data with
+// the application through the lock-free ring buffers.
+#[derive(Debug)]
+pub struct BlockingStream<'ctx> {
+    stream: Box<AudioUnitStream<'ctx>>,
+    input: Option<BlockingSide<Consumer<u8>>>,
+    output: Option<BlockingSide<Producer<u8>>>,
+    states: Arc<BlockingStreamStates>,
+    timeout: Option<Duration>,
+}
+
+impl<'ctx> BlockingStream<'ctx> {
+    pub fn new(
+        context: &'ctx AudioUnitContext,
+        input_device: DeviceId,
+        input_stream_params: Option<&StreamParamsRef>,
+        output_device: DeviceId,
+        output_stream_params: Option<&StreamParamsRef>,
+        latency_frames: u32,
+        options: BlockingOptions,
+    ) -> Result<Self> {
+        if options.buffer_frames == 0 {
+            cubeb_log!("The buffer of a blocking stream must not be empty.");
+            return Err(Error::invalid_parameter());
+        }
+
+        let states = Arc::new(BlockingStreamStates::new(context.serial_queue));
+        let mut callback_data = BlockingCallbackData {
+            input: None,
+            output: None,
+            states: states.clone(),
+        };
+
+        let input = input_stream_params.map(|params| {
+            let params = StreamParams::from(unsafe { *params.as_ptr() });
+            let frame_size = cubeb_sample_size(params.format()) * params.channels() as usize;
+            let (producer, consumer) =
+                ring_buffer::<u8>(options.buffer_frames as usize * frame_size);
+            callback_data.input = Some((producer, frame_size));
+            BlockingSide {
+                buffer: consumer,
+                params,
+                frame_size,
+            }
+        });
+
+        let output = output_stream_params.map(|params| {
+            let params = StreamParams::from(unsafe { *params.as_ptr() });
+            let frame_size = cubeb_sample_size(params.format()) * params.channels() as usize;
+            let (producer, consumer) =
+                ring_buffer::<u8>(options.buffer_frames as usize * frame_size);
+            callback_data.output = Some((consumer, frame_size));
+            BlockingSide {
+                buffer: producer,
+                params,
+                frame_size,
+            }
+        });
+
+        let stream = context.create_stream(
+            input_device,
+            input_stream_params,
+            output_device,
+            output_stream_params,
+            latency_frames,
+            |_| callback_data.into_callbacks(),
+            ptr::null_mut(),
+            StreamOptions::default(),
+        )?;
+
+        Ok(Self {
+            stream,
+            input,
+            output,
+            states,
+            timeout: options.timeout,
+        })
+    }
+
+    pub fn stream(&mut self) -> &mut AudioUnitStream<'ctx> {
+        &mut self.stream
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        self.stream.start()
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        self.stream.stop()
+    }
+
+    // Write the interleaved frames in `data` and return the number of the written frames, which
+    // is less than the frames in `data` if the timeout expires or the stream is not running. A
+    // stream that isn't started only takes the frames fitting in the buffer, without waiting,
+    // which pre-fills the output.
+    pub fn write<T: Sample>(&mut self, data: &[T]) -> Result<usize> {
+        let side = self.output.as_mut().ok_or_else(Error::invalid_parameter)?;
+        let bytes = to_bytes(data, &side.params)?;
+        let states = &self.states;
+        let frame_size = side.frame_size;
+        let buffer = &mut side.buffer;
+        let shutdown = &self.stream.shutdown;
+        let written = transfer(
+            bytes.len(),
+            frame_size,
+            self.timeout,
+            states,
+            shutdown,
+            |n| {
+                let available = buffer.available() / frame_size * frame_size;
+                let end = n + available.min(bytes.len() - n);
+                buffer.push(&bytes[n..end])
+            },
+        )?;
+        Ok(written / frame_size)
+    }
+
+    // Read the interleaved frames into `data` and return the number of the read frames, which
+    // is less than the frames in `data` if the timeout expires or the stream is not running. A
+    // stream that isn't started only gives the frames already buffered, without waiting.
+    pub fn read<T: Sample>(&mut self, data: &mut [T]) -> Result<usize> {
+        let side = self.input.as_mut().ok_or_else(Error::invalid_parameter)?;
+        let bytes = to_bytes_mut(data, &side.params)?;
+        let len = bytes.len();
+        let states = &self.states;
+        let frame_size = side.frame_size;
+        let buffer = &mut side.buffer;
+        let shutdown = &self.stream.shutdown;
+        let read = transfer(len, frame_size, self.timeout, states, shutdown, |n| {
+            let available = buffer.available() / frame_size * frame_size;
+            let end = n + available.min(len - n);
+            buffer.pop(&mut bytes[n..end])
+        })?;
+        Ok(read / frame_size)
+    }
+
+    // The number of the frames the application can write without waiting.
+    pub fn write_available(&self) -> usize {
+        self.output
+            .as_ref()
+            .map_or(0, |side| side.buffer.available() / side.frame_size)
+    }
+
+    // The number of the frames the application can read without waiting.
+    pub fn read_available(&self) -> usize {
+        self.input
+            .as_ref()
+            .map_or(0, |side| side.buffer.available() / side.frame_size)
+    }
+
+    pub fn dropped_input_frames(&self) -> usize {
+        self.states.dropped_input_frames.load(Ordering::SeqCst)
+    }
+
+    pub fn missing_output_frames(&self) -> usize {
+        self.states.missing_output_frames.load(Ordering::SeqCst)
+    }
+}
+
+// Run `step` until `len` bytes are transferred, the timeout expires or the stream stops running,
+// waiting for the callbacks in between. `step` gets the number of bytes transferred so far and
+// returns the bytes transferred this time.
+fn transfer<F>(
+    len: usize,
+    frame_size: usize,
+    timeout: Option<Duration>,
+    states: &BlockingStreamStates,
+    shutdown: &AtomicBool,
+    mut step: F,
+) -> Result<usize>
+where
+    F: FnMut(usize) -> usize,
+{
+    let start = Instant::now();
+    let mut transferred = 0;
+    loop {
+        if states.error.load(Ordering::SeqCst) {
+            return Err(Error::error());
+        }
+        transferred += step(transferred);
+        let remaining = timeout.map(|timeout| timeout.checked_sub(start.elapsed()));
+        if transferred == len || shutdown.load(Ordering::SeqCst) || remaining == Some(None) {
+            assert_eq!(transferred % frame_size, 0);
+            return Ok(transferred);
+        }
+
+        let mut guard = states.progress.lock().unwrap();
+        states.waiting.store(true, Ordering::SeqCst);
+        // Try again once the callbacks know about the waiter, so their progress isn't missed.
+        transferred += step(transferred);
+        if transferred < len && !states.error.load(Ordering::SeqCst) {
+            let wait = remaining.map_or(MAX_WAIT, |remaining| {
+                remaining.unwrap_or_default().min(MAX_WAIT)
+            });
+            guard = states.condvar.wait_timeout(guard, wait).unwrap().0;
+        }
+        states.waiting.store(false, Ordering::SeqCst);
+        drop(guard);
+    }
+}
+
+fn to_bytes<'a, T: Sample>(data: &'a [T], params: &StreamParams) -> Result<&'a [u8]> {
+    check_samples::<T>(data.len(), params)?;
+    Ok(unsafe {
+        slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * mem::size_of::<T>())
+    })
+}
+
+fn to_bytes_mut<'a, T: Sample>(data: &'a mut [T], params: &StreamParams) -> Result<&'a mut [u8]> {
+    check_samples::<T>(data.len(), params)?;
+    Ok(unsafe {
+        slice::from_raw_parts_mut(
+            data.as_mut_ptr() as *mut u8,
+            data.len() * mem::size_of::<T>(),
+        )
+    })
+}
+
+fn check_samples<T: Sample>(samples: usize, params: &StreamParams) -> Result<()> {
+    if !T::is_compatible(params.format()) {
+        return Err(Error::invalid_format());
+    }
+    // Only whole frames can be transferred.
+    if samples % params.channels() as usize != 0 {
+        return Err(Error::invalid_parameter());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sample_is_compatible() {
+    assert!(i16::is_compatible(SampleFormat::S16NE));
+    assert!(!i16::is_compatible(SampleFormat::Float32NE));
+    assert!(f32::is_compatible(SampleFormat::Float32NE));
+    assert!(!f32::is_compatible(SampleFormat::S16NE));
+    assert_eq!(
+        i16::is_compatible(SampleFormat::S16LE),
+        !i16::is_compatible(SampleFormat::S16BE)
+    );
+    assert_eq!(
+        f32::is_compatible(SampleFormat::Float32LE),
+        !f32::is_compatible(SampleFormat::Float32BE)
+    );
+}
+
+#[test]
+fn test_blocking_callback_data() {
+    let states = Arc::new(BlockingStreamStates::new(ptr::null_mut()));
+    // Stereo i16 input and output.
+    let frame_size = 4;
+    let (input_producer, mut input_consumer) = ring_buffer::<u8>(4 * frame_size);
+    let (mut output_producer, output_consumer) = ring_buffer::<u8>(4 * frame_size);
+    let mut data = BlockingCallbackData {
+        input: Some((input_producer, frame_size)),
+        output: Some((output_consumer, frame_size)),
+        states: states.clone(),
+    };
+
+    assert_eq!(output_producer.push(&[1; 8]), 8);
+
+    let input = [2_u8; 6 * 4];
+    let mut output = [0xff_u8; 6 * 4];
+    assert_eq!(data.process(&input, &mut output, 6), 6);
+
+    // 2 frames were written, the remaining 4 frames are silence.
+    assert!(output[..8].iter().all(|byte| *byte == 1));
+    assert!(output[8..].iter().all(|byte| *byte == 0));
+    assert_eq!(states.missing_output_frames.load(Ordering::SeqCst), 4);
+
+    // Only 4 frames fit in the input buffer.
+    let mut read = [0_u8; 6 * 4];
+    assert_eq!(input_consumer.pop(&mut read), 4 * frame_size);
+    assert_eq!(states.dropped_input_frames.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_transfer_without_running_stream() {
+    // The stream isn't started: only the bytes fitting right away are transferred.
+    let states = BlockingStreamStates::new(ptr::null_mut());
+    let shutdown = AtomicBool::new(true);
+    let mut steps = 0;
+    let transferred = transfer(16, 4, None, &states, &shutdown, |_| {
+        steps += 1;
+        8
+    });
+    assert_eq!(transferred, Ok(8));
+    assert_eq!(steps, 1);
+}
+
+#[test]
+fn test_transfer_waits_for_callbacks() {
+    use super::coreaudio_sys_utils::dispatch::{
+        create_dispatch_queue, release_dispatch_queue, DISPATCH_QUEUE_SERIAL,
+    };
+    use std::thread;
+
+    let serial_queue = create_dispatch_queue("blocking_stream.tests", DISPATCH_QUEUE_SERIAL);
+    let states = Arc::new(BlockingStreamStates::new(serial_queue));
+    let shutdown = AtomicBool::new(false);
+    let (mut producer, mut consumer) = ring_buffer::<u8>(16);
+
+    // Play the callbacks, delivering 4 bytes at a time.
+    let callback_states = states.clone();
+    let handle = thread::spawn(move || {
+        for _ in 0..4 {
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(producer.push(&[1; 4]), 4);
+            BlockingStreamStates::signal(&callback_states);
+        }
+    });
+
+    let mut data = [0_u8; 16];
+    let read = transfer(16, 4, None, &states, &shutdown, |n| {
+        let end = n + (consumer.available() / 4 * 4).min(16 - n);
+        consumer.pop(&mut data[n..end])
+    });
+    assert_eq!(read, Ok(16));
+    assert!(data.iter().all(|byte| *byte == 1));
+    handle.join().unwrap();
+    release_dispatch_queue(serial_queue);
+}
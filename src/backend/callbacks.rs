@@ -0,0 +1,280 @@
+use cubeb_backend::{ffi, DeviceType, Error, Result, State};
+use std::os::raw::{c_long, c_void};
+use std::ptr;
+
+// Get the input frames and render the output frames, both interleaved in the formats of the
+// stream parameters. The buffer of the side the stream doesn't have is empty. Return the number
+// of the frames rendered. Rendering less frames than asked drains the stream.
+pub type StreamDataCallback = dyn FnMut(&[u8], &mut [u8], usize) -> Result<usize> + Send;
+pub type StreamStateCallback = dyn FnMut(State) + Send;
+pub type DeviceChangedCallback = dyn FnMut() + Send;
+// Get the side whose device changed its nominal sample rate, and the new rate.
+pub type SampleRateChangedCallback = dyn FnMut(DeviceType, u32) + Send;
+
+// The callbacks of a stream. The C callbacks of the cubeb API are one kind of implementation,
+// adapted by `from_capi`.
+pub struct StreamCallbacks {
+    pub data: Box<StreamDataCallback>,
+    pub state: Box<StreamStateCallback>,
+}
+
+impl StreamCallbacks {
+    pub fn new<D, S>(data: D, state: S) -> Self
+    where
+        D: FnMut(&[u8], &mut [u8], usize) -> Result<usize> + Send + 'static,
+        S: FnMut(State) + Send + 'static,
+    {
+        Self {
+            data: Box::new(data),
+            state: Box::new(state),
+        }
+    }
+
+    // Without a data callback, the stream renders nothing.
+    pub fn from_capi(
+        stream: *mut ffi::cubeb_stream,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Self {
+        let pointers = CPointers { stream, user_ptr };
+        Self::new(
+            move |input, output, frames| {
+                let callback = match data_callback {
+                    Some(callback) => callback,
+                    None => return Ok(0),
+                };
+                let input_buffer = if input.is_empty() {
+                    ptr::null()
+                } else {
+                    input.as_ptr() as *const c_void
+                };
+                let output_buffer = if output.is_empty() {
+                    ptr::null_mut()
+                } else {
+                    output.as_mut_ptr() as *mut c_void
+                };
+                let rendered = unsafe {
+                    callback(
+                        pointers.stream,
+                        pointers.user_ptr,
+                        input_buffer,
+                        output_buffer,
+                        frames as c_long,
+                    )
+                };
+                if rendered < 0 {
+                    Err(Error::error())
+                } else {
+                    Ok(rendered as usize)
+                }
+            },
+            move |state| {
+                if let Some(callback) = state_callback {
+                    unsafe { callback(pointers.stream, pointers.user_ptr, state.into()) }
+                }
+            },
+        )
+    }
+}
+
+impl Default for StreamCallbacks {
+    // Render nothing and ignore the state changes.
+    fn default() -> Self {
+        Self::new(|_, _, _| Ok(0), |_| {})
+    }
+}
+
+impl std::fmt::Debug for StreamCallbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("StreamCallbacks").finish()
+    }
+}
+
+// Hold a callback registered on a stream, if any.
+pub struct CallbackSlot<C: ?Sized>(Option<Box<C>>);
+
+pub type DeviceChangedCallbackSlot = CallbackSlot<DeviceChangedCallback>;
+pub type SampleRateChangedCallbackSlot = CallbackSlot<SampleRateChangedCallback>;
+
+impl<C: ?Sized> CallbackSlot<C> {
+    // Register the callback, or unregister the current one if `callback` is None.
+    pub fn register(&mut self, callback: Option<Box<C>>) -> Result<()> {
+        // Note: second register without unregister first causes 'nope' error.
+        // Current implementation requires unregister before register a new cb.
+        if callback.is_some() && self.0.is_some() {
+            Err(Error::invalid_parameter())
+        } else {
+            self.0 = callback;
+            Ok(())
+        }
+    }
+}
+
+impl DeviceChangedCallbackSlot {
+    pub fn call(&mut self) {
+        if let Some(callback) = self.0.as_mut() {
+            callback();
+        }
+    }
+}
+
+impl SampleRateChangedCallbackSlot {
+    pub fn call(&mut self, side: DeviceType, rate: u32) {
+        if let Some(callback) = self.0.as_mut() {
+            callback(side, rate);
+        }
+    }
+}
+
+impl<C: ?Sized> Default for CallbackSlot<C> {
+    fn default() -> Self {
+        CallbackSlot(None)
+    }
+}
+
+impl<C: ?Sized> std::fmt::Debug for CallbackSlot<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("CallbackSlot")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+pub fn device_changed_callback_from_capi(
+    callback: ffi::cubeb_device_changed_callback,
+    user_ptr: *mut c_void,
+) -> Option<Box<DeviceChangedCallback>> {
+    let pointers = CPointers {
+        stream: ptr::null_mut(),
+        user_ptr,
+    };
+    callback.map(|callback| {
+        Box::new(move || unsafe { callback(pointers.user_ptr) }) as Box<DeviceChangedCallback>
+    })
+}
+
+// The pointers given by the C caller, who guarantees they are valid on the callback threads as
+// long as the stream is alive.
+#[derive(Clone, Copy)]
+struct CPointers {
+    stream: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+}
+
+unsafe impl Send for CPointers {}
+
+#[cfg(test)]
+extern "C" fn test_data_callback(
+    stream: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input_buffer: *const c_void,
+    output_buffer: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    assert_eq!(stream as usize, 0x1234);
+    assert!(input_buffer.is_null());
+    let calls = unsafe { &mut *(user_ptr as *mut u32) };
+    *calls += 1;
+    if output_buffer.is_null() {
+        -1
+    } else {
+        nframes / 2
+    }
+}
+
+#[cfg(test)]
+extern "C" fn test_state_callback(
+    _stream: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    state: ffi::cubeb_state,
+) {
+    assert_eq!(state, ffi::CUBEB_STATE_DRAINED);
+    let calls = unsafe { &mut *(user_ptr as *mut u32) };
+    *calls += 1;
+}
+
+#[test]
+fn test_stream_callbacks_from_capi() {
+    let mut calls: u32 = 0;
+    let user_ptr = &mut calls as *mut u32 as *mut c_void;
+    let stream = 0x1234 as *mut ffi::cubeb_stream;
+
+    // Nothing is rendered without a data callback.
+    let mut callbacks = StreamCallbacks::from_capi(stream, None, None, user_ptr);
+    assert_eq!((callbacks.data)(&[], &mut [0; 8], 8), Ok(0));
+    (callbacks.state)(State::Started);
+    assert_eq!(calls, 0);
+
+    let mut callbacks = StreamCallbacks::from_capi(
+        stream,
+        Some(test_data_callback),
+        Some(test_state_callback),
+        user_ptr,
+    );
+    let mut output = [0_u8; 8];
+    assert_eq!((callbacks.data)(&[], &mut output, 8), Ok(4));
+    // A negative value returned by the C callback is an error.
+    assert_eq!((callbacks.data)(&[], &mut [], 8), Err(Error::error()));
+    (callbacks.state)(State::Drained);
+    assert_eq!(calls, 3);
+
+    // The state callback is optional.
+    let mut callbacks =
+        StreamCallbacks::from_capi(stream, Some(test_data_callback), None, user_ptr);
+    (callbacks.state)(State::Drained);
+    assert_eq!(calls, 3);
+}
+
+#[test]
+fn test_device_changed_callback_slot() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut slot = DeviceChangedCallbackSlot::default();
+    // Nothing to call.
+    slot.call();
+
+    let counter = calls.clone();
+    assert!(slot
+        .register(Some(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })))
+        .is_ok());
+    slot.call();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Unregister before registering another one.
+    assert_eq!(
+        slot.register(Some(Box::new(|| {}))),
+        Err(Error::invalid_parameter())
+    );
+    assert!(slot.register(None).is_ok());
+    assert!(slot.register(Some(Box::new(|| {}))).is_ok());
+    slot.call();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_sample_rate_changed_callback_slot() {
+    use std::sync::{Arc, Mutex};
+
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let mut slot = SampleRateChangedCallbackSlot::default();
+    // Nothing to call.
+    slot.call(DeviceType::OUTPUT, 44_100);
+
+    let recorder = changes.clone();
+    assert!(slot
+        .register(Some(Box::new(move |side, rate| {
+            recorder.lock().unwrap().push((side, rate));
+        })))
+        .is_ok());
+    slot.call(DeviceType::INPUT, 48_000);
+    slot.call(DeviceType::OUTPUT, 96_000);
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![(DeviceType::INPUT, 48_000), (DeviceType::OUTPUT, 96_000)]
+    );
+}
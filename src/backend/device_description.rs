@@ -0,0 +1,317 @@
+use super::*;
+
+// The rates listed for the ranges of the device covering more than one rate.
+const STANDARD_SAMPLE_RATES: [u32; 13] = [
+    8_000, 11_025, 16_000, 22_050, 32_000, 44_100, 48_000, 88_200, 96_000, 176_400, 192_000,
+    352_800, 384_000,
+];
+
+// A range of the nominal rates supported by a device. A discrete rate is a range of one rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleRateRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl SampleRateRange {
+    pub fn contains(&self, rate: u32) -> bool {
+        self.min <= rate && rate <= self.max
+    }
+}
+
+// The description of a device, owned by Rust. It's converted into the C strings of
+// ffi::cubeb_device_info only when it's given to the collection of enumerate_devices, and the
+// collection is released by audiounit_device_destroy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub id: AudioObjectID,
+    pub uid: Option<String>,
+    pub group_id: Option<String>,
+    pub friendly_name: String,
+    pub vendor_name: Option<String>,
+    // INPUT, OUTPUT, or both for an in-out device described in one entry.
+    pub device_type: DeviceType,
+    // True if it's the default input, or the default output device. Only the types of the entry
+    // are set.
+    pub preferred_input: bool,
+    pub preferred_output: bool,
+    pub input_channels: u32,
+    pub output_channels: u32,
+    // The cubeb sample formats the device supports natively, as CUBEB_DEVICE_FMT_* flags.
+    pub formats: ffi::cubeb_device_fmt,
+    pub default_format: ffi::cubeb_device_fmt,
+    pub default_rate: u32,
+    // The nominal rates, as reported by the device.
+    pub sample_rate_ranges: Vec<SampleRateRange>,
+    pub latency_lo: u32,
+    pub latency_hi: u32,
+}
+
+impl DeviceInfo {
+    // The lowest supported rate, or 0 if the rates are unknown.
+    pub fn min_rate(&self) -> u32 {
+        self.sample_rate_ranges
+            .iter()
+            .map(|range| range.min)
+            .min()
+            .unwrap_or(0)
+    }
+
+    // The highest supported rate, or 0 if the rates are unknown.
+    pub fn max_rate(&self) -> u32 {
+        self.sample_rate_ranges
+            .iter()
+            .map(|range| range.max)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // True if it's the default device of every type of the entry. This is what the preference of
+    // ffi::cubeb_device_info reports, so an in-out entry isn't preferred when the device is the
+    // default of only one side.
+    pub fn is_preferred(&self) -> bool {
+        (!self.device_type.contains(DeviceType::INPUT) || self.preferred_input)
+            && (!self.device_type.contains(DeviceType::OUTPUT) || self.preferred_output)
+    }
+
+    pub fn supports_sample_rate(&self, rate: u32) -> bool {
+        self.sample_rate_ranges
+            .iter()
+            .any(|range| range.contains(rate))
+    }
+
+    // The sorted rates the device can run at: the discrete rates, and the standard rates within
+    // the continuous ranges.
+    pub fn sample_rates(&self) -> Vec<u32> {
+        let mut rates = Vec::new();
+        for range in &self.sample_rate_ranges {
+            if range.min == range.max {
+                rates.push(range.min);
+            } else {
+                rates.extend(
+                    STANDARD_SAMPLE_RATES
+                        .iter()
+                        .filter(|rate| range.contains(**rate)),
+                );
+            }
+        }
+        rates.sort();
+        rates.dedup();
+        rates
+    }
+}
+
+// Describe the output and the input sides of a device in one entry. The rates and the latencies
+// cover both sides.
+pub fn combine_device_infos(output: DeviceInfo, input: DeviceInfo) -> DeviceInfo {
+    assert_eq!(output.id, input.id);
+    assert_eq!(output.device_type, DeviceType::OUTPUT);
+    assert_eq!(input.device_type, DeviceType::INPUT);
+    let mut sample_rate_ranges = output.sample_rate_ranges;
+    for range in input.sample_rate_ranges {
+        if !sample_rate_ranges.contains(&range) {
+            sample_rate_ranges.push(range);
+        }
+    }
+    DeviceInfo {
+        id: output.id,
+        uid: output.uid,
+        group_id: output.group_id,
+        friendly_name: output.friendly_name,
+        vendor_name: output.vendor_name.or(input.vendor_name),
+        device_type: DeviceType::INPUT | DeviceType::OUTPUT,
+        preferred_input: input.preferred_input,
+        preferred_output: output.preferred_output,
+        input_channels: input.input_channels,
+        output_channels: output.output_channels,
+        formats: output.formats | input.formats,
+        default_format: output.default_format,
+        default_rate: output.default_rate,
+        sample_rate_ranges,
+        latency_lo: cmp::min(output.latency_lo, input.latency_lo),
+        latency_hi: cmp::max(output.latency_hi, input.latency_hi),
+    }
+}
+
+impl From<DeviceInfo> for ffi::cubeb_device_info {
+    fn from(info: DeviceInfo) -> Self {
+        assert!(
+            mem::size_of::<ffi::cubeb_devid>() >= mem::size_of_val(&info.id),
+            "cubeb_devid can't represent devid"
+        );
+        let mut device = ffi::cubeb_device_info::default();
+        device.devid = info.id as ffi::cubeb_devid;
+        // Every string gets its own allocation, released one by one in audiounit_device_destroy.
+        device.device_id = info.uid.map_or(ptr::null(), into_raw_c_string);
+        device.group_id = info.group_id.map_or(ptr::null(), into_raw_c_string);
+        device.friendly_name = into_raw_c_string(info.friendly_name);
+        device.vendor_name = info.vendor_name.map_or(ptr::null(), into_raw_c_string);
+        device.device_type = info.device_type.bits();
+        device.state = ffi::CUBEB_DEVICE_STATE_ENABLED;
+        device.preferred = if info.is_preferred() {
+            ffi::CUBEB_DEVICE_PREF_ALL
+        } else {
+            ffi::CUBEB_DEVICE_PREF_NONE
+        };
+        device.format = info.formats;
+        device.default_format = info.default_format;
+        device.max_channels = cmp::max(info.input_channels, info.output_channels);
+        device.default_rate = info.default_rate;
+        device.min_rate = info.min_rate();
+        device.max_rate = info.max_rate();
+        device.latency_lo = info.latency_lo;
+        device.latency_hi = info.latency_hi;
+        device
+    }
+}
+
+fn into_raw_c_string(string: String) -> *const c_char {
+    // The strings read from CoreAudio never contain a nul character.
+    CString::new(string)
+        .expect("Fail to create a C string from the device info")
+        .into_raw()
+}
+
+// Release the strings of a device info converted from DeviceInfo. The pointers are set to null,
+// so calling this twice is harmless.
+pub fn audiounit_device_destroy(device: &mut ffi::cubeb_device_info) {
+    for string in &mut [
+        &mut device.device_id,
+        &mut device.group_id,
+        &mut device.friendly_name,
+        &mut device.vendor_name,
+    ] {
+        if !string.is_null() {
+            unsafe {
+                let _ = CString::from_raw(**string as *mut c_char);
+            }
+            **string = ptr::null();
+        }
+    }
+}
+
+// A device info for the tests, to adjust with the struct update syntax.
+#[cfg(test)]
+pub fn test_device_info() -> DeviceInfo {
+    DeviceInfo {
+        id: kAudioObjectUnknown,
+        uid: Some(String::from("test: device id")),
+        group_id: Some(String::from("test: group id")),
+        friendly_name: String::from("test: friendly name"),
+        vendor_name: Some(String::from("test: vendor name")),
+        device_type: DeviceType::OUTPUT,
+        preferred_input: false,
+        preferred_output: false,
+        input_channels: 0,
+        output_channels: 2,
+        formats: ffi::CUBEB_DEVICE_FMT_F32NE | ffi::CUBEB_DEVICE_FMT_S16NE,
+        default_format: ffi::CUBEB_DEVICE_FMT_F32NE,
+        default_rate: 48_000,
+        sample_rate_ranges: vec![
+            SampleRateRange {
+                min: 44_100,
+                max: 48_000,
+            },
+            SampleRateRange {
+                min: 96_000,
+                max: 96_000,
+            },
+        ],
+        latency_lo: 128,
+        latency_hi: 4096,
+    }
+}
+
+#[test]
+fn test_combine_device_infos() {
+    let output = DeviceInfo {
+        vendor_name: None,
+        ..test_device_info()
+    };
+    let input = DeviceInfo {
+        friendly_name: String::from("Microphone"),
+        vendor_name: Some(String::from("vendor")),
+        device_type: DeviceType::INPUT,
+        preferred_input: true,
+        input_channels: 1,
+        output_channels: 0,
+        formats: ffi::CUBEB_DEVICE_FMT_F32BE,
+        default_format: ffi::CUBEB_DEVICE_FMT_F32BE,
+        default_rate: 44_100,
+        sample_rate_ranges: vec![SampleRateRange {
+            min: 8_000,
+            max: 48_000,
+        }],
+        latency_lo: 64,
+        latency_hi: 1024,
+        ..test_device_info()
+    };
+
+    let info = combine_device_infos(output, input);
+    assert_eq!(info.device_type, DeviceType::INPUT | DeviceType::OUTPUT);
+    assert_eq!(info.group_id, Some(String::from("test: group id")));
+    assert_eq!(info.friendly_name, "test: friendly name");
+    assert_eq!(info.vendor_name, Some(String::from("vendor")));
+    // The default input only, so the combined entry isn't preferred.
+    assert!(info.preferred_input && !info.preferred_output);
+    assert!(!info.is_preferred());
+    assert_eq!((info.input_channels, info.output_channels), (1, 2));
+    assert_eq!(
+        (info.default_rate, info.min_rate(), info.max_rate()),
+        (48_000, 8_000, 96_000)
+    );
+    assert_eq!(info.sample_rate_ranges.len(), 3);
+    assert_eq!((info.latency_lo, info.latency_hi), (64, 4096));
+
+    let device = ffi::cubeb_device_info::from(info);
+    assert_eq!(
+        device.device_type,
+        ffi::CUBEB_DEVICE_TYPE_INPUT | ffi::CUBEB_DEVICE_TYPE_OUTPUT
+    );
+    assert_eq!(device.max_channels, 2);
+    assert_eq!(
+        device.format,
+        ffi::CUBEB_DEVICE_FMT_F32NE | ffi::CUBEB_DEVICE_FMT_S16NE | ffi::CUBEB_DEVICE_FMT_F32BE
+    );
+    assert_eq!(device.default_format, ffi::CUBEB_DEVICE_FMT_F32NE);
+    assert_eq!(device.preferred, ffi::CUBEB_DEVICE_PREF_NONE);
+    let mut device = device;
+    audiounit_device_destroy(&mut device);
+}
+
+#[test]
+fn test_device_sample_rates() {
+    let mut info = DeviceInfo {
+        sample_rate_ranges: vec![
+            SampleRateRange {
+                min: 44_100,
+                max: 44_100,
+            },
+            SampleRateRange {
+                min: 96_000,
+                max: 96_000,
+            },
+        ],
+        ..test_device_info()
+    };
+    // The rates between the discrete ones aren't supported.
+    assert_eq!(info.sample_rates(), vec![44_100, 96_000]);
+    assert_eq!((info.min_rate(), info.max_rate()), (44_100, 96_000));
+    assert!(info.supports_sample_rate(96_000));
+    assert!(!info.supports_sample_rate(48_000));
+
+    // The standard rates are listed for a continuous range.
+    info.sample_rate_ranges.push(SampleRateRange {
+        min: 8_000,
+        max: 32_000,
+    });
+    assert_eq!(
+        info.sample_rates(),
+        vec![8_000, 11_025, 16_000, 22_050, 32_000, 44_100, 96_000]
+    );
+    assert!(info.supports_sample_rate(12_000));
+
+    info.sample_rate_ranges.clear();
+    assert!(info.sample_rates().is_empty());
+    assert_eq!((info.min_rate(), info.max_rate()), (0, 0));
+}
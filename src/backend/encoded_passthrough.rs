@@ -0,0 +1,196 @@
+use super::*;
+
+// The compressed formats sent untouched, in IEC 61937 bursts, to a receiver decoding them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncodedFormat {
+    Ac3,
+    EnhancedAc3,
+}
+
+impl EncodedFormat {
+    fn format_id(self) -> AudioFormatID {
+        match self {
+            EncodedFormat::Ac3 => kAudioFormat60958AC3,
+            EncodedFormat::EnhancedAc3 => kAudioFormatEnhancedAC3,
+        }
+    }
+}
+
+// An output stream of a device switched to the physical format of an encoded format. The
+// previous physical format is restored when dropped.
+#[derive(Debug)]
+pub struct EncodedPassthrough {
+    stream: AudioStreamID,
+    original_format: AudioStreamBasicDescription,
+}
+
+impl EncodedPassthrough {
+    // Switch the first output stream of the device supporting the format at the rate. The
+    // IEC 61937 bursts are then played as 16-bit stereo frames.
+    pub fn new(device: AudioObjectID, format: EncodedFormat, rate: f64) -> Result<Self> {
+        for stream in get_output_streams(device)? {
+            let physical_format = get_available_physical_formats(stream)?
+                .into_iter()
+                .find(|ranged| {
+                    ranged.mFormat.mFormatID == format.format_id()
+                        && ranged.mSampleRateRange.mMinimum <= rate
+                        && rate <= ranged.mSampleRateRange.mMaximum
+                })
+                .map(|ranged| AudioStreamBasicDescription {
+                    // A range may be given with a rate of kAudioStreamAnyRate.
+                    mSampleRate: rate,
+                    ..ranged.mFormat
+                });
+            let physical_format = match physical_format {
+                Some(physical_format) => physical_format,
+                None => continue,
+            };
+            let original_format = get_physical_format(stream)?;
+            set_physical_format(stream, &physical_format)?;
+            // The switch is asynchronous. Restore the previous format if it never happens.
+            let passthrough = Self {
+                stream,
+                original_format,
+            };
+            passthrough.wait_for_format(format.format_id())?;
+            cubeb_log!(
+                "Pass {:?} through stream {} of device {}: {:?}",
+                format,
+                stream,
+                device,
+                physical_format
+            );
+            return Ok(passthrough);
+        }
+        cubeb_log!(
+            "Device {} can't pass {:?} through at rate {}.",
+            device,
+            format,
+            rate
+        );
+        Err(Error::not_supported())
+    }
+
+    fn wait_for_format(&self, format_id: AudioFormatID) -> Result<()> {
+        const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+        const MAX_WAITS: u32 = 100;
+
+        for _ in 0..MAX_WAITS {
+            if get_physical_format(self.stream)?.mFormatID == format_id {
+                return Ok(());
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+        cubeb_log!(
+            "Stream {} didn't switch its physical format in time.",
+            self.stream
+        );
+        Err(Error::error())
+    }
+}
+
+impl Drop for EncodedPassthrough {
+    fn drop(&mut self) {
+        if set_physical_format(self.stream, &self.original_format).is_err() {
+            cubeb_log!(
+                "Could not restore the physical format of stream {}.",
+                self.stream
+            );
+        }
+    }
+}
+
+fn get_output_streams(device: AudioObjectID) -> Result<Vec<AudioStreamID>> {
+    let mut size = 0;
+    let r =
+        audio_object_get_property_data_size(device, &OUTPUT_STREAMS_PROPERTY_ADDRESS, &mut size);
+    if r != NO_ERR {
+        cubeb_log!(
+            "AudioObjectGetPropertyDataSize/kAudioDevicePropertyStreams rv={}, device id={}",
+            r,
+            device
+        );
+        return Err(Error::error());
+    }
+    let mut streams: Vec<AudioStreamID> = allocate_array_by_size(size);
+    let r = audio_object_get_property_data(
+        device,
+        &OUTPUT_STREAMS_PROPERTY_ADDRESS,
+        &mut size,
+        streams.as_mut_ptr(),
+    );
+    if r != NO_ERR {
+        cubeb_log!(
+            "AudioObjectGetPropertyData/kAudioDevicePropertyStreams rv={}, device id={}",
+            r,
+            device
+        );
+        return Err(Error::error());
+    }
+    Ok(streams)
+}
+
+fn get_available_physical_formats(
+    stream: AudioStreamID,
+) -> Result<Vec<AudioStreamRangedDescription>> {
+    let address = &AVAILABLE_PHYSICAL_FORMATS_PROPERTY_ADDRESS;
+    let mut size = 0;
+    let r = audio_object_get_property_data_size(stream, address, &mut size);
+    if r != NO_ERR {
+        cubeb_log!(
+            "AudioObjectGetPropertyDataSize/kAudioStreamPropertyAvailablePhysicalFormats rv={}, stream id={}",
+            r,
+            stream
+        );
+        return Err(Error::error());
+    }
+    let mut formats: Vec<AudioStreamRangedDescription> = allocate_array_by_size(size);
+    let r = audio_object_get_property_data(stream, address, &mut size, formats.as_mut_ptr());
+    if r != NO_ERR {
+        cubeb_log!(
+            "AudioObjectGetPropertyData/kAudioStreamPropertyAvailablePhysicalFormats rv={}, stream id={}",
+            r,
+            stream
+        );
+        return Err(Error::error());
+    }
+    Ok(formats)
+}
+
+fn get_physical_format(stream: AudioStreamID) -> Result<AudioStreamBasicDescription> {
+    let mut format = AudioStreamBasicDescription::default();
+    let mut size = mem::size_of::<AudioStreamBasicDescription>();
+    let r = audio_object_get_property_data(
+        stream,
+        &PHYSICAL_FORMAT_PROPERTY_ADDRESS,
+        &mut size,
+        &mut format,
+    );
+    if r != NO_ERR {
+        cubeb_log!(
+            "AudioObjectGetPropertyData/kAudioStreamPropertyPhysicalFormat rv={}, stream id={}",
+            r,
+            stream
+        );
+        return Err(Error::error());
+    }
+    Ok(format)
+}
+
+fn set_physical_format(stream: AudioStreamID, format: &AudioStreamBasicDescription) -> Result<()> {
+    let r = audio_object_set_property_data(
+        stream,
+        &PHYSICAL_FORMAT_PROPERTY_ADDRESS,
+        mem::size_of::<AudioStreamBasicDescription>(),
+        format,
+    );
+    if r != NO_ERR {
+        cubeb_log!(
+            "AudioObjectSetPropertyData/kAudioStreamPropertyPhysicalFormat rv={}, stream id={}",
+            r,
+            stream
+        );
+        return Err(Error::error());
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use super::*;
+
+// The exclusive access to a device, released when dropped. CoreAudio also releases it when the
+// process exits, so a crashed process doesn't keep the device.
+#[derive(Debug)]
+pub struct HogMode {
+    device: AudioObjectID,
+}
+
+impl HogMode {
+    pub fn acquire(device: AudioObjectID) -> Result<Self> {
+        let pid = process::id() as libc::pid_t;
+        match Self::get_owner(device)? {
+            owner if owner == pid => {
+                // Another stream of this process already owns the device. Releasing this one
+                // would release the device for both.
+                cubeb_log!("Device {} is already hogged by this process.", device);
+                return Err(Error::device_unavailable());
+            }
+            -1 => {}
+            owner => {
+                cubeb_log!("Device {} is hogged by process {}.", device, owner);
+                return Err(Error::device_unavailable());
+            }
+        }
+
+        let r = audio_object_set_property_data(
+            device,
+            &HOG_MODE_PROPERTY_ADDRESS,
+            mem::size_of::<libc::pid_t>(),
+            &pid,
+        );
+        if r != NO_ERR {
+            cubeb_log!(
+                "AudioObjectSetPropertyData/kAudioDevicePropertyHogMode rv={}, device id={}",
+                r,
+                device
+            );
+            return Err(Error::error());
+        }
+        // Another process may have taken it in the meantime.
+        let owner = Self::get_owner(device)?;
+        if owner != pid {
+            cubeb_log!("Device {} is hogged by process {}.", device, owner);
+            return Err(Error::device_unavailable());
+        }
+        Ok(Self { device })
+    }
+
+    fn get_owner(device: AudioObjectID) -> Result<libc::pid_t> {
+        let mut owner: libc::pid_t = -1;
+        let mut size = mem::size_of::<libc::pid_t>();
+        let r = audio_object_get_property_data(
+            device,
+            &HOG_MODE_PROPERTY_ADDRESS,
+            &mut size,
+            &mut owner,
+        );
+        if r != NO_ERR {
+            cubeb_log!(
+                "AudioObjectGetPropertyData/kAudioDevicePropertyHogMode rv={}, device id={}",
+                r,
+                device
+            );
+            return Err(Error::error());
+        }
+        Ok(owner)
+    }
+}
+
+impl Drop for HogMode {
+    fn drop(&mut self) {
+        let free: libc::pid_t = -1;
+        let r = audio_object_set_property_data(
+            self.device,
+            &HOG_MODE_PROPERTY_ADDRESS,
+            mem::size_of::<libc::pid_t>(),
+            &free,
+        );
+        if r != NO_ERR {
+            cubeb_log!(
+                "AudioObjectSetPropertyData/kAudioDevicePropertyHogMode rv={}, device id={}",
+                r,
+                self.device
+            );
+        }
+    }
+}
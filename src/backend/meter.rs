@@ -0,0 +1,257 @@
+use super::coreaudio_sys_utils::sys::*;
+use std::mem;
+use std::os::raw::c_void;
+use std::slice;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// The levels of a channel over the last integration window. The amplitudes are linear, where 1.0
+// is the full scale.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelLevel {
+    pub peak: f32,
+    pub rms: f32,
+    // The samples at or over the full scale since the meter was created or reset.
+    pub clipped_samples: u64,
+}
+
+#[derive(Debug, Default)]
+struct ChannelLevels {
+    // The bits of the f32 levels.
+    peak: AtomicU32,
+    rms: AtomicU32,
+    clipped_samples: AtomicU64,
+}
+
+// The levels of all the channels of one side of a stream, readable from any thread.
+#[derive(Clone, Debug)]
+pub struct LevelMeter {
+    channels: Arc<Vec<ChannelLevels>>,
+}
+
+impl LevelMeter {
+    fn new(channels: usize) -> Self {
+        Self {
+            channels: Arc::new((0..channels).map(|_| ChannelLevels::default()).collect()),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn level(&self, channel: usize) -> Option<ChannelLevel> {
+        self.channels.get(channel).map(|levels| ChannelLevel {
+            peak: f32::from_bits(levels.peak.load(Ordering::Relaxed)),
+            rms: f32::from_bits(levels.rms.load(Ordering::Relaxed)),
+            clipped_samples: levels.clipped_samples.load(Ordering::Relaxed),
+        })
+    }
+
+    pub fn levels(&self) -> Vec<ChannelLevel> {
+        (0..self.channels()).filter_map(|i| self.level(i)).collect()
+    }
+
+    pub fn reset_clipped_samples(&self) {
+        for levels in self.channels.iter() {
+            levels.clipped_samples.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// Measure the levels of the interleaved buffers on the callback thread and publish them to the
+// LevelMeter at the end of every integration window. This never allocates nor blocks.
+#[derive(Debug)]
+pub struct MeterAccumulator {
+    meter: LevelMeter,
+    float: bool,
+    window_frames: usize,
+    frames: usize,
+    peaks: Vec<f32>,
+    squares: Vec<f64>,
+    clipped_samples: Vec<u64>,
+}
+
+impl MeterAccumulator {
+    // Measure the buffers in the format of `desc`, which must be f32 or i16 in the native
+    // endianness. The meter of the `previous` accumulator is kept if it has the same channels, so
+    // the readers keep getting the levels after the stream is set up again.
+    pub fn new(
+        previous: Option<MeterAccumulator>,
+        desc: &AudioStreamBasicDescription,
+        window: Duration,
+    ) -> Self {
+        let channels = desc.mChannelsPerFrame as usize;
+        let float = desc.mFormatFlags & kAudioFormatFlagIsFloat != 0;
+        assert_eq!(
+            desc.mBitsPerChannel as usize,
+            if float {
+                mem::size_of::<f32>()
+            } else {
+                mem::size_of::<i16>()
+            } * 8
+        );
+        let meter = match previous {
+            Some(accumulator) if accumulator.meter.channels() == channels => accumulator.meter,
+            _ => LevelMeter::new(channels),
+        };
+        let seconds =
+            window.as_secs() as f64 + f64::from(window.subsec_nanos()) / 1_000_000_000_f64;
+        let window_frames = ((seconds * desc.mSampleRate).round() as usize).max(1);
+        Self {
+            meter,
+            float,
+            window_frames,
+            frames: 0,
+            peaks: vec![0.0; channels],
+            squares: vec![0.0; channels],
+            clipped_samples: vec![0; channels],
+        }
+    }
+
+    pub fn meter(&self) -> &LevelMeter {
+        &self.meter
+    }
+
+    pub fn process(&mut self, data: *const c_void, frames: usize) {
+        let samples = frames * self.peaks.len();
+        if data.is_null() || samples == 0 {
+            return;
+        }
+        if self.float {
+            let buffer = unsafe { slice::from_raw_parts(data as *const f32, samples) };
+            self.accumulate(buffer, |sample| {
+                let level = sample.abs();
+                (level, level >= 1.0)
+            });
+        } else {
+            let buffer = unsafe { slice::from_raw_parts(data as *const i16, samples) };
+            self.accumulate(buffer, |sample| {
+                let level = (f32::from(sample) / 32768.0).abs();
+                (
+                    level,
+                    sample == i16::max_value() || sample == i16::min_value(),
+                )
+            });
+        }
+    }
+
+    fn accumulate<T: Copy, F: Fn(T) -> (f32, bool)>(&mut self, buffer: &[T], measure: F) {
+        let channels = self.peaks.len();
+        for frame in buffer.chunks(channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let (level, clipped) = measure(*sample);
+                if level > self.peaks[channel] {
+                    self.peaks[channel] = level;
+                }
+                self.squares[channel] += f64::from(level) * f64::from(level);
+                if clipped {
+                    self.clipped_samples[channel] += 1;
+                }
+            }
+            self.frames += 1;
+            if self.frames == self.window_frames {
+                self.publish_window();
+            }
+        }
+        for (levels, clipped) in self
+            .meter
+            .channels
+            .iter()
+            .zip(self.clipped_samples.iter_mut())
+        {
+            if *clipped > 0 {
+                levels
+                    .clipped_samples
+                    .fetch_add(*clipped, Ordering::Relaxed);
+                *clipped = 0;
+            }
+        }
+    }
+
+    fn publish_window(&mut self) {
+        for (channel, levels) in self.meter.channels.iter().enumerate() {
+            let rms = (self.squares[channel] / self.frames as f64).sqrt() as f32;
+            levels
+                .peak
+                .store(self.peaks[channel].to_bits(), Ordering::Relaxed);
+            levels.rms.store(rms.to_bits(), Ordering::Relaxed);
+            self.peaks[channel] = 0.0;
+            self.squares[channel] = 0.0;
+        }
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+fn test_description(float: bool, channels: u32, rate: f64) -> AudioStreamBasicDescription {
+    let mut desc = AudioStreamBasicDescription::default();
+    desc.mFormatID = kAudioFormatLinearPCM;
+    desc.mFormatFlags = if float {
+        kAudioFormatFlagIsFloat
+    } else {
+        kAudioFormatFlagIsSignedInteger
+    };
+    desc.mBitsPerChannel = if float { 32 } else { 16 };
+    desc.mChannelsPerFrame = channels;
+    desc.mSampleRate = rate;
+    desc
+}
+
+#[test]
+fn test_meter_float() {
+    // A window of 4 frames.
+    let desc = test_description(true, 2, 1000.0);
+    let mut accumulator = MeterAccumulator::new(None, &desc, Duration::from_millis(4));
+    let meter = accumulator.meter().clone();
+    assert_eq!(meter.channels(), 2);
+    assert_eq!(meter.level(0), Some(ChannelLevel::default()));
+    assert_eq!(meter.level(2), None);
+
+    let data: [f32; 6] = [0.5, -1.0, -0.5, 0.25, 0.5, -0.25];
+    accumulator.process(data.as_ptr() as *const c_void, 3);
+    // The window isn't complete yet, but the clips are counted.
+    assert_eq!(meter.level(0).unwrap().peak, 0.0);
+    assert_eq!(meter.level(1).unwrap().clipped_samples, 1);
+
+    accumulator.process(data.as_ptr() as *const c_void, 1);
+    assert_eq!(
+        meter.levels(),
+        vec![
+            ChannelLevel {
+                peak: 0.5,
+                rms: 0.5,
+                clipped_samples: 0,
+            },
+            ChannelLevel {
+                peak: 1.0,
+                rms: (2.125_f64 / 4.0).sqrt() as f32,
+                clipped_samples: 2,
+            },
+        ]
+    );
+
+    meter.reset_clipped_samples();
+    assert_eq!(meter.level(1).unwrap().clipped_samples, 0);
+}
+
+#[test]
+fn test_meter_int16() {
+    let desc = test_description(false, 1, 1000.0);
+    let mut accumulator = MeterAccumulator::new(None, &desc, Duration::from_millis(2));
+    let meter = accumulator.meter().clone();
+    let data: [i16; 2] = [i16::min_value(), 16384];
+    accumulator.process(data.as_ptr() as *const c_void, 2);
+    let level = meter.level(0).unwrap();
+    assert_eq!(level.peak, 1.0);
+    assert_eq!(level.clipped_samples, 1);
+
+    // The meter is kept with the same channels, and replaced otherwise.
+    let accumulator = MeterAccumulator::new(Some(accumulator), &desc, Duration::from_millis(2));
+    assert_eq!(accumulator.meter().level(0).unwrap().clipped_samples, 1);
+    let desc = test_description(false, 2, 1000.0);
+    let accumulator = MeterAccumulator::new(Some(accumulator), &desc, Duration::from_millis(2));
+    assert_eq!(accumulator.meter().channels(), 2);
+    assert_eq!(meter.channels(), 1);
+}
@@ -57,6 +57,10 @@ impl Mixer {
         }
     }
 
+    pub fn input_channels(&self) -> u32 {
+        self.in_channels
+    }
+
     pub fn get_buffer_mut_ptr(&mut self) -> *mut u8 {
         self.buffer.as_mut_ptr()
     }
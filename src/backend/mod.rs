@@ -11,25 +11,58 @@ extern crate libc;
 mod aggregate_device;
 mod auto_array;
 mod auto_release;
+mod blocking_stream;
+mod callbacks;
+mod device_description;
+mod encoded_passthrough;
+mod hog_mode;
+mod meter;
 mod mixer;
+mod output_tap;
 mod property_address;
+mod render_quantum;
 mod resampler;
+mod ring_buffer;
+mod typed_stream;
 mod utils;
+mod wav_capture;
+
+pub use self::blocking_stream::{BlockingOptions, BlockingStream, Sample};
+pub use self::callbacks::{
+    DeviceChangedCallback, SampleRateChangedCallback, StreamCallbacks, StreamDataCallback,
+    StreamStateCallback,
+};
+pub use self::device_description::{DeviceInfo, SampleRateRange};
+pub use self::encoded_passthrough::EncodedFormat;
+pub use self::meter::{ChannelLevel, LevelMeter};
+pub use self::output_tap::{OutputTap, TapFormat};
+pub use self::typed_stream::{
+    DataCallback, Events, NextEvent, StreamBuilder, StreamEvent, TypedStream,
+};
 
 use self::aggregate_device::*;
 use self::auto_array::*;
 use self::auto_release::*;
+use self::callbacks::*;
 use self::coreaudio_sys_utils::aggregate_device::*;
 use self::coreaudio_sys_utils::audio_object::*;
 use self::coreaudio_sys_utils::audio_unit::*;
 use self::coreaudio_sys_utils::cf_mutable_dict::*;
 use self::coreaudio_sys_utils::dispatch::*;
+use self::coreaudio_sys_utils::host_time::*;
 use self::coreaudio_sys_utils::string::*;
 use self::coreaudio_sys_utils::sys::*;
+use self::device_description::*;
+use self::encoded_passthrough::*;
+use self::hog_mode::*;
+use self::meter::*;
 use self::mixer::*;
+use self::output_tap::*;
 use self::property_address::*;
+use self::render_quantum::*;
 use self::resampler::*;
 use self::utils::*;
+use self::wav_capture::*;
 use atomic;
 use cubeb_backend::{
     ffi, ChannelLayout, Context, ContextOps, DeviceCollectionRef, DeviceId, DeviceRef, DeviceType,
@@ -37,14 +70,19 @@ use cubeb_backend::{
     StreamPrefs,
 };
 use std::cmp;
+use std::collections::HashMap;
+use std::env;
 use std::ffi::{CStr, CString};
 use std::fmt;
 use std::mem;
-use std::os::raw::{c_char, c_void};
+use std::os::raw::{c_char, c_long, c_void};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::ptr;
 use std::slice;
-use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
 use std::time::Duration;
 
 const NO_ERR: OSStatus = 0;
@@ -57,6 +95,7 @@ const PRIVATE_AGGREGATE_DEVICE_NAME: &str = "CubebAggregateDevice";
 
 // Testing empirically, some headsets report a minimal latency that is very low,
 // but this does not work in practice. Lie and say the minimum is 256 frames.
+// These are the default bounds. They can be changed by `set_latency_bounds` on the context.
 const SAFE_MIN_LATENCY_FRAMES: u32 = 256;
 const SAFE_MAX_LATENCY_FRAMES: u32 = 512;
 
@@ -224,11 +263,9 @@ fn set_notification_runloop() {
     }
 }
 
-fn clamp_latency(latency_frames: u32) -> u32 {
-    cmp::max(
-        cmp::min(latency_frames, SAFE_MAX_LATENCY_FRAMES),
-        SAFE_MIN_LATENCY_FRAMES,
-    )
+fn clamp_latency(latency_frames: u32, min_frames: u32, max_frames: u32) -> u32 {
+    assert!(min_frames <= max_frames);
+    cmp::max(cmp::min(latency_frames, max_frames), min_frames)
 }
 
 fn create_device_info(id: AudioDeviceID, devtype: DeviceType) -> Result<device_info> {
@@ -260,6 +297,20 @@ fn create_device_info(id: AudioDeviceID, devtype: DeviceType) -> Result<device_i
     Ok(info)
 }
 
+// Get the devices whose buffer frame size will be set by the stream.
+fn get_latency_devices(
+    input_device: Option<&device_info>,
+    output_device: Option<&device_info>,
+) -> Vec<AudioDeviceID> {
+    let mut devices = Vec::new();
+    for device in input_device.iter().chain(output_device.iter()) {
+        if !devices.contains(&device.id) {
+            devices.push(device.id);
+        }
+    }
+    devices
+}
+
 fn create_stream_description(stream_params: &StreamParams) -> Result<AudioStreamBasicDescription> {
     assert!(stream_params.rate() > 0);
     assert!(stream_params.channels() > 0);
@@ -332,6 +383,44 @@ fn create_auto_array(
     Err(Error::invalid_format())
 }
 
+// True if the samples in the format of `desc` can be given to the hardware as they are.
+fn is_bit_perfect_description(
+    desc: &AudioStreamBasicDescription,
+    hw_desc: &AudioStreamBasicDescription,
+) -> bool {
+    desc.mFormatID == hw_desc.mFormatID
+        && desc.mFormatFlags == hw_desc.mFormatFlags
+        && desc.mBitsPerChannel == hw_desc.mBitsPerChannel
+        && desc.mChannelsPerFrame == hw_desc.mChannelsPerFrame
+        && desc.mBytesPerFrame == hw_desc.mBytesPerFrame
+        && desc.mSampleRate == hw_desc.mSampleRate
+}
+
+// The cubeb sample format of the samples in the format of `desc`, if it has one. This is the
+// inverse of create_stream_description.
+fn get_device_format(desc: &AudioStreamBasicDescription) -> Option<ffi::cubeb_device_fmt> {
+    if desc.mFormatID != kAudioFormatLinearPCM {
+        return None;
+    }
+    let big_endian = desc.mFormatFlags & kAudioFormatFlagIsBigEndian != 0;
+    if desc.mFormatFlags & kAudioFormatFlagIsFloat != 0 && desc.mBitsPerChannel == 32 {
+        Some(if big_endian {
+            ffi::CUBEB_DEVICE_FMT_F32BE
+        } else {
+            ffi::CUBEB_DEVICE_FMT_F32LE
+        })
+    } else if desc.mFormatFlags & kAudioFormatFlagIsSignedInteger != 0 && desc.mBitsPerChannel == 16
+    {
+        Some(if big_endian {
+            ffi::CUBEB_DEVICE_FMT_S16BE
+        } else {
+            ffi::CUBEB_DEVICE_FMT_S16LE
+        })
+    } else {
+        None
+    }
+}
+
 fn set_volume(unit: AudioUnit, volume: f32) -> Result<()> {
     assert!(!unit.is_null());
     let r = audio_unit_set_parameter(
@@ -377,6 +466,50 @@ fn minimum_resampling_input_frames(input_rate: f64, output_rate: f64, output_fra
     (input_rate * output_frames as f64 / output_rate).ceil() as i64
 }
 
+// Get the time of the timestamp in nanoseconds, or the current time if the timestamp
+// doesn't carry a valid host time.
+fn get_host_time_in_nanos(tstamp: *const AudioTimeStamp) -> u64 {
+    let host_time =
+        if !tstamp.is_null() && unsafe { (*tstamp).mFlags } & kAudioTimeStampHostTimeValid != 0 {
+            unsafe { (*tstamp).mHostTime }
+        } else {
+            get_current_host_time()
+        };
+    convert_host_time_to_nanos(host_time)
+}
+
+// The number of frames at `rate` from the time stamp of a buffer to the scheduled start host time,
+// or 0 if the start time is reached.
+fn frames_before_start(start_host_time: u64, tstamp: *const AudioTimeStamp, rate: f64) -> u64 {
+    let start_ns = convert_host_time_to_nanos(start_host_time);
+    let buffer_ns = get_host_time_in_nanos(tstamp);
+    if start_ns <= buffer_ns {
+        return 0;
+    }
+    ((start_ns - buffer_ns) as f64 * rate / 1_000_000_000_f64).round() as u64
+}
+
+// Estimate the frames played at `now_ns`, by extrapolating the frames played at the last output
+// callback, so the clock doesn't only move forward once per callback when the buffer is large.
+// The estimation never goes beyond the frames that have been queued to the device.
+fn interpolate_frames_played(
+    frames_played: u64,
+    frames_queued: u64,
+    frames_played_time_ns: u64,
+    now_ns: u64,
+    rate: u32,
+) -> u64 {
+    if frames_played_time_ns == 0
+        || now_ns <= frames_played_time_ns
+        || frames_queued <= frames_played
+    {
+        return frames_played;
+    }
+    let elapsed_frames =
+        (now_ns - frames_played_time_ns) as f64 * f64::from(rate) / 1_000_000_000_f64;
+    cmp::min(frames_played + elapsed_frames as u64, frames_queued)
+}
+
 fn audiounit_make_silent(io_data: &mut AudioBuffer) {
     assert!(!io_data.mData.is_null());
     let bytes = unsafe {
@@ -432,6 +565,20 @@ extern "C" fn audiounit_input_callback(
         input_buffer_list.mBuffers[0].mNumberChannels =
             stm.core_stream_data.input_desc.mChannelsPerFrame;
         input_buffer_list.mNumberBuffers = 1;
+        // Render the device channels into the mixer, if any. Its buffers are sized in setup.
+        if let Some(mixer) = stm.core_stream_data.input_mixer.as_mut() {
+            if input_frames > stm.core_stream_data.max_frames_per_slice {
+                return (
+                    ErrorHandle::Return(kAudioUnitErr_TooManyFramesToProcess),
+                    None,
+                );
+            }
+            let channels = mixer.input_channels();
+            input_buffer_list.mBuffers[0].mDataByteSize =
+                stm.core_stream_data.input_desc.mBitsPerChannel / 8 * channels * input_frames;
+            input_buffer_list.mBuffers[0].mData = mixer.get_buffer_mut_ptr() as *mut c_void;
+            input_buffer_list.mBuffers[0].mNumberChannels = channels;
+        }
 
         assert!(!stm.core_stream_data.input_unit.is_null());
         let status = audio_unit_render(
@@ -469,6 +616,21 @@ extern "C" fn audiounit_input_callback(
             ErrorHandle::Reinit
         } else {
             assert_eq!(status, NO_ERR);
+            let input_bytes =
+                input_frames as usize * stm.core_stream_data.input_desc.mBytesPerFrame as usize;
+            // Mix the device channels to the channels of the stream.
+            let input_data = match stm.core_stream_data.input_mixer.as_mut() {
+                None => input_buffer_list.mBuffers[0].mData,
+                Some(mixer) => {
+                    let buffer = &mut stm.core_stream_data.input_mixer_buffer[..input_bytes];
+                    mixer.mix(
+                        input_frames as usize,
+                        buffer.as_mut_ptr() as *mut c_void,
+                        input_bytes,
+                    );
+                    buffer.as_mut_ptr() as *mut c_void
+                }
+            };
             // Copy input data in linear buffer.
             let elements =
                 (input_frames * stm.core_stream_data.input_desc.mChannelsPerFrame) as usize;
@@ -476,7 +638,14 @@ extern "C" fn audiounit_input_callback(
                 .input_linear_buffer
                 .as_mut()
                 .unwrap()
-                .push(input_buffer_list.mBuffers[0].mData, elements);
+                .push(input_data, elements);
+            if let Some(capture) = stm.core_stream_data.debug_capture.as_mut() {
+                let data = unsafe { slice::from_raw_parts(input_data as *const u8, input_bytes) };
+                capture.write_input(data);
+            }
+            if let Some(meter) = stm.core_stream_data.input_meter.as_mut() {
+                meter.process(input_data, input_frames as usize);
+            }
             ErrorHandle::Return(status)
         };
 
@@ -504,6 +673,27 @@ extern "C" fn audiounit_input_callback(
             return (handle, None);
         }
 
+        // Drop the input captured before the scheduled start time, if any.
+        let start_host_time = stm.start_host_time.load(Ordering::SeqCst);
+        if start_host_time != 0 {
+            // The input frames are at the rate of the input device.
+            let frames = frames_before_start(
+                start_host_time,
+                tstamp,
+                stm.core_stream_data.input_hw_rate.load(Ordering::SeqCst),
+            );
+            let input_buffer = stm.core_stream_data.input_linear_buffer.as_mut().unwrap();
+            if frames >= u64::from(input_frames) {
+                input_buffer.clear();
+                return (handle, None);
+            }
+            stm.start_host_time.store(0, Ordering::SeqCst);
+            // Only the frames of this callback are in the buffer.
+            let elements =
+                frames as usize * stm.core_stream_data.input_desc.mChannelsPerFrame as usize;
+            assert!(input_buffer.pop(elements));
+        }
+
         // Input only. Call the user callback through resampler.
         // Resampler will deliver input buffer in the correct rate.
         assert!(
@@ -576,7 +766,7 @@ extern "C" fn audiounit_input_callback(
 extern "C" fn audiounit_output_callback(
     user_ptr: *mut c_void,
     _: *mut AudioUnitRenderActionFlags,
-    _tstamp: *const AudioTimeStamp,
+    tstamp: *const AudioTimeStamp,
     bus: u32,
     output_frames: u32,
     out_buffer_list: *mut AudioBufferList,
@@ -611,12 +801,55 @@ extern "C" fn audiounit_output_callback(
     }
 
     if stm.draining.load(Ordering::SeqCst) {
+        // The last frames rendered by the user callback may still be in flight. Keep feeding
+        // silence until they have gone through the device latency before notifying drained.
+        audiounit_make_silent(&mut buffers[0]);
+        let remaining_frames = stm
+            .drain_frames_remaining
+            .fetch_sub(i64::from(output_frames), Ordering::SeqCst)
+            - i64::from(output_frames);
+        if remaining_frames > 0 {
+            cubeb_logv!(
+                "({:p}) output: draining, {} frames remaining.",
+                stm as *const AudioUnitStream,
+                remaining_frames
+            );
+            return NO_ERR;
+        }
+        stm.frames_played
+            .store(stm.frames_queued.load(Ordering::SeqCst), Ordering::SeqCst);
         stm.core_stream_data.stop_audiounits();
         stm.notify_state_changed(State::Drained);
-        audiounit_make_silent(&mut buffers[0]);
         return NO_ERR;
     }
 
+    // Wait for the scheduled start time, if any.
+    let mut silent_frames = 0;
+    let start_host_time = stm.start_host_time.load(Ordering::SeqCst);
+    if start_host_time != 0 {
+        let frames = frames_before_start(
+            start_host_time,
+            tstamp,
+            stm.core_stream_data.output_desc.mSampleRate,
+        );
+        if frames >= u64::from(output_frames) {
+            audiounit_make_silent(&mut buffers[0]);
+            if !stm.core_stream_data.input_unit.is_null() {
+                // Drop the input until the start time, so the duplex stream starts like a new one.
+                stm.core_stream_data
+                    .input_linear_buffer
+                    .as_mut()
+                    .unwrap()
+                    .clear();
+                stm.frames_read.store(0, Ordering::SeqCst);
+                stm.frames_written.store(0, Ordering::SeqCst);
+            }
+            return NO_ERR;
+        }
+        stm.start_host_time.store(0, Ordering::SeqCst);
+        silent_frames = frames as u32;
+    }
+
     let handler = |stm: &mut AudioUnitStream,
                    output_frames: u32,
                    buffers: &mut [AudioBuffer]|
@@ -655,7 +888,7 @@ extern "C" fn audiounit_output_callback(
             // fact that we're lacking some input data.
             let frames_written = stm.frames_written.load(Ordering::SeqCst);
             let input_frames_needed = minimum_resampling_input_frames(
-                stm.core_stream_data.input_hw_rate,
+                stm.core_stream_data.input_hw_rate.load(Ordering::SeqCst),
                 f64::from(stm.core_stream_data.output_stream_params.rate()),
                 frames_written,
             );
@@ -695,18 +928,29 @@ extern "C" fn audiounit_output_callback(
             (ptr::null_mut::<c_void>(), 0)
         };
 
-        // Call user callback through resampler.
+        // Call user callback through resampler, or directly into the device buffer.
         assert!(!output_buffer.is_null());
-        let outframes = stm.core_stream_data.resampler.fill(
-            input_buffer,
-            if input_buffer.is_null() {
-                ptr::null_mut()
-            } else {
-                &mut input_frames
-            },
-            output_buffer,
-            i64::from(output_frames),
-        );
+        let outframes = if stm.core_stream_data.passthrough {
+            // The output only stream has the format of the device, checked by setup.
+            audiounit_stream_data_callback(
+                stm.core_stream_data.stm_ptr as *mut ffi::cubeb_stream,
+                stm.core_stream_data.stm_ptr as *mut c_void,
+                ptr::null(),
+                output_buffer,
+                i64::from(output_frames),
+            )
+        } else {
+            stm.core_stream_data.resampler.fill(
+                input_buffer,
+                if input_buffer.is_null() {
+                    ptr::null_mut()
+                } else {
+                    &mut input_frames
+                },
+                output_buffer,
+                i64::from(output_frames),
+            )
+        };
         if !input_buffer.is_null() {
             // Pop from the buffer the frames used by the the resampler.
             let elements =
@@ -726,9 +970,14 @@ extern "C" fn audiounit_output_callback(
         }
 
         *stm.draining.get_mut() = outframes < i64::from(output_frames);
-        stm.frames_played
-            .store(stm.frames_queued, atomic::Ordering::SeqCst);
-        stm.frames_queued += outframes as u64;
+        stm.frames_played.store(
+            stm.frames_queued.load(Ordering::SeqCst),
+            atomic::Ordering::SeqCst,
+        );
+        stm.frames_queued
+            .fetch_add(outframes as u64, Ordering::SeqCst);
+        stm.frames_played_time
+            .store(get_host_time_in_nanos(tstamp), Ordering::SeqCst);
 
         let outaff = stm.core_stream_data.output_desc.mFormatFlags;
         let panning = if stm.core_stream_data.output_desc.mChannelsPerFrame == 2 {
@@ -739,6 +988,12 @@ extern "C" fn audiounit_output_callback(
 
         // Post process output samples.
         if stm.draining.load(Ordering::SeqCst) {
+            // The rendered frames of this buffer, plus the device latency, are still to be
+            // played before the stream is drained. Both are at the stream rate.
+            stm.drain_frames_remaining.store(
+                outframes + i64::from(stm.drain_latency_frames.load(Ordering::SeqCst)),
+                Ordering::SeqCst,
+            );
             // Clear missing frames (silence)
             let count_bytes = |frames: usize| -> usize {
                 let sample_size =
@@ -757,6 +1012,14 @@ extern "C" fn audiounit_output_callback(
             }
         }
 
+        if let Some(capture) = stm.core_stream_data.debug_capture.as_mut() {
+            let len = output_frames as usize
+                * stm.core_stream_data.output_stream_params.channels() as usize
+                * cubeb_sample_size(stm.core_stream_data.output_stream_params.format());
+            let data = unsafe { slice::from_raw_parts(output_buffer as *const u8, len) };
+            capture.write_callback_output(data);
+        }
+
         // Mixing
         if stm.core_stream_data.mixer.is_none() {
             // Pan stereo.
@@ -789,10 +1052,45 @@ extern "C" fn audiounit_output_callback(
             );
         }
 
+        // Copy the final output to the tap. Never wait for the observer on this thread.
+        if let Ok(mut tap) = stm.output_tap.try_lock() {
+            if let Some(sender) = tap.as_mut() {
+                let len =
+                    (stm.core_stream_data.output_desc.mBytesPerFrame * output_frames) as usize;
+                assert!(len <= buffers[0].mDataByteSize as usize);
+                let data = unsafe { slice::from_raw_parts(buffers[0].mData as *const u8, len) };
+                sender.send(data);
+            }
+        }
+
+        if let Some(meter) = stm.core_stream_data.output_meter.as_mut() {
+            meter.process(buffers[0].mData, output_frames as usize);
+        }
+
+        if let Some(capture) = stm.core_stream_data.debug_capture.as_mut() {
+            let len = (stm.core_stream_data.output_desc.mBytesPerFrame * output_frames) as usize;
+            let data = unsafe { slice::from_raw_parts(buffers[0].mData as *const u8, len) };
+            capture.write_device_output(data);
+        }
+
         (NO_ERR, None)
     };
 
-    let (status, notification) = handler(stm, output_frames, &mut buffers);
+    let (status, notification) = if silent_frames > 0 {
+        // The data starts at the frame of the start time, in the middle of this buffer.
+        let silent_bytes = stm.core_stream_data.output_desc.mBytesPerFrame * silent_frames;
+        assert!(silent_bytes < buffers[0].mDataByteSize);
+        let data = buffers[0].mData as *mut u8;
+        unsafe { ptr::write_bytes(data, 0, silent_bytes as usize) };
+        let mut rest = [AudioBuffer {
+            mNumberChannels: buffers[0].mNumberChannels,
+            mDataByteSize: buffers[0].mDataByteSize - silent_bytes,
+            mData: unsafe { data.add(silent_bytes as usize) } as *mut c_void,
+        }];
+        handler(stm, output_frames - silent_frames, &mut rest)
+    } else {
+        handler(stm, output_frames, &mut buffers)
+    };
     if let Some(state) = notification {
         stm.notify_state_changed(state);
     }
@@ -880,12 +1178,7 @@ extern "C" fn audiounit_property_listener_callback(
     }
 
     for _addr in addrs.iter() {
-        let callback = stm.device_changed_callback.lock().unwrap();
-        if let Some(device_changed_callback) = *callback {
-            unsafe {
-                device_changed_callback(stm.user_ptr);
-            }
-        }
+        stm.device_changed_callback.lock().unwrap().call();
     }
 
     stm.reinit_async();
@@ -893,6 +1186,92 @@ extern "C" fn audiounit_property_listener_callback(
     NO_ERR
 }
 
+extern "C" fn audiounit_sample_rate_listener_callback(
+    id: AudioObjectID,
+    _address_count: u32,
+    _addresses: *const AudioObjectPropertyAddress,
+    user: *mut c_void,
+) -> OSStatus {
+    let stm = unsafe { &mut *(user as *mut AudioUnitStream) };
+    let rate = match get_device_nominal_sample_rate(id) {
+        Ok(rate) => rate,
+        Err(_) => return NO_ERR,
+    };
+    cubeb_log!(
+        "({:p}) Nominal sample rate of device {} changed to {}.",
+        stm as *const AudioUnitStream,
+        id,
+        rate
+    );
+
+    let output_changed = !stm.core_stream_data.output_unit.is_null()
+        && id == stm.core_stream_data.output_device.id
+        && rate != stm.core_stream_data.output_hw_rate.load(Ordering::SeqCst);
+    let input_changed = !stm.core_stream_data.input_unit.is_null()
+        && id == stm.core_stream_data.input_device.id
+        && rate != stm.core_stream_data.input_hw_rate.load(Ordering::SeqCst);
+
+    if output_changed {
+        // The output AudioUnit converts the stream rate to the new device rate by itself, so the
+        // stream keeps running as it is.
+        stm.core_stream_data
+            .output_hw_rate
+            .store(rate, Ordering::SeqCst);
+        stm.sample_rate_changed_callback
+            .lock()
+            .unwrap()
+            .call(DeviceType::OUTPUT, rate as u32);
+    }
+
+    if input_changed {
+        stm.sample_rate_changed_callback
+            .lock()
+            .unwrap()
+            .call(DeviceType::INPUT, rate as u32);
+        // The input AudioUnit delivers the device rate, which is converted by the resampler. Both
+        // are updated on the queue used by the reinit, so they don't race with it.
+        stm.update_input_rate_async(rate);
+    }
+
+    NO_ERR
+}
+
+// The data callback given to the resampler, or to the render quantum adapter. It calls the
+// data callback of the stream `user_ptr` with the buffers in the formats of the stream parameters.
+extern "C" fn audiounit_stream_data_callback(
+    _stream: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input_buffer: *const c_void,
+    output_buffer: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    assert!(!user_ptr.is_null());
+    assert!(nframes >= 0);
+    let stm = unsafe { &mut *(user_ptr as *mut AudioUnitStream) };
+    let frames = nframes as usize;
+
+    let input: &[u8] = if input_buffer.is_null() {
+        &[]
+    } else {
+        let params = &stm.core_stream_data.input_stream_params;
+        let len = frames * cubeb_sample_size(params.format()) * params.channels() as usize;
+        unsafe { slice::from_raw_parts(input_buffer as *const u8, len) }
+    };
+    let output: &mut [u8] = if output_buffer.is_null() {
+        &mut []
+    } else {
+        let params = &stm.core_stream_data.output_stream_params;
+        let len = frames * cubeb_sample_size(params.format()) * params.channels() as usize;
+        unsafe { slice::from_raw_parts_mut(output_buffer as *mut u8, len) }
+    };
+
+    match (stm.callbacks.data)(input, output, frames) {
+        // More frames than asked is an error of the data callback, raised by the output callback.
+        Ok(rendered) => rendered as c_long,
+        Err(_) => ffi::CUBEB_ERROR as c_long,
+    }
+}
+
 fn audiounit_get_acceptable_latency_range() -> Result<AudioValueRange> {
     let output_device_buffer_size_range = AudioObjectPropertyAddress {
         mSelector: kAudioDevicePropertyBufferFrameSizeRange,
@@ -1023,13 +1402,30 @@ fn audiounit_get_preferred_channel_layout(output_unit: AudioUnit) -> ChannelLayo
 }
 
 fn audiounit_get_current_channel_layout(output_unit: AudioUnit) -> ChannelLayout {
+    audiounit_get_channel_layout(output_unit, kAudioUnitScope_Output, AU_OUT_BUS)
+        // This property isn't known before macOS 10.12, attempt another method.
+        .unwrap_or_else(|| audiounit_get_preferred_channel_layout(output_unit))
+}
+
+// The layout of the device channels of the input AudioUnit.
+fn audiounit_get_input_channel_layout(input_unit: AudioUnit) -> ChannelLayout {
+    audiounit_get_channel_layout(input_unit, kAudioUnitScope_Input, AU_IN_BUS)
+        .unwrap_or(ChannelLayout::UNDEFINED)
+}
+
+// Return None if the AudioUnit doesn't know the channel layout property.
+fn audiounit_get_channel_layout(
+    unit: AudioUnit,
+    scope: AudioUnitScope,
+    element: AudioUnitElement,
+) -> Option<ChannelLayout> {
     let mut rv = NO_ERR;
     let mut size: usize = 0;
     rv = audio_unit_get_property_info(
-        output_unit,
+        unit,
         kAudioUnitProperty_AudioChannelLayout,
-        kAudioUnitScope_Output,
-        AU_OUT_BUS,
+        scope,
+        element,
         &mut size,
         ptr::null_mut(),
     );
@@ -1038,17 +1434,16 @@ fn audiounit_get_current_channel_layout(output_unit: AudioUnit) -> ChannelLayout
             "AudioUnitGetPropertyInfo/kAudioUnitProperty_AudioChannelLayout rv={}",
             rv
         );
-        // This property isn't known before macOS 10.12, attempt another method.
-        return audiounit_get_preferred_channel_layout(output_unit);
+        return None;
     }
     assert!(size > 0);
 
     let mut layout = make_sized_audio_channel_layout(size);
     rv = audio_unit_get_property(
-        output_unit,
+        unit,
         kAudioUnitProperty_AudioChannelLayout,
-        kAudioUnitScope_Output,
-        AU_OUT_BUS,
+        scope,
+        element,
         layout.as_mut(),
         &mut size,
     );
@@ -1057,10 +1452,10 @@ fn audiounit_get_current_channel_layout(output_unit: AudioUnit) -> ChannelLayout
             "AudioUnitGetProperty/kAudioUnitProperty_AudioChannelLayout rv={}",
             rv
         );
-        return ChannelLayout::UNDEFINED;
+        return Some(ChannelLayout::UNDEFINED);
     }
 
-    audiounit_convert_channel_layout(layout.as_ref())
+    Some(audiounit_convert_channel_layout(layout.as_ref()))
 }
 
 fn audiounit_set_channel_layout(
@@ -1400,6 +1795,26 @@ fn set_buffer_size(
     }
 }
 
+// The most frames the unit renders or delivers in a callback.
+fn get_max_frames_per_slice(unit: AudioUnit) -> std::result::Result<u32, OSStatus> {
+    assert!(!unit.is_null());
+    let mut frames: u32 = 0;
+    let mut size = mem::size_of::<u32>();
+    let status = audio_unit_get_property(
+        unit,
+        kAudioUnitProperty_MaximumFramesPerSlice,
+        kAudioUnitScope_Global,
+        0,
+        &mut frames,
+        &mut size,
+    );
+    if status == NO_ERR {
+        Ok(frames)
+    } else {
+        Err(status)
+    }
+}
+
 fn set_buffer_size_sync(unit: AudioUnit, side: io_side, frames: u32) -> Result<()> {
     let current_frames = get_buffer_size(unit, side.clone()).map_err(|r| {
         cubeb_log!(
@@ -1588,289 +2003,564 @@ fn audiounit_strref_to_cstr_utf8(strref: CFStringRef) -> CString {
     CString::new(buffer).unwrap_or(empty)
 }
 
+fn audiounit_strref_to_string(strref: CFStringRef) -> String {
+    audiounit_strref_to_cstr_utf8(strref)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// The channels of the device in the scope, or 0 if they can't be read.
 fn audiounit_get_channel_count(devid: AudioObjectID, scope: AudioObjectPropertyScope) -> u32 {
-    let mut count: u32 = 0;
-    let mut size: usize = 0;
+    get_channel_count(devid, scope).unwrap_or(0)
+}
 
+fn get_channel_count(
+    devid: AudioObjectID,
+    scope: AudioObjectPropertyScope,
+) -> std::result::Result<u32, OSStatus> {
     let adr = AudioObjectPropertyAddress {
         mSelector: kAudioDevicePropertyStreamConfiguration,
         mScope: scope,
         mElement: kAudioObjectPropertyElementMaster,
     };
 
-    if audio_object_get_property_data_size(devid, &adr, &mut size) == NO_ERR && size > 0 {
-        let mut data: Vec<u8> = allocate_array_by_size(size);
-        let ptr = data.as_mut_ptr() as *mut AudioBufferList;
-        if audio_object_get_property_data(devid, &adr, &mut size, ptr) == NO_ERR {
-            let list: &AudioBufferList = unsafe { &(*ptr) };
-            let ptr = list.mBuffers.as_ptr() as *const AudioBuffer;
-            let len = list.mNumberBuffers as usize;
-            if len == 0 {
-                return 0;
-            }
-            let buffers = unsafe { slice::from_raw_parts(ptr, len) };
-            for buffer in buffers {
-                count += buffer.mNumberChannels;
-            }
-        }
+    let mut size: usize = 0;
+    let r = audio_object_get_property_data_size(devid, &adr, &mut size);
+    if r != NO_ERR {
+        return Err(r);
+    }
+    if size == 0 {
+        return Ok(0);
+    }
+    let mut data: Vec<u8> = allocate_array_by_size(size);
+    let ptr = data.as_mut_ptr() as *mut AudioBufferList;
+    let r = audio_object_get_property_data(devid, &adr, &mut size, ptr);
+    if r != NO_ERR {
+        return Err(r);
+    }
+    let list: &AudioBufferList = unsafe { &(*ptr) };
+    let len = list.mNumberBuffers as usize;
+    if len == 0 {
+        return Ok(0);
+    }
+    let buffers = unsafe { slice::from_raw_parts(list.mBuffers.as_ptr(), len) };
+    Ok(buffers.iter().map(|buffer| buffer.mNumberChannels).sum())
+}
+
+fn get_device_string_property(
+    devid: AudioObjectID,
+    address: &AudioObjectPropertyAddress,
+) -> std::result::Result<String, OSStatus> {
+    let mut size = mem::size_of::<CFStringRef>();
+    let mut string: CFStringRef = ptr::null();
+    let r = audio_object_get_property_data(devid, address, &mut size, &mut string);
+    if r != NO_ERR {
+        return Err(r);
+    }
+    if string.is_null() {
+        return Err(kAudioHardwareUnspecifiedError as OSStatus);
     }
-    count
+    let value = audiounit_strref_to_string(string);
+    unsafe {
+        CFRelease(string as *const c_void);
+    }
+    Ok(value)
+}
+
+fn get_device_uid(devid: AudioObjectID) -> std::result::Result<String, OSStatus> {
+    let address = AudioObjectPropertyAddress {
+        mSelector: kAudioDevicePropertyDeviceUID,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+    get_device_string_property(devid, &address)
 }
 
-fn audiounit_get_available_samplerate(
+// The name of the current data source of the device in the scope, like "Internal Speakers", or
+// the device name if it has no data source.
+fn get_device_label(
     devid: AudioObjectID,
     scope: AudioObjectPropertyScope,
-    min: &mut u32,
-    max: &mut u32,
-    def: &mut u32,
-) {
+) -> std::result::Result<String, OSStatus> {
     let mut adr = AudioObjectPropertyAddress {
-        mSelector: 0,
+        mSelector: kAudioDevicePropertyDataSource,
         mScope: scope,
         mElement: kAudioObjectPropertyElementMaster,
     };
-
-    adr.mSelector = kAudioDevicePropertyNominalSampleRate;
-    if audio_object_has_property(devid, &adr) {
-        let mut size = mem::size_of::<f64>();
-        let mut fvalue: f64 = 0.0;
-        if audio_object_get_property_data(devid, &adr, &mut size, &mut fvalue) == NO_ERR {
-            *def = fvalue as u32;
+    let mut ds: u32 = 0;
+    let mut size = mem::size_of::<u32>();
+    if audio_object_get_property_data(devid, &adr, &mut size, &mut ds) == NO_ERR {
+        let mut source_name: CFStringRef = ptr::null();
+        let mut trl = AudioValueTranslation {
+            mInputData: &mut ds as *mut u32 as *mut c_void,
+            mInputDataSize: mem::size_of_val(&ds) as u32,
+            mOutputData: &mut source_name as *mut CFStringRef as *mut c_void,
+            mOutputDataSize: mem::size_of::<CFStringRef>() as u32,
+        };
+        adr.mSelector = kAudioDevicePropertyDataSourceNameForIDCFString;
+        size = mem::size_of::<AudioValueTranslation>();
+        let r = audio_object_get_property_data(devid, &adr, &mut size, &mut trl);
+        if r == NO_ERR && !source_name.is_null() {
+            let label = audiounit_strref_to_string(source_name);
+            unsafe {
+                CFRelease(source_name as *const c_void);
+            }
+            return Ok(label);
         }
     }
 
-    adr.mSelector = kAudioDevicePropertyAvailableNominalSampleRates;
-    let mut size = 0;
-    let mut range = AudioValueRange::default();
-    if audio_object_has_property(devid, &adr)
-        && audio_object_get_property_data_size(devid, &adr, &mut size) == NO_ERR
-    {
-        let mut ranges: Vec<AudioValueRange> = allocate_array_by_size(size);
-        range.mMinimum = std::f64::MAX;
-        range.mMaximum = std::f64::MIN;
-        if audio_object_get_property_data(devid, &adr, &mut size, ranges.as_mut_ptr()) == NO_ERR {
-            for rng in &ranges {
-                if rng.mMaximum > range.mMaximum {
-                    range.mMaximum = rng.mMaximum;
-                }
-                if rng.mMinimum < range.mMinimum {
-                    range.mMinimum = rng.mMinimum;
-                }
-            }
+    adr.mSelector = kAudioObjectPropertyName;
+    get_device_string_property(devid, &adr)
+}
+
+fn get_device_manufacturer(devid: AudioObjectID) -> std::result::Result<String, OSStatus> {
+    let address = AudioObjectPropertyAddress {
+        mSelector: kAudioObjectPropertyManufacturer,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+    get_device_string_property(devid, &address)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct DeviceSampleRates {
+    default: u32,
+    // The ranges as reported by the device. The discrete rates have the same minimum and maximum.
+    ranges: Vec<SampleRateRange>,
+}
+
+fn get_device_sample_rates(
+    devid: AudioObjectID,
+    scope: AudioObjectPropertyScope,
+) -> std::result::Result<DeviceSampleRates, OSStatus> {
+    let nominal_rate = get_device_nominal_sample_rate_in_scope(devid, scope)?;
+    let ranges = get_device_sample_rate_ranges_in_scope(devid, scope)?;
+    if ranges.is_empty() {
+        return Err(kAudioHardwareUnspecifiedError as OSStatus);
+    }
+    let mut rate_ranges: Vec<SampleRateRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        let range = SampleRateRange {
+            min: range.mMinimum as u32,
+            max: range.mMaximum as u32,
+        };
+        // Some devices list the same rate more than once.
+        if !rate_ranges.contains(&range) {
+            rate_ranges.push(range);
         }
-        *max = range.mMaximum as u32;
-        *min = range.mMinimum as u32;
-    } else {
-        *max = 0;
-        *min = 0;
     }
+    Ok(DeviceSampleRates {
+        default: nominal_rate as u32,
+        ranges: rate_ranges,
+    })
 }
 
-fn audiounit_get_device_presentation_latency(
+// The lowest and the highest latencies in frames of the device in the scope, including its
+// presentation latency.
+fn get_device_latency_range(
     devid: AudioObjectID,
     scope: AudioObjectPropertyScope,
-) -> u32 {
-    let mut adr = AudioObjectPropertyAddress {
-        mSelector: 0,
+) -> std::result::Result<(u32, u32), OSStatus> {
+    let adr = AudioObjectPropertyAddress {
+        mSelector: kAudioDevicePropertyBufferFrameSizeRange,
         mScope: scope,
         mElement: kAudioObjectPropertyElementMaster,
     };
-    let mut size: usize = 0;
-    let mut dev: u32 = 0;
-    let mut stream: u32 = 0;
-    let mut sid: [AudioStreamID; 1] = [kAudioObjectUnknown];
-
-    adr.mSelector = kAudioDevicePropertyLatency;
-    size = mem::size_of::<u32>();
-    if audio_object_get_property_data(devid, &adr, &mut size, &mut dev) != NO_ERR {
-        dev = 0;
-    }
-
-    adr.mSelector = kAudioDevicePropertyStreams;
-    size = mem::size_of_val(&sid);
-    assert_eq!(size, mem::size_of::<AudioStreamID>());
-    if audio_object_get_property_data(devid, &adr, &mut size, sid.as_mut_ptr()) == NO_ERR {
-        adr.mSelector = kAudioStreamPropertyLatency;
-        size = mem::size_of::<u32>();
-        audio_object_get_property_data(sid[0], &adr, &mut size, &mut stream);
+    let mut range = AudioValueRange::default();
+    let mut size = mem::size_of::<AudioValueRange>();
+    let r = audio_object_get_property_data(devid, &adr, &mut size, &mut range);
+    if r != NO_ERR {
+        return Err(r);
     }
-
-    dev + stream
+    let latency = audiounit_get_device_presentation_latency(devid, scope);
+    Ok((
+        latency + range.mMinimum as u32,
+        latency + range.mMaximum as u32,
+    ))
 }
 
-fn audiounit_create_device_from_hwdev(
-    dev_info: &mut ffi::cubeb_device_info,
+fn get_device_streams(
     devid: AudioObjectID,
-    devtype: DeviceType,
-) -> Result<()> {
-    assert!(devtype == DeviceType::INPUT || devtype == DeviceType::OUTPUT);
-
-    let mut adr = AudioObjectPropertyAddress {
-        mSelector: 0,
-        mScope: 0,
+    scope: AudioObjectPropertyScope,
+) -> std::result::Result<Vec<AudioStreamID>, OSStatus> {
+    let adr = AudioObjectPropertyAddress {
+        mSelector: kAudioDevicePropertyStreams,
+        mScope: scope,
         mElement: kAudioObjectPropertyElementMaster,
     };
-    let mut size: usize = 0;
+    let mut size = 0;
+    let r = audio_object_get_property_data_size(devid, &adr, &mut size);
+    if r != NO_ERR {
+        return Err(r);
+    }
+    let mut streams: Vec<AudioStreamID> = allocate_array_by_size(size);
+    let r = audio_object_get_property_data(devid, &adr, &mut size, streams.as_mut_ptr());
+    if r != NO_ERR {
+        return Err(r);
+    }
+    Ok(streams)
+}
 
-    adr.mScope = if devtype == DeviceType::OUTPUT {
-        kAudioDevicePropertyScopeOutput
-    } else {
-        kAudioDevicePropertyScopeInput
+// The formats of the stream listed by `selector`, kAudioStreamPropertyAvailableVirtualFormats or
+// kAudioStreamPropertyAvailablePhysicalFormats.
+fn get_stream_available_formats(
+    stream: AudioStreamID,
+    selector: AudioObjectPropertySelector,
+) -> std::result::Result<Vec<AudioStreamRangedDescription>, OSStatus> {
+    let adr = AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMaster,
     };
+    let mut size = 0;
+    let r = audio_object_get_property_data_size(stream, &adr, &mut size);
+    if r != NO_ERR {
+        return Err(r);
+    }
+    let mut formats: Vec<AudioStreamRangedDescription> = allocate_array_by_size(size);
+    let r = audio_object_get_property_data(stream, &adr, &mut size, formats.as_mut_ptr());
+    if r != NO_ERR {
+        return Err(r);
+    }
+    Ok(formats)
+}
 
-    let ch = audiounit_get_channel_count(devid, adr.mScope);
-    if ch == 0 {
-        return Err(Error::error());
+fn get_stream_physical_format(
+    stream: AudioStreamID,
+) -> std::result::Result<AudioStreamBasicDescription, OSStatus> {
+    let mut format = AudioStreamBasicDescription::default();
+    let mut size = mem::size_of::<AudioStreamBasicDescription>();
+    let r = audio_object_get_property_data(
+        stream,
+        &PHYSICAL_FORMAT_PROPERTY_ADDRESS,
+        &mut size,
+        &mut format,
+    );
+    if r != NO_ERR {
+        return Err(r);
     }
+    Ok(format)
+}
 
-    *dev_info = ffi::cubeb_device_info::default();
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DeviceFormats {
+    // The cubeb sample formats among the virtual and the physical formats of the streams.
+    supported: ffi::cubeb_device_fmt,
+    // The current physical format of the first stream, or the preferred supported format if
+    // the physical format has no cubeb sample format, like a 24-bit one.
+    default: ffi::cubeb_device_fmt,
+}
 
-    let mut device_id_str: CFStringRef = ptr::null();
-    size = mem::size_of::<CFStringRef>();
-    adr.mSelector = kAudioDevicePropertyDeviceUID;
-    let mut ret = audio_object_get_property_data(devid, &adr, &mut size, &mut device_id_str);
-    if ret == NO_ERR && !device_id_str.is_null() {
-        let c_string = audiounit_strref_to_cstr_utf8(device_id_str);
-        dev_info.device_id = c_string.into_raw();
+fn get_device_formats(
+    devid: AudioObjectID,
+    scope: AudioObjectPropertyScope,
+) -> std::result::Result<DeviceFormats, OSStatus> {
+    let streams = get_device_streams(devid, scope)?;
+    let mut supported: ffi::cubeb_device_fmt = 0;
+    let mut error = None;
+    let mut read_any = false;
+    for stream in &streams {
+        for selector in &[
+            kAudioStreamPropertyAvailableVirtualFormats,
+            kAudioStreamPropertyAvailablePhysicalFormats,
+        ] {
+            // Skip the streams we cannot query, instead of failing the whole device.
+            match get_stream_available_formats(*stream, *selector) {
+                Ok(formats) => {
+                    read_any = true;
+                    for ranged in formats {
+                        supported |= get_device_format(&ranged.mFormat).unwrap_or(0);
+                    }
+                }
+                Err(r) => {
+                    cubeb_log!(
+                        "Cannot get the available formats of stream {} (selector {}) on device {}, rv={}",
+                        stream,
+                        selector,
+                        devid,
+                        r
+                    );
+                    error = Some(r);
+                }
+            }
+        }
+    }
+    if !read_any {
+        return Err(error.unwrap_or(kAudioHardwareUnspecifiedError as OSStatus));
+    }
+    if supported == 0 {
+        return Err(kAudioHardwareUnspecifiedError as OSStatus);
+    }
+
+    let physical = streams
+        .iter()
+        .find_map(|stream| get_stream_physical_format(*stream).ok());
+    let default = physical
+        .as_ref()
+        .and_then(get_device_format)
+        .filter(|format| supported & format != 0)
+        .or_else(|| {
+            [
+                ffi::CUBEB_DEVICE_FMT_F32NE,
+                ffi::CUBEB_DEVICE_FMT_F32LE,
+                ffi::CUBEB_DEVICE_FMT_F32BE,
+                ffi::CUBEB_DEVICE_FMT_S16NE,
+                ffi::CUBEB_DEVICE_FMT_S16LE,
+                ffi::CUBEB_DEVICE_FMT_S16BE,
+            ]
+            .iter()
+            .cloned()
+            .find(|format| supported & format != 0)
+        })
+        .unwrap();
+    Ok(DeviceFormats { supported, default })
+}
 
-        assert!(
-            mem::size_of::<ffi::cubeb_devid>() >= mem::size_of_val(&devid),
-            "cubeb_devid can't represent devid"
+fn get_device_nominal_sample_rate_in_scope(
+    devid: AudioObjectID,
+    scope: AudioObjectPropertyScope,
+) -> std::result::Result<f64, OSStatus> {
+    let address = AudioObjectPropertyAddress {
+        mScope: scope,
+        ..NOMINAL_SAMPLE_RATE_PROPERTY_ADDRESS
+    };
+    let mut size = mem::size_of::<f64>();
+    let mut rate: f64 = 0.0;
+    let r = audio_object_get_property_data(devid, &address, &mut size, &mut rate);
+    if r != NO_ERR {
+        return Err(r);
+    }
+    Ok(rate)
+}
+
+fn get_device_sample_rate_ranges_in_scope(
+    devid: AudioObjectID,
+    scope: AudioObjectPropertyScope,
+) -> std::result::Result<Vec<AudioValueRange>, OSStatus> {
+    let address = AudioObjectPropertyAddress {
+        mScope: scope,
+        ..AVAILABLE_NOMINAL_SAMPLE_RATES_PROPERTY_ADDRESS
+    };
+    let mut size = 0;
+    let r = audio_object_get_property_data_size(devid, &address, &mut size);
+    if r != NO_ERR {
+        return Err(r);
+    }
+    let mut ranges: Vec<AudioValueRange> = allocate_array_by_size(size);
+    let r = audio_object_get_property_data(devid, &address, &mut size, ranges.as_mut_ptr());
+    if r != NO_ERR {
+        return Err(r);
+    }
+    Ok(ranges)
+}
+
+fn get_device_nominal_sample_rate(devid: AudioObjectID) -> Result<f64> {
+    get_device_nominal_sample_rate_in_scope(devid, kAudioObjectPropertyScopeGlobal).map_err(|r| {
+        cubeb_log!(
+            "AudioObjectGetPropertyData/kAudioDevicePropertyNominalSampleRate rv={}, device id={}",
+            r,
+            devid
         );
-        dev_info.devid = devid as ffi::cubeb_devid;
+        Error::error()
+    })
+}
 
-        dev_info.group_id = dev_info.device_id;
+fn get_device_sample_rate_ranges(devid: AudioObjectID) -> Result<Vec<AudioValueRange>> {
+    get_device_sample_rate_ranges_in_scope(devid, kAudioObjectPropertyScopeGlobal).map_err(|r| {
+        cubeb_log!(
+            "AudioObjectGetPropertyData/kAudioDevicePropertyAvailableNominalSampleRates rv={}, device id={}",
+            r,
+            devid
+        );
+        Error::error()
+    })
+}
 
-        unsafe {
-            CFRelease(device_id_str as *const c_void);
-        }
-    }
+// Set the nominal sample rate of the device and wait until the device runs at it.
+fn set_device_nominal_sample_rate(devid: AudioObjectID, rate: f64) -> Result<()> {
+    const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+    const MAX_WAITS: u32 = 100;
 
-    let mut friendly_name_str: CFStringRef = ptr::null();
-    let mut ds: u32 = 0;
-    size = mem::size_of::<u32>();
-    adr.mSelector = kAudioDevicePropertyDataSource;
-    ret = audio_object_get_property_data(devid, &adr, &mut size, &mut ds);
-    if ret == NO_ERR {
-        let mut trl = AudioValueTranslation {
-            mInputData: &mut ds as *mut u32 as *mut c_void,
-            mInputDataSize: mem::size_of_val(&ds) as u32,
-            mOutputData: &mut friendly_name_str as *mut CFStringRef as *mut c_void,
-            mOutputDataSize: mem::size_of::<CFStringRef>() as u32,
-        };
-        adr.mSelector = kAudioDevicePropertyDataSourceNameForIDCFString;
-        size = mem::size_of::<AudioValueTranslation>();
-        audio_object_get_property_data(devid, &adr, &mut size, &mut trl);
+    let supported = get_device_sample_rate_ranges(devid)?
+        .iter()
+        .any(|range| range.mMinimum <= rate && rate <= range.mMaximum);
+    if !supported {
+        cubeb_log!("Device {} doesn't support the rate {}.", devid, rate);
+        return Err(Error::invalid_format());
     }
 
-    // If there is no datasource for this device, fall back to the
-    // device name.
-    if friendly_name_str.is_null() {
-        size = mem::size_of::<CFStringRef>();
-        adr.mSelector = kAudioObjectPropertyName;
-        audio_object_get_property_data(devid, &adr, &mut size, &mut friendly_name_str);
+    let r = audio_object_set_property_data(
+        devid,
+        &NOMINAL_SAMPLE_RATE_PROPERTY_ADDRESS,
+        mem::size_of::<f64>(),
+        &rate,
+    );
+    if r != NO_ERR {
+        cubeb_log!(
+            "AudioObjectSetPropertyData/kAudioDevicePropertyNominalSampleRate rv={}, device id={}",
+            r,
+            devid
+        );
+        return Err(Error::error());
     }
 
-    if friendly_name_str.is_null() {
-        // Couldn't get a datasource name nor a device name, return a
-        // valid string of length 0.
-        let c_string = CString::default();
-        dev_info.friendly_name = c_string.into_raw();
-    } else {
-        let c_string = audiounit_strref_to_cstr_utf8(friendly_name_str);
-        dev_info.friendly_name = c_string.into_raw();
-        unsafe {
-            CFRelease(friendly_name_str as *const c_void);
+    // The rate is changed asynchronously.
+    for _ in 0..MAX_WAITS {
+        if get_device_nominal_sample_rate(devid)? == rate {
+            return Ok(());
         }
+        thread::sleep(WAIT_INTERVAL);
+    }
+    cubeb_log!("Device {} didn't switch to the rate {}.", devid, rate);
+    Err(Error::error())
+}
+
+fn audiounit_get_device_presentation_latency(
+    devid: AudioObjectID,
+    scope: AudioObjectPropertyScope,
+) -> u32 {
+    let mut adr = AudioObjectPropertyAddress {
+        mSelector: 0,
+        mScope: scope,
+        mElement: kAudioObjectPropertyElementMaster,
     };
+    let mut size: usize = 0;
+    let mut dev: u32 = 0;
+    let mut stream: u32 = 0;
+    let mut sid: [AudioStreamID; 1] = [kAudioObjectUnknown];
 
-    let mut vendor_name_str: CFStringRef = ptr::null();
-    size = mem::size_of::<CFStringRef>();
-    adr.mSelector = kAudioObjectPropertyManufacturer;
-    ret = audio_object_get_property_data(devid, &adr, &mut size, &mut vendor_name_str);
-    if ret == NO_ERR && !vendor_name_str.is_null() {
-        let c_string = audiounit_strref_to_cstr_utf8(vendor_name_str);
-        dev_info.vendor_name = c_string.into_raw();
-        unsafe {
-            CFRelease(vendor_name_str as *const c_void);
-        }
+    adr.mSelector = kAudioDevicePropertyLatency;
+    size = mem::size_of::<u32>();
+    if audio_object_get_property_data(devid, &adr, &mut size, &mut dev) != NO_ERR {
+        dev = 0;
+    }
+
+    adr.mSelector = kAudioDevicePropertyStreams;
+    size = mem::size_of_val(&sid);
+    assert_eq!(size, mem::size_of::<AudioStreamID>());
+    if audio_object_get_property_data(devid, &adr, &mut size, sid.as_mut_ptr()) == NO_ERR {
+        adr.mSelector = kAudioStreamPropertyLatency;
+        size = mem::size_of::<u32>();
+        audio_object_get_property_data(sid[0], &adr, &mut size, &mut stream);
     }
 
-    dev_info.device_type = if devtype == DeviceType::OUTPUT {
-        ffi::CUBEB_DEVICE_TYPE_OUTPUT
+    dev + stream
+}
+
+fn audiounit_create_device_from_hwdev(
+    devid: AudioObjectID,
+    devtype: DeviceType,
+) -> Result<DeviceInfo> {
+    assert!(devtype == DeviceType::INPUT || devtype == DeviceType::OUTPUT);
+
+    let scope = if devtype == DeviceType::OUTPUT {
+        kAudioDevicePropertyScopeOutput
     } else {
-        ffi::CUBEB_DEVICE_TYPE_INPUT
+        kAudioDevicePropertyScopeInput
     };
-    dev_info.state = ffi::CUBEB_DEVICE_STATE_ENABLED;
-    dev_info.preferred = if devid == audiounit_get_default_device_id(devtype) {
-        ffi::CUBEB_DEVICE_PREF_ALL
-    } else {
-        ffi::CUBEB_DEVICE_PREF_NONE
+    let log_failure = |property: &str, status: OSStatus| {
+        cubeb_log!(
+            "Cannot get the {} of device {}. Error: {}",
+            property,
+            devid,
+            status
+        );
     };
 
-    dev_info.max_channels = ch;
-    dev_info.format = ffi::CUBEB_DEVICE_FMT_ALL;
-    dev_info.default_format = ffi::CUBEB_DEVICE_FMT_F32NE;
-    audiounit_get_available_samplerate(
-        devid,
-        adr.mScope,
-        &mut dev_info.min_rate,
-        &mut dev_info.max_rate,
-        &mut dev_info.default_rate,
-    );
-
-    let latency = audiounit_get_device_presentation_latency(devid, adr.mScope);
-    let mut range = AudioValueRange::default();
-    adr.mSelector = kAudioDevicePropertyBufferFrameSizeRange;
-    size = mem::size_of::<AudioValueRange>();
-    ret = audio_object_get_property_data(devid, &adr, &mut size, &mut range);
-    if ret == NO_ERR {
-        dev_info.latency_lo = latency + range.mMinimum as u32;
-        dev_info.latency_hi = latency + range.mMaximum as u32;
-    } else {
-        dev_info.latency_lo = 10 * dev_info.default_rate / 1000; // Default to 10ms
-        dev_info.latency_hi = 100 * dev_info.default_rate / 1000; // Default to 10ms
+    let max_channels = get_channel_count(devid, scope).map_err(|status| {
+        log_failure("channel count", status);
+        Error::error()
+    })?;
+    if max_channels == 0 {
+        return Err(Error::error());
     }
 
-    Ok(())
-}
+    let uid = get_device_uid(devid)
+        .map_err(|status| log_failure("uid", status))
+        .ok();
+    // Use an empty name if there is neither a data source name nor a device name.
+    let friendly_name = get_device_label(devid, scope)
+        .map_err(|status| log_failure("label", status))
+        .unwrap_or_default();
+    let vendor_name = get_device_manufacturer(devid)
+        .map_err(|status| log_failure("manufacturer", status))
+        .ok();
+    let rates = get_device_sample_rates(devid, scope)
+        .map_err(|status| log_failure("sample rates", status))
+        .unwrap_or_default();
+    // The AudioUnit converts from any cubeb sample format when the formats are unknown.
+    let formats = get_device_formats(devid, scope).unwrap_or_else(|status| {
+        log_failure("formats", status);
+        DeviceFormats {
+            supported: ffi::CUBEB_DEVICE_FMT_ALL,
+            default: ffi::CUBEB_DEVICE_FMT_F32NE,
+        }
+    });
+    let (latency_lo, latency_hi) =
+        get_device_latency_range(devid, scope).unwrap_or_else(|status| {
+            log_failure("latency range", status);
+            // Default to 10ms and 100ms.
+            (10 * rates.default / 1000, 100 * rates.default / 1000)
+        });
 
-fn is_aggregate_device(device_info: &ffi::cubeb_device_info) -> bool {
-    assert!(!device_info.friendly_name.is_null());
-    let private_name =
-        CString::new(PRIVATE_AGGREGATE_DEVICE_NAME).expect("Fail to create a private name");
-    unsafe {
-        libc::strncmp(
-            device_info.friendly_name,
-            private_name.as_ptr(),
-            libc::strlen(private_name.as_ptr()),
-        ) == 0
-    }
+    let preferred = devid == audiounit_get_default_device_id(devtype);
+    Ok(DeviceInfo {
+        id: devid,
+        // The group of a device is the device itself, so the input and the output entries of an
+        // in-out device share the same stable group.
+        group_id: uid.clone(),
+        uid,
+        friendly_name,
+        vendor_name,
+        device_type: devtype,
+        preferred_input: preferred && devtype == DeviceType::INPUT,
+        preferred_output: preferred && devtype == DeviceType::OUTPUT,
+        input_channels: if devtype == DeviceType::INPUT {
+            max_channels
+        } else {
+            0
+        },
+        output_channels: if devtype == DeviceType::OUTPUT {
+            max_channels
+        } else {
+            0
+        },
+        formats: formats.supported,
+        default_format: formats.default,
+        default_rate: rates.default,
+        sample_rate_ranges: rates.ranges,
+        latency_lo,
+        latency_hi,
+    })
 }
 
-fn audiounit_device_destroy(device: &mut ffi::cubeb_device_info) {
-    // This should be mapped to the memory allocation in audiounit_create_device_from_hwdev.
-    // Set the pointers to null in case it points to some released memory.
-    unsafe {
-        if !device.device_id.is_null() {
-            // group_id is a mirror to device_id, so we could skip it.
-            assert!(!device.group_id.is_null());
-            assert_eq!(device.device_id, device.group_id);
-            let _ = CString::from_raw(device.device_id as *mut _);
-            device.device_id = ptr::null();
-            device.group_id = ptr::null();
-        }
-        if !device.friendly_name.is_null() {
-            let _ = CString::from_raw(device.friendly_name as *mut _);
-            device.friendly_name = ptr::null();
-        }
-        if !device.vendor_name.is_null() {
-            let _ = CString::from_raw(device.vendor_name as *mut _);
-            device.vendor_name = ptr::null();
+// Describe an in-out device in one entry, or the side it has. The entry is named after the
+// device, since the data sources of the sides have their own names.
+fn audiounit_create_inout_device_from_hwdev(devid: AudioObjectID) -> Result<DeviceInfo> {
+    let output = audiounit_create_device_from_hwdev(devid, DeviceType::OUTPUT);
+    let input = audiounit_create_device_from_hwdev(devid, DeviceType::INPUT);
+    match (output, input) {
+        (Ok(output), Ok(input)) => {
+            let mut info = combine_device_infos(output, input);
+            match get_device_label(devid, kAudioObjectPropertyScopeGlobal) {
+                Ok(name) => info.friendly_name = name,
+                Err(status) => {
+                    cubeb_log!("Cannot get the name of device {}. Error: {}", devid, status)
+                }
+            }
+            Ok(info)
         }
+        (Ok(info), Err(_)) | (Err(_), Ok(info)) => Ok(info),
+        (Err(e), Err(_)) => Err(e),
     }
 }
 
+fn is_aggregate_device(device_info: &DeviceInfo) -> bool {
+    device_info
+        .friendly_name
+        .starts_with(PRIVATE_AGGREGATE_DEVICE_NAME)
+}
+
 fn audiounit_get_devices() -> Vec<AudioObjectID> {
     let mut size: usize = 0;
     let mut ret = audio_object_get_property_data_size(
@@ -1917,23 +2607,56 @@ fn audiounit_get_devices_of_type(devtype: DeviceType) -> Vec<AudioObjectID> {
 
     // Expected sorted but did not find anything in the docs.
     devices.sort();
-    if devtype.contains(DeviceType::INPUT | DeviceType::OUTPUT) {
-        return devices;
+
+    let mut scopes = Vec::new();
+    if devtype.contains(DeviceType::INPUT) {
+        scopes.push(kAudioDevicePropertyScopeInput);
     }
+    if devtype.contains(DeviceType::OUTPUT) {
+        scopes.push(kAudioDevicePropertyScopeOutput);
+    }
+    // Keep the devices having channels of one of the types.
+    devices.retain(|&device| {
+        scopes
+            .iter()
+            .any(|&scope| audiounit_get_channel_count(device, scope) > 0)
+    });
+    devices
+}
 
-    let scope = if devtype == DeviceType::INPUT {
-        kAudioDevicePropertyScopeInput
+// Describe the input and the output devices separately. An in-out device has an entry of each
+// type.
+fn audiounit_get_device_infos(devtype: DeviceType) -> Vec<DeviceInfo> {
+    let input_devs = if devtype.contains(DeviceType::INPUT) {
+        audiounit_get_devices_of_type(DeviceType::INPUT)
     } else {
-        kAudioDevicePropertyScopeOutput
+        Vec::<AudioObjectID>::new()
+    };
+
+    let output_devs = if devtype.contains(DeviceType::OUTPUT) {
+        audiounit_get_devices_of_type(DeviceType::OUTPUT)
+    } else {
+        Vec::<AudioObjectID>::new()
     };
-    let mut devices_in_scope = Vec::new();
-    for device in devices {
-        if audiounit_get_channel_count(device, scope) > 0 {
-            devices_in_scope.push(device);
+
+    // The number of input and output devices is not necessarily the same as the count of
+    // raw devices supported by the system since, for example, with Soundflower installed,
+    // some devices may report as being both input *and* output and cubeb separates those
+    // into two different devices.
+    let mut infos = Vec::with_capacity(output_devs.len() + input_devs.len());
+    for (devs, dev_type) in &[
+        (output_devs, DeviceType::OUTPUT),
+        (input_devs, DeviceType::INPUT),
+    ] {
+        for dev in devs {
+            if let Ok(info) = audiounit_create_device_from_hwdev(*dev, *dev_type) {
+                if !is_aggregate_device(&info) {
+                    infos.push(info);
+                }
+            }
         }
     }
-
-    devices_in_scope
+    infos
 }
 
 extern "C" fn audiounit_collection_changed_callback(
@@ -2051,32 +2774,159 @@ impl Default for SharedDevices {
     }
 }
 
+// The backend specific settings of a stream, which are not part of the cubeb stream parameters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamOptions {
+    // Call the data callback with exactly this number of frames every time, instead of the
+    // number of frames asked by the device. This adds up to the same number of frames of latency.
+    pub render_quantum: Option<u32>,
+    // Measure the levels of the input and the output over windows of this duration.
+    pub meter_window: Option<Duration>,
+    // Capture these channels of the input device, indexed from 0, instead of its first channels.
+    // There must be one device channel for each channel of the input stream parameters.
+    pub input_channel_map: Option<Vec<u32>>,
+    // Play the channels of the output stream on these channels of the output device, indexed from
+    // 0, instead of mixing them to the device layout. The other device channels are silent.
+    pub output_channel_map: Option<Vec<u32>>,
+    // Switch the devices to the rate of the stream parameters, if they support it, instead of
+    // resampling the stream. The rates of the devices are restored when the stream is destroyed.
+    pub set_device_sample_rate: bool,
+    // Take the exclusive access, the hog mode, of the devices while the stream exists. Creating
+    // the stream fails with `Error::device_unavailable` if another process has it.
+    pub exclusive: bool,
+    // The output rendered by the data callback is given to the device as it is, without the
+    // resampler nor the mixer, whenever the stream is output only and has the format, rate and
+    // channels of the device. Set this to require it: creating the stream fails with
+    // `Error::invalid_format` otherwise, and the volume and the panning can't be changed.
+    pub bit_perfect: bool,
+    // Send the output, IEC 61937 bursts in 16-bit stereo frames, to a receiver decoding this
+    // format over S/PDIF or HDMI. The stream is bit-perfect and exclusive, and creating it fails
+    // with `Error::not_supported` if the output device can't take the format at the stream rate.
+    pub encoded_format: Option<EncodedFormat>,
+}
+
+impl StreamOptions {
+    fn validate(&self) -> Result<()> {
+        if self.render_quantum == Some(0) {
+            cubeb_log!("The render quantum must not be 0.");
+            return Err(Error::invalid_parameter());
+        }
+        if self.meter_window == Some(Duration::from_secs(0)) {
+            cubeb_log!("The meter window must not be 0.");
+            return Err(Error::invalid_parameter());
+        }
+        if self.passes_through()
+            && (self.render_quantum.is_some() || self.output_channel_map.is_some())
+        {
+            cubeb_log!("The bit-perfect output can't have a render quantum nor a channel map.");
+            return Err(Error::invalid_parameter());
+        }
+        Ok(())
+    }
+
+    fn passes_through(&self) -> bool {
+        self.bit_perfect || self.encoded_format.is_some()
+    }
+
+    fn hogs_devices(&self) -> bool {
+        self.exclusive || self.encoded_format.is_some()
+    }
+}
+
+// The backend specific options of the device enumeration.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnumerateOptions {
+    // Describe each device having both inputs and outputs in one entry of type INPUT | OUTPUT,
+    // instead of one INPUT and one OUTPUT entry. Both types must be enumerated.
+    pub combine_in_out_devices: bool,
+}
+
+// The latency a stream runs with, decided by the context for the requested latency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencyDecision {
+    pub latency: u32,
+    // True if the latency is inherited from another stream running on the same device.
+    pub shared: bool,
+}
+
+#[derive(Debug)]
+struct DeviceLatency {
+    streams: u32,
+    latency: u32,
+}
+
+// The buffer frame size (kAudioDevicePropertyBufferFrameSize) is a property of the device
+// rather than of the AudioUnit, so all the streams running on the same device must use the
+// same latency. Streams running on different devices can have their own latency.
 #[derive(Debug)]
 struct LatencyController {
     streams: u32,
-    latency: Option<u32>,
+    devices: HashMap<AudioDeviceID, DeviceLatency>,
+    // The bounds of the latency for the streams that don't share a device.
+    min_frames: u32,
+    max_frames: u32,
 }
 
 impl LatencyController {
-    fn add_stream(&mut self, latency: u32) -> Option<u32> {
+    fn add_stream(&mut self, devices: &[AudioDeviceID], latency: u32) -> LatencyDecision {
         self.streams += 1;
-        // For the 1st stream set anything within safe min-max
-        if self.streams == 1 {
-            assert!(self.latency.is_none());
-            // Silently clamp the latency down to the platform default, because we
-            // synthetize the clock from the callbacks, and we want the clock to update often.
-            self.latency = Some(clamp_latency(latency));
+
+        let shared_latencies: Vec<u32> = devices
+            .iter()
+            .filter_map(|id| self.devices.get(id).map(|device| device.latency))
+            .collect();
+        let decision = match shared_latencies.first() {
+            Some(&latency) => {
+                if shared_latencies.iter().any(|&l| l != latency) {
+                    cubeb_log!(
+                        "Devices {:?} are running with different latencies {:?}. Use {}.",
+                        devices,
+                        shared_latencies,
+                        latency
+                    );
+                }
+                LatencyDecision {
+                    latency,
+                    shared: true,
+                }
+            }
+            // Silently clamp the latency into the configured bounds. The clock is interpolated
+            // between the callbacks, so it keeps updating often even with a large latency.
+            None => LatencyDecision {
+                latency: clamp_latency(latency, self.min_frames, self.max_frames),
+                shared: false,
+            },
+        };
+
+        for id in devices {
+            let device = self.devices.entry(*id).or_insert(DeviceLatency {
+                streams: 0,
+                latency: decision.latency,
+            });
+            device.streams += 1;
         }
-        self.latency
+
+        decision
     }
 
-    fn subtract_stream(&mut self) -> Option<u32> {
+    fn subtract_stream(&mut self, devices: &[AudioDeviceID]) {
+        assert!(self.streams > 0);
         self.streams -= 1;
-        if self.streams == 0 {
-            assert!(self.latency.is_some());
-            self.latency = None;
+        for id in devices {
+            let remove = {
+                let device = self
+                    .devices
+                    .get_mut(id)
+                    .expect("Remove a stream from an unregistered device");
+                assert!(device.streams > 0);
+                device.streams -= 1;
+                device.streams == 0
+            };
+            if remove {
+                self.devices.remove(id);
+            }
         }
-        self.latency
+        assert!(self.streams > 0 || self.devices.is_empty());
     }
 }
 
@@ -2084,7 +2934,9 @@ impl Default for LatencyController {
     fn default() -> Self {
         Self {
             streams: 0,
-            latency: None,
+            devices: HashMap::new(),
+            min_frames: SAFE_MIN_LATENCY_FRAMES,
+            max_frames: SAFE_MAX_LATENCY_FRAMES,
         }
     }
 }
@@ -2105,15 +2957,23 @@ pub struct AudioUnitContext {
     serial_queue: dispatch_queue_t,
     latency_controller: Mutex<LatencyController>,
     devices: Mutex<SharedDevices>,
+    // Capture the audio of the streams into WAV files in this directory, for debugging.
+    debug_capture_dir: Option<PathBuf>,
+    debug_captures: AtomicUsize,
 }
 
 impl AudioUnitContext {
-    fn new() -> Self {
+    // Create a context for the Rust API. The streams created by it borrow it, so several of them
+    // can run at the same time.
+    pub fn new() -> Self {
+        set_notification_runloop();
         Self {
             _ops: &OPS as *const _,
             serial_queue: create_dispatch_queue(DISPATCH_QUEUE_LABEL, DISPATCH_QUEUE_SERIAL),
             latency_controller: Mutex::new(LatencyController::default()),
             devices: Mutex::new(SharedDevices::default()),
+            debug_capture_dir: env::var_os(DEBUG_CAPTURE_DIR_ENV).map(PathBuf::from),
+            debug_captures: AtomicUsize::new(0),
         }
     }
 
@@ -2122,14 +2982,308 @@ impl AudioUnitContext {
         controller.streams
     }
 
-    fn update_latency_by_adding_stream(&self, latency_frames: u32) -> Option<u32> {
+    fn update_latency_by_adding_stream(
+        &self,
+        devices: &[AudioDeviceID],
+        latency_frames: u32,
+    ) -> LatencyDecision {
         let mut controller = self.latency_controller.lock().unwrap();
-        controller.add_stream(latency_frames)
+        controller.add_stream(devices, latency_frames)
     }
 
-    fn update_latency_by_removing_stream(&self) -> Option<u32> {
+    fn update_latency_by_removing_stream(&self, devices: &[AudioDeviceID]) {
+        let mut controller = self.latency_controller.lock().unwrap();
+        controller.subtract_stream(devices);
+    }
+
+    // Set the bounds used to clamp the latency of the streams created afterwards. The bounds
+    // must be within the buffer frame size range supported by the default output device.
+    pub fn set_latency_bounds(&self, min_frames: u32, max_frames: u32) -> Result<()> {
+        if min_frames == 0 || min_frames > max_frames {
+            return Err(Error::invalid_parameter());
+        }
+
+        let range = audiounit_get_acceptable_latency_range().map_err(|e| {
+            cubeb_log!("Could not get acceptable latency range.");
+            e
+        })?;
+        if f64::from(min_frames) < range.mMinimum || f64::from(max_frames) > range.mMaximum {
+            cubeb_log!(
+                "Latency bounds {}-{} are out of the acceptable range {}-{}.",
+                min_frames,
+                max_frames,
+                range.mMinimum,
+                range.mMaximum
+            );
+            return Err(Error::invalid_parameter());
+        }
+
         let mut controller = self.latency_controller.lock().unwrap();
-        controller.subtract_stream()
+        controller.min_frames = min_frames;
+        controller.max_frames = max_frames;
+        Ok(())
+    }
+
+    pub fn latency_bounds(&self) -> (u32, u32) {
+        let controller = self.latency_controller.lock().unwrap();
+        (controller.min_frames, controller.max_frames)
+    }
+
+    // Capture the input, the output rendered by the data callback and the output given to the
+    // device of the streams set up afterwards, into WAV files in `dir`. This overrides the
+    // directory set by the CUBEB_COREAUDIO_CAPTURE_DIR environment variable.
+    pub fn set_debug_capture_dir(&mut self, dir: Option<PathBuf>) {
+        self.debug_capture_dir = dir;
+    }
+
+    pub fn debug_capture_dir(&self) -> Option<&Path> {
+        self.debug_capture_dir.as_ref().map(PathBuf::as_path)
+    }
+
+    // The devices enumerated by enumerate_devices_with_options, with the details the collection
+    // of ffi::cubeb_device_info can't carry, like the discrete sample rates.
+    pub fn device_infos(
+        &self,
+        devtype: DeviceType,
+        options: EnumerateOptions,
+    ) -> Result<Vec<DeviceInfo>> {
+        if !options.combine_in_out_devices {
+            return Ok(audiounit_get_device_infos(devtype));
+        }
+        if !devtype.contains(DeviceType::INPUT | DeviceType::OUTPUT) {
+            cubeb_log!("Combining the in-out devices needs both the input and the output types");
+            return Err(Error::invalid_parameter());
+        }
+        Ok(
+            audiounit_get_devices_of_type(DeviceType::INPUT | DeviceType::OUTPUT)
+                .into_iter()
+                .filter_map(|dev| audiounit_create_inout_device_from_hwdev(dev).ok())
+                .filter(|info| !is_aggregate_device(info))
+                .collect(),
+        )
+    }
+
+    // Same as ContextOps::enumerate_devices, with the backend specific options of the
+    // enumeration.
+    pub fn enumerate_devices_with_options(
+        &mut self,
+        devtype: DeviceType,
+        collection: &DeviceCollectionRef,
+        options: EnumerateOptions,
+    ) -> Result<()> {
+        // Only the collection given to the caller holds the C strings.
+        let devices: Vec<ffi::cubeb_device_info> = self
+            .device_infos(devtype, options)?
+            .into_iter()
+            .map(ffi::cubeb_device_info::from)
+            .collect();
+        let coll = unsafe { &mut *collection.as_ptr() };
+        if !devices.is_empty() {
+            let (ptr, len) = forget_vec(devices);
+            coll.device = ptr;
+            coll.count = len;
+        } else {
+            coll.device = ptr::null_mut();
+            coll.count = 0;
+        }
+
+        Ok(())
+    }
+
+    // Same as ContextOps::stream_init, with the backend specific options of the stream.
+    #[allow(clippy::too_many_arguments)]
+    pub fn stream_init_with_options(
+        &mut self,
+        _stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&StreamParamsRef>,
+        output_device: DeviceId,
+        output_stream_params: Option<&StreamParamsRef>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+        options: StreamOptions,
+    ) -> Result<Stream> {
+        let boxed_stream = self.create_stream(
+            input_device,
+            input_stream_params,
+            output_device,
+            output_stream_params,
+            latency_frames,
+            |stream| StreamCallbacks::from_capi(stream, data_callback, state_callback, user_ptr),
+            user_ptr,
+            options,
+        )?;
+        let cubeb_stream = unsafe { Stream::from_ptr(Box::into_raw(boxed_stream) as *mut _) };
+        cubeb_log!(
+            "({:p}) Cubeb stream init successful.",
+            &cubeb_stream as *const Stream
+        );
+        Ok(cubeb_stream)
+    }
+
+    // Same as stream_init_with_options, with the Rust callbacks instead of the C callbacks.
+    #[allow(clippy::too_many_arguments)]
+    pub fn stream_init_with_callbacks(
+        &self,
+        input_device: DeviceId,
+        input_stream_params: Option<&StreamParamsRef>,
+        output_device: DeviceId,
+        output_stream_params: Option<&StreamParamsRef>,
+        latency_frames: u32,
+        callbacks: StreamCallbacks,
+        options: StreamOptions,
+    ) -> Result<Box<AudioUnitStream>> {
+        self.create_stream(
+            input_device,
+            input_stream_params,
+            output_device,
+            output_stream_params,
+            latency_frames,
+            |_| callbacks,
+            ptr::null_mut(),
+            options,
+        )
+    }
+
+    // Create a stream read and written by the application, instead of calling back the
+    // application to get or deliver the data.
+    pub fn blocking_stream_init(
+        &self,
+        input_device: DeviceId,
+        input_stream_params: Option<&StreamParamsRef>,
+        output_device: DeviceId,
+        output_stream_params: Option<&StreamParamsRef>,
+        latency_frames: u32,
+        options: BlockingOptions,
+    ) -> Result<BlockingStream> {
+        BlockingStream::new(
+            self,
+            input_device,
+            input_stream_params,
+            output_device,
+            output_stream_params,
+            latency_frames,
+            options,
+        )
+    }
+
+    // Create a stream with the callbacks created by `create_callbacks` from the stream's pointer.
+    // `user_ptr` is only kept for the cubeb C API and can be null.
+    #[allow(clippy::too_many_arguments)]
+    fn create_stream<F>(
+        &self,
+        input_device: DeviceId,
+        input_stream_params: Option<&StreamParamsRef>,
+        output_device: DeviceId,
+        output_stream_params: Option<&StreamParamsRef>,
+        latency_frames: u32,
+        create_callbacks: F,
+        user_ptr: *mut c_void,
+        options: StreamOptions,
+    ) -> Result<Box<AudioUnitStream>>
+    where
+        F: FnOnce(*mut ffi::cubeb_stream) -> StreamCallbacks,
+    {
+        if (!input_device.is_null() && input_stream_params.is_none())
+            || (!output_device.is_null() && output_stream_params.is_none())
+        {
+            return Err(Error::invalid_parameter());
+        }
+
+        options.validate()?;
+        if options.passes_through() && input_stream_params.is_some() {
+            cubeb_log!("The bit-perfect stream must be output only.");
+            return Err(Error::invalid_parameter());
+        }
+        if options.encoded_format.is_some()
+            && output_stream_params.map_or(true, |params| {
+                params.format() != SampleFormat::S16NE || params.channels() != 2
+            })
+        {
+            cubeb_log!("The encoded output must be in 16-bit stereo frames.");
+            return Err(Error::invalid_format());
+        }
+        if let Some(map) = options.input_channel_map.as_ref() {
+            if input_stream_params.map_or(true, |params| params.channels() as usize != map.len()) {
+                cubeb_log!(
+                    "The input channel map {:?} doesn't match the input channels.",
+                    map
+                );
+                return Err(Error::invalid_parameter());
+            }
+        }
+        if let Some(map) = options.output_channel_map.as_ref() {
+            if output_stream_params.map_or(true, |params| params.channels() as usize != map.len()) {
+                cubeb_log!(
+                    "The output channel map {:?} doesn't match the output channels.",
+                    map
+                );
+                return Err(Error::invalid_parameter());
+            }
+        }
+
+        let in_stm_settings = if let Some(params) = input_stream_params {
+            let in_device = create_device_info(input_device as AudioDeviceID, DeviceType::INPUT)
+                .map_err(|e| {
+                    cubeb_log!("Fail to create device info for input.");
+                    e
+                })?;
+            let stm_params = StreamParams::from(unsafe { (*params.as_ptr()) });
+            Some((stm_params, in_device))
+        } else {
+            None
+        };
+
+        let out_stm_settings = if let Some(params) = output_stream_params {
+            let out_device = create_device_info(output_device as AudioDeviceID, DeviceType::OUTPUT)
+                .map_err(|e| {
+                    cubeb_log!("Fail to create device info for output.");
+                    e
+                })?;
+            let stm_params = StreamParams::from(unsafe { (*params.as_ptr()) });
+            Some((stm_params, out_device))
+        } else {
+            None
+        };
+
+        // Latency cannot change if another stream is operating on the same device in parallel.
+        // In this case latency is set to the other stream value.
+        let latency_devices = get_latency_devices(
+            in_stm_settings.as_ref().map(|(_, device)| device),
+            out_stm_settings.as_ref().map(|(_, device)| device),
+        );
+        let latency = self.update_latency_by_adding_stream(&latency_devices, latency_frames);
+        if latency.latency != latency_frames {
+            cubeb_log!(
+                "Use {} latency {} instead of the requested latency {}.",
+                if latency.shared { "shared" } else { "clamped" },
+                latency.latency,
+                latency_frames
+            );
+        }
+
+        let mut boxed_stream = Box::new(AudioUnitStream::new(self, user_ptr, latency));
+        boxed_stream.requested_latency_frames = latency_frames;
+        boxed_stream.latency_devices = latency_devices;
+        boxed_stream.options = options;
+        boxed_stream.callbacks = create_callbacks(
+            boxed_stream.as_mut() as *mut AudioUnitStream as *mut ffi::cubeb_stream
+        );
+
+        boxed_stream.core_stream_data =
+            CoreStreamData::new(boxed_stream.as_ref(), in_stm_settings, out_stm_settings);
+
+        if let Err(r) = boxed_stream.core_stream_data.setup() {
+            cubeb_log!(
+                "({:p}) Could not setup the audiounit stream.",
+                boxed_stream.as_ref()
+            );
+            return Err(r);
+        }
+
+        Ok(boxed_stream)
     }
 
     fn add_devices_changed_listener(
@@ -2234,7 +3388,6 @@ impl AudioUnitContext {
 
 impl ContextOps for AudioUnitContext {
     fn init(_context_name: Option<&CStr>) -> Result<Context> {
-        set_notification_runloop();
         let ctx = Box::new(AudioUnitContext::new());
         Ok(unsafe { Context::from_ptr(Box::into_raw(ctx) as *mut _) })
     }
@@ -2290,7 +3443,8 @@ impl ContextOps for AudioUnitContext {
             cubeb_log!("Could not get acceptable latency range.");
             e
         })?;
-        Ok(cmp::max(range.mMinimum as u32, SAFE_MIN_LATENCY_FRAMES))
+        let (min_frames, _) = self.latency_bounds();
+        Ok(cmp::max(range.mMinimum as u32, min_frames))
     }
     #[cfg(target_os = "ios")]
     fn preferred_sample_rate(&mut self) -> Result<u32> {
@@ -2333,162 +3487,48 @@ impl ContextOps for AudioUnitContext {
         devtype: DeviceType,
         collection: &DeviceCollectionRef,
     ) -> Result<()> {
-        let input_devs = if devtype.contains(DeviceType::INPUT) {
-            audiounit_get_devices_of_type(DeviceType::INPUT)
-        } else {
-            Vec::<AudioObjectID>::new()
-        };
-
-        let output_devs = if devtype.contains(DeviceType::OUTPUT) {
-            audiounit_get_devices_of_type(DeviceType::OUTPUT)
-        } else {
-            Vec::<AudioObjectID>::new()
-        };
-
-        // Count number of input and output devices.  This is not necessarily the same as
-        // the count of raw devices supported by the system since, for example, with Soundflower
-        // installed, some devices may report as being both input *and* output and cubeb
-        // separates those into two different devices.
-
-        let mut devices: Vec<ffi::cubeb_device_info> =
-            allocate_array(output_devs.len() + input_devs.len());
-
-        let mut count = 0;
-        if devtype.contains(DeviceType::OUTPUT) {
-            for dev in output_devs {
-                let device = &mut devices[count];
-                if audiounit_create_device_from_hwdev(device, dev, DeviceType::OUTPUT).is_err()
-                    || is_aggregate_device(device)
-                {
-                    continue;
-                }
-                count += 1;
-            }
-        }
-
-        if devtype.contains(DeviceType::INPUT) {
-            for dev in input_devs {
-                let device = &mut devices[count];
-                if audiounit_create_device_from_hwdev(device, dev, DeviceType::INPUT).is_err()
-                    || is_aggregate_device(device)
-                {
-                    continue;
-                }
-                count += 1;
-            }
-        }
-
-        // Remove the redundant space, set len to count.
-        devices.truncate(count);
-
-        let coll = unsafe { &mut *collection.as_ptr() };
-        if count > 0 {
-            let (ptr, len) = forget_vec(devices);
-            coll.device = ptr;
-            coll.count = len;
-        } else {
-            coll.device = ptr::null_mut();
-            coll.count = 0;
-        }
-
-        Ok(())
+        self.enumerate_devices_with_options(devtype, collection, EnumerateOptions::default())
     }
     fn device_collection_destroy(&mut self, collection: &mut DeviceCollectionRef) -> Result<()> {
         assert!(!collection.as_ptr().is_null());
         let coll = unsafe { &mut *collection.as_ptr() };
         if coll.device.is_null() {
             return Ok(());
-        }
-
-        let mut devices = retake_forgotten_vec(coll.device, coll.count);
-        for device in &mut devices {
-            audiounit_device_destroy(device);
-        }
-        drop(devices); // Release the memory.
-        coll.device = ptr::null_mut();
-        coll.count = 0;
-        Ok(())
-    }
-    fn stream_init(
-        &mut self,
-        _stream_name: Option<&CStr>,
-        input_device: DeviceId,
-        input_stream_params: Option<&StreamParamsRef>,
-        output_device: DeviceId,
-        output_stream_params: Option<&StreamParamsRef>,
-        latency_frames: u32,
-        data_callback: ffi::cubeb_data_callback,
-        state_callback: ffi::cubeb_state_callback,
-        user_ptr: *mut c_void,
-    ) -> Result<Stream> {
-        if (!input_device.is_null() && input_stream_params.is_none())
-            || (!output_device.is_null() && output_stream_params.is_none())
-        {
-            return Err(Error::invalid_parameter());
-        }
-
-        // Latency cannot change if another stream is operating in parallel. In this case
-        // latency is set to the other stream value.
-        let global_latency_frames = self
-            .update_latency_by_adding_stream(latency_frames)
-            .unwrap();
-        if global_latency_frames != latency_frames {
-            cubeb_log!(
-                "Use global latency {} instead of the requested latency {}.",
-                global_latency_frames,
-                latency_frames
-            );
-        }
-
-        let in_stm_settings = if let Some(params) = input_stream_params {
-            let in_device = create_device_info(input_device as AudioDeviceID, DeviceType::INPUT)
-                .map_err(|e| {
-                    cubeb_log!("Fail to create device info for input.");
-                    e
-                })?;
-            let stm_params = StreamParams::from(unsafe { (*params.as_ptr()) });
-            Some((stm_params, in_device))
-        } else {
-            None
-        };
-
-        let out_stm_settings = if let Some(params) = output_stream_params {
-            let out_device = create_device_info(output_device as AudioDeviceID, DeviceType::OUTPUT)
-                .map_err(|e| {
-                    cubeb_log!("Fail to create device info for output.");
-                    e
-                })?;
-            let stm_params = StreamParams::from(unsafe { (*params.as_ptr()) });
-            Some((stm_params, out_device))
-        } else {
-            None
-        };
+        }
 
-        let mut boxed_stream = Box::new(AudioUnitStream::new(
-            self,
-            user_ptr,
+        let mut devices = retake_forgotten_vec(coll.device, coll.count);
+        for device in &mut devices {
+            audiounit_device_destroy(device);
+        }
+        drop(devices); // Release the memory.
+        coll.device = ptr::null_mut();
+        coll.count = 0;
+        Ok(())
+    }
+    fn stream_init(
+        &mut self,
+        stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&StreamParamsRef>,
+        output_device: DeviceId,
+        output_stream_params: Option<&StreamParamsRef>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<Stream> {
+        self.stream_init_with_options(
+            stream_name,
+            input_device,
+            input_stream_params,
+            output_device,
+            output_stream_params,
+            latency_frames,
             data_callback,
             state_callback,
-            global_latency_frames,
-        ));
-
-        boxed_stream.core_stream_data =
-            CoreStreamData::new(boxed_stream.as_ref(), in_stm_settings, out_stm_settings);
-
-        if let Err(r) = boxed_stream.core_stream_data.setup() {
-            cubeb_log!(
-                "({:p}) Could not setup the audiounit stream.",
-                boxed_stream.as_ref()
-            );
-            return Err(r);
-        }
-
-        let cubeb_stream = unsafe { Stream::from_ptr(Box::into_raw(boxed_stream) as *mut _) };
-        cubeb_log!(
-            "({:p}) Cubeb stream init successful.",
-            &cubeb_stream as *const Stream
-        );
-        Ok(cubeb_stream)
+            user_ptr,
+            StreamOptions::default(),
+        )
     }
     fn register_device_collection_changed(
         &mut self,
@@ -2530,6 +3570,12 @@ impl Drop for AudioUnitContext {
     }
 }
 
+impl Default for AudioUnitContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 unsafe impl Send for AudioUnitContext {}
 unsafe impl Sync for AudioUnitContext {}
 
@@ -2538,7 +3584,17 @@ struct CoreStreamData<'ctx> {
     stm_ptr: *const AudioUnitStream<'ctx>,
     aggregate_device: AggregateDevice,
     mixer: Option<Mixer>,
+    // Mix the device channels of the input to the layout of the input stream parameters.
+    input_mixer: Option<Mixer>,
+    input_mixer_buffer: Vec<u8>,
     resampler: Resampler,
+    // Adapt the device buffer size to the render quantum of the data callback, if any.
+    render_quantum: Option<Box<RenderQuantumAdapter>>,
+    // Copy the audio into WAV files, if the context asks for it.
+    debug_capture: Option<DebugCapture>,
+    // Measure the levels of the input and output, if the stream options ask for it.
+    input_meter: Option<MeterAccumulator>,
+    output_meter: Option<MeterAccumulator>,
     // Stream creation parameters.
     input_stream_params: StreamParams,
     output_stream_params: StreamParams,
@@ -2551,11 +3607,14 @@ struct CoreStreamData<'ctx> {
     // Info of the I/O devices.
     input_device: device_info,
     output_device: device_info,
-    // Sample rates of the I/O devices.
-    input_hw_rate: f64,
-    output_hw_rate: f64,
+    // Sample rates of the I/O devices. Written on the serial queue or by the rate listener, while
+    // the callbacks and the listener read them.
+    input_hw_rate: atomic::Atomic<f64>,
+    output_hw_rate: atomic::Atomic<f64>,
     // Channel layout of the output AudioUnit.
     device_layout: ChannelLayout,
+    // The most frames given to the callbacks, to size the buffers they use up front.
+    max_frames_per_slice: u32,
     // Hold the input samples in every input callback iteration.
     // Only accessed on input/output callback thread and during initial configure.
     input_linear_buffer: Option<Box<AutoArrayWrapper>>,
@@ -2565,6 +3624,16 @@ struct CoreStreamData<'ctx> {
     input_alive_listener: Option<device_property_listener>,
     input_source_listener: Option<device_property_listener>,
     output_source_listener: Option<device_property_listener>,
+    input_rate_listener: Option<device_property_listener>,
+    output_rate_listener: Option<device_property_listener>,
+    // The nominal sample rates of the devices before the stream changed them.
+    original_device_rates: Vec<(AudioDeviceID, f64)>,
+    // The devices in hog mode, released when dropped.
+    hogged_devices: Vec<HogMode>,
+    // True if the data callback renders directly into the output device buffer.
+    passthrough: bool,
+    // The output stream switched to the encoded format.
+    encoded_passthrough: Option<EncodedPassthrough>,
 }
 
 impl<'ctx> Default for CoreStreamData<'ctx> {
@@ -2573,7 +3642,13 @@ impl<'ctx> Default for CoreStreamData<'ctx> {
             stm_ptr: ptr::null(),
             aggregate_device: AggregateDevice::default(),
             mixer: None,
+            input_mixer: None,
+            input_mixer_buffer: Vec::new(),
             resampler: Resampler::default(),
+            render_quantum: None,
+            debug_capture: None,
+            input_meter: None,
+            output_meter: None,
             input_stream_params: StreamParams::from(ffi::cubeb_stream_params {
                 format: ffi::CUBEB_SAMPLE_FLOAT32NE,
                 rate: 0,
@@ -2594,15 +3669,22 @@ impl<'ctx> Default for CoreStreamData<'ctx> {
             output_unit: ptr::null_mut(),
             input_device: device_info::default(),
             output_device: device_info::default(),
-            input_hw_rate: 0_f64,
-            output_hw_rate: 0_f64,
+            input_hw_rate: atomic::Atomic::new(0_f64),
+            output_hw_rate: atomic::Atomic::new(0_f64),
             device_layout: ChannelLayout::UNDEFINED,
+            max_frames_per_slice: 0,
             input_linear_buffer: None,
             default_input_listener: None,
             default_output_listener: None,
             input_alive_listener: None,
             input_source_listener: None,
             output_source_listener: None,
+            input_rate_listener: None,
+            output_rate_listener: None,
+            original_device_rates: Vec::new(),
+            hogged_devices: Vec::new(),
+            passthrough: false,
+            encoded_passthrough: None,
         }
     }
 }
@@ -2630,7 +3712,13 @@ impl<'ctx> CoreStreamData<'ctx> {
             stm_ptr: stm,
             aggregate_device: AggregateDevice::default(),
             mixer: None,
+            input_mixer: None,
+            input_mixer_buffer: Vec::new(),
             resampler: Resampler::default(),
+            render_quantum: None,
+            debug_capture: None,
+            input_meter: None,
+            output_meter: None,
             input_stream_params: in_stm_params,
             output_stream_params: out_stm_params,
             input_desc: AudioStreamBasicDescription::default(),
@@ -2639,15 +3727,22 @@ impl<'ctx> CoreStreamData<'ctx> {
             output_unit: ptr::null_mut(),
             input_device: in_dev,
             output_device: out_dev,
-            input_hw_rate: 0_f64,
-            output_hw_rate: 0_f64,
+            input_hw_rate: atomic::Atomic::new(0_f64),
+            output_hw_rate: atomic::Atomic::new(0_f64),
             device_layout: ChannelLayout::UNDEFINED,
+            max_frames_per_slice: 0,
             input_linear_buffer: None,
             default_input_listener: None,
             default_output_listener: None,
             input_alive_listener: None,
             input_source_listener: None,
             output_source_listener: None,
+            input_rate_listener: None,
+            output_rate_listener: None,
+            original_device_rates: Vec::new(),
+            hogged_devices: Vec::new(),
+            passthrough: false,
+            encoded_passthrough: None,
         }
     }
 
@@ -2674,10 +3769,144 @@ impl<'ctx> CoreStreamData<'ctx> {
         self.input_stream_params.rate() > 0
     }
 
+    // The format of the frames in the input callback, which are at the rate of the input device.
+    fn input_callback_description(&self) -> AudioStreamBasicDescription {
+        let mut desc = self.input_desc;
+        desc.mSampleRate = self.input_hw_rate.load(Ordering::SeqCst);
+        desc
+    }
+
+    // We use a resampler because input AudioUnit operates
+    // reliable only in the capture device sample rate.
+    // Resampler will convert it to the user sample rate
+    // and deliver it to the callback, through the render quantum adapter if any.
+    fn create_resampler(&mut self) {
+        let target_sample_rate = if self.has_input() {
+            self.input_stream_params.rate()
+        } else {
+            assert!(self.has_output());
+            self.output_stream_params.rate()
+        };
+        let resampler_input_params = if self.has_input() {
+            let mut params = unsafe { (*(self.input_stream_params.as_ptr())) };
+            params.rate = self.input_hw_rate.load(Ordering::SeqCst) as u32;
+            Some(params)
+        } else {
+            None
+        };
+        let resampler_output_params = if self.has_output() {
+            let params = unsafe { (*(self.output_stream_params.as_ptr())) };
+            Some(params)
+        } else {
+            None
+        };
+        let (data_callback, user_ptr): (ffi::cubeb_data_callback, *mut c_void) =
+            match self.render_quantum.as_mut() {
+                Some(adapter) => (
+                    Some(render_quantum_data_callback),
+                    adapter.as_mut() as *mut RenderQuantumAdapter as *mut c_void,
+                ),
+                None => (
+                    Some(audiounit_stream_data_callback),
+                    self.stm_ptr as *mut c_void,
+                ),
+            };
+        self.resampler = Resampler::new(
+            self.stm_ptr as *mut ffi::cubeb_stream,
+            resampler_input_params,
+            resampler_output_params,
+            target_sample_rate,
+            data_callback,
+            user_ptr,
+        );
+    }
+
+    // Follow a new nominal rate of the input device without setting up the stream again. The
+    // input AudioUnit delivers the device rate, so its format and the resampler are updated.
+    // The AudioUnits must be stopped.
+    fn update_input_rate(&mut self, rate: f64) -> Result<()> {
+        assert!(!self.input_unit.is_null());
+        let mut desc = AudioStreamBasicDescription::default();
+        let mut size = mem::size_of::<AudioStreamBasicDescription>();
+        let r = audio_unit_get_property(
+            self.input_unit,
+            kAudioUnitProperty_StreamFormat,
+            kAudioUnitScope_Output,
+            AU_IN_BUS,
+            &mut desc,
+            &mut size,
+        );
+        if r != NO_ERR {
+            cubeb_log!(
+                "AudioUnitGetProperty/input/kAudioUnitProperty_StreamFormat rv={}",
+                r
+            );
+            return Err(Error::error());
+        }
+        desc.mSampleRate = rate;
+
+        // The format can only be changed while the unit is uninitialized.
+        audio_unit_uninitialize(self.input_unit);
+        let r = audio_unit_set_property(
+            self.input_unit,
+            kAudioUnitProperty_StreamFormat,
+            kAudioUnitScope_Output,
+            AU_IN_BUS,
+            &desc,
+            mem::size_of::<AudioStreamBasicDescription>(),
+        );
+        if r != NO_ERR {
+            cubeb_log!(
+                "AudioUnitSetProperty/input/kAudioUnitProperty_StreamFormat rv={}",
+                r
+            );
+            return Err(Error::error());
+        }
+        let r = audio_unit_initialize(self.input_unit);
+        if r != NO_ERR {
+            cubeb_log!("AudioUnitInitialize/input rv={}", r);
+            return Err(Error::error());
+        }
+
+        self.input_hw_rate.store(rate, Ordering::SeqCst);
+        // The buffered input and the frames counted so far are at the previous rate.
+        self.input_linear_buffer.as_mut().unwrap().clear();
+        let stream = unsafe { &(*self.stm_ptr) };
+        stream.frames_read.store(0, Ordering::SeqCst);
+        stream.frames_written.store(0, Ordering::SeqCst);
+        if let (Some(window), Some(meter)) = (stream.options.meter_window, self.input_meter.take())
+        {
+            self.input_meter = Some(MeterAccumulator::new(
+                Some(meter),
+                &self.input_callback_description(),
+                window,
+            ));
+        }
+        // The captured input is written at the device rate, into new files.
+        if self.debug_capture.is_some() {
+            self.debug_capture = self.create_debug_capture();
+        }
+        if !self.passthrough {
+            self.create_resampler();
+        }
+        Ok(())
+    }
+
     fn has_output(&self) -> bool {
         self.output_stream_params.rate() > 0
     }
 
+    fn output_tap_format(&self) -> Option<TapFormat> {
+        if !self.has_output() {
+            return None;
+        }
+        Some(TapFormat {
+            format: self.output_stream_params.format(),
+            channels: self.output_desc.mChannelsPerFrame,
+            rate: self.output_desc.mSampleRate as u32,
+        })
+    }
+
     fn setup(&mut self) -> Result<()> {
         if self
             .input_stream_params
@@ -2692,6 +3921,24 @@ impl<'ctx> CoreStreamData<'ctx> {
             return Err(Error::not_supported());
         }
 
+        assert!(!self.stm_ptr.is_null());
+        // A failed setup is retried by reinit without closing the stream, so the devices may
+        // still be held.
+        self.hogged_devices.clear();
+        if unsafe { (*self.stm_ptr).options.hogs_devices() } {
+            self.hog_devices()?;
+        }
+        if let Some(format) = unsafe { (*self.stm_ptr).options.encoded_format } {
+            self.encoded_passthrough = Some(EncodedPassthrough::new(
+                self.output_device.id,
+                format,
+                f64::from(self.output_stream_params.rate()),
+            )?);
+        }
+        if unsafe { (*self.stm_ptr).options.set_device_sample_rate } {
+            self.set_device_sample_rates();
+        }
+
         let mut in_dev_info = self.input_device.clone();
         let mut out_dev_info = self.output_device.clone();
 
@@ -2729,14 +3976,15 @@ impl<'ctx> CoreStreamData<'ctx> {
             })?;
 
             cubeb_log!(
-                "({:p}) Opening input side: rate {}, channels {}, format {:?}, layout {:?}, prefs {:?}, latency in frames {}.",
+                "({:p}) Opening input side: rate {}, channels {}, format {:?}, layout {:?}, prefs {:?}, latency in frames {} (shared: {}).",
                 self.stm_ptr,
                 self.input_stream_params.rate(),
                 self.input_stream_params.channels(),
                 self.input_stream_params.format(),
                 self.input_stream_params.layout(),
                 self.input_stream_params.prefs(),
-                stream.latency_frames
+                stream.latency_frames,
+                stream.latency_shared
             );
 
             // Get input device sample rate.
@@ -2762,7 +4010,8 @@ impl<'ctx> CoreStreamData<'ctx> {
                 self.stm_ptr,
                 input_hw_desc
             );
-            self.input_hw_rate = input_hw_desc.mSampleRate;
+            self.input_hw_rate
+                .store(input_hw_desc.mSampleRate, Ordering::SeqCst);
 
             // Set format description according to the input params.
             self.input_desc =
@@ -2786,7 +4035,45 @@ impl<'ctx> CoreStreamData<'ctx> {
             let mut src_desc = self.input_desc;
             // Input AudioUnit must be configured with device's sample rate.
             // we will resample inside input callback.
-            src_desc.mSampleRate = self.input_hw_rate;
+            src_desc.mSampleRate = self.input_hw_rate.load(Ordering::SeqCst);
+
+            // The channels picked by the map are captured as they are, without the mixer.
+            let hw_channels = input_hw_desc.mChannelsPerFrame;
+            let input_layout = self.input_stream_params.layout();
+            self.input_mixer = if stream.options.input_channel_map.is_none()
+                && input_layout != ChannelLayout::UNDEFINED
+                && hw_channels != 0
+            {
+                let device_layout = audiounit_get_input_channel_layout(self.input_unit);
+                cubeb_log!(
+                    "({:p}) Input hardware layout: {:?}",
+                    self.stm_ptr,
+                    device_layout
+                );
+                if hw_channels != self.input_stream_params.channels()
+                    || device_layout != input_layout
+                {
+                    cubeb_log!(
+                        "({:p}) Incompatible input channel layouts detected, setting up remixer",
+                        self.stm_ptr
+                    );
+                    // Capture all the device channels and mix them in the input callback.
+                    src_desc.mChannelsPerFrame = hw_channels;
+                    src_desc.mBytesPerFrame = (src_desc.mBitsPerChannel / 8) * hw_channels;
+                    src_desc.mBytesPerPacket = src_desc.mBytesPerFrame * src_desc.mFramesPerPacket;
+                    Some(Mixer::new(
+                        self.input_stream_params.format(),
+                        hw_channels,
+                        device_layout,
+                        self.input_stream_params.channels(),
+                        input_layout,
+                    ))
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
             let r = audio_unit_set_property(
                 self.input_unit,
                 kAudioUnitProperty_StreamFormat,
@@ -2803,13 +4090,18 @@ impl<'ctx> CoreStreamData<'ctx> {
                 return Err(Error::error());
             }
 
+            if let Some(map) = stream.options.input_channel_map.as_ref() {
+                self.set_input_channel_map(map, input_hw_desc.mChannelsPerFrame)?;
+            }
+
             // Frames per buffer in the input callback.
+            let max_frames = self.callback_max_frames(self.input_unit, io_side::INPUT);
             let r = audio_unit_set_property(
                 self.input_unit,
                 kAudioUnitProperty_MaximumFramesPerSlice,
                 kAudioUnitScope_Global,
                 AU_IN_BUS,
-                &stream.latency_frames,
+                &max_frames,
                 mem::size_of::<u32>(),
             );
             if r != NO_ERR {
@@ -2864,14 +4156,15 @@ impl<'ctx> CoreStreamData<'ctx> {
             })?;
 
             cubeb_log!(
-                "({:p}) Opening output side: rate {}, channels {}, format {:?}, layout {:?}, prefs {:?}, latency in frames {}.",
+                "({:p}) Opening output side: rate {}, channels {}, format {:?}, layout {:?}, prefs {:?}, latency in frames {} (shared: {}).",
                 self.stm_ptr,
                 self.output_stream_params.rate(),
                 self.output_stream_params.channels(),
                 self.output_stream_params.format(),
                 self.output_stream_params.layout(),
                 self.output_stream_params.prefs(),
-                stream.latency_frames
+                stream.latency_frames,
+                stream.latency_shared
             );
 
             self.output_desc =
@@ -2906,19 +4199,55 @@ impl<'ctx> CoreStreamData<'ctx> {
                 self.stm_ptr,
                 output_hw_desc
             );
-            self.output_hw_rate = output_hw_desc.mSampleRate;
+            self.output_hw_rate
+                .store(output_hw_desc.mSampleRate, Ordering::SeqCst);
             let hw_channels = output_hw_desc.mChannelsPerFrame;
 
-            // Set the input layout to match the output device layout.
+            // Set the input layout to match the output device layout, unless the channels are
+            // routed by the map.
             self.device_layout = audiounit_get_current_channel_layout(self.output_unit);
-            audiounit_set_channel_layout(self.output_unit, io_side::OUTPUT, self.device_layout);
+            if stream.options.output_channel_map.is_none() {
+                audiounit_set_channel_layout(self.output_unit, io_side::OUTPUT, self.device_layout);
+            }
             cubeb_log!(
                 "({:p}) Output hardware layout: {:?}",
                 self.stm_ptr,
                 self.device_layout
             );
 
-            self.mixer = if hw_channels != self.output_stream_params.channels()
+            // The encoded frames can't be mixed with the other streams of the device.
+            if stream.options.encoded_format.is_some() {
+                self.output_desc.mFormatFlags |= kAudioFormatFlagIsNonMixable;
+            }
+            // The output matching the device exactly skips the resampler and the mixer. The
+            // render quantum adapter and the channel map need their own buffers, and the
+            // duplex output is resampled with the input.
+            self.passthrough = !self.has_input()
+                && stream.options.render_quantum.is_none()
+                && stream.options.output_channel_map.is_none()
+                && is_bit_perfect_description(&self.output_desc, &output_hw_desc);
+            if stream.options.passes_through() && !self.passthrough {
+                cubeb_log!(
+                    "({:p}) The output description {:?} doesn't match the hardware. No bit-perfect output.",
+                    self.stm_ptr,
+                    self.output_desc
+                );
+                return Err(Error::invalid_format());
+            }
+            cubeb_log!(
+                "({:p}) Output passthrough: {}",
+                self.stm_ptr,
+                self.passthrough
+            );
+
+            // The channels routed by the map, or passed through, are played as they are, without
+            // the mixer.
+            self.mixer = if let Some(map) = stream.options.output_channel_map.as_ref() {
+                self.set_output_channel_map(map, hw_channels)?;
+                None
+            } else if self.passthrough {
+                None
+            } else if hw_channels != self.output_stream_params.channels()
                 || self.device_layout != self.output_stream_params.layout()
             {
                 cubeb_log!("Incompatible channel layouts detected, setting up remixer");
@@ -2966,13 +4295,14 @@ impl<'ctx> CoreStreamData<'ctx> {
                 return Err(r);
             }
 
-            // Frames per buffer in the input callback.
+            // Frames per buffer in the output callback.
+            let max_frames = self.callback_max_frames(self.output_unit, io_side::OUTPUT);
             let r = audio_unit_set_property(
                 self.output_unit,
                 kAudioUnitProperty_MaximumFramesPerSlice,
                 kAudioUnitScope_Global,
                 AU_OUT_BUS,
-                &stream.latency_frames,
+                &max_frames,
                 mem::size_of::<u32>(),
             );
             if r != NO_ERR {
@@ -3008,10 +4338,32 @@ impl<'ctx> CoreStreamData<'ctx> {
             cubeb_log!("({:p}) Output audiounit init successfully.", self.stm_ptr);
         }
 
-        // We use a resampler because input AudioUnit operates
-        // reliable only in the capture device sample rate.
-        // Resampler will convert it to the user sample rate
-        // and deliver it to the callback.
+        self.max_frames_per_slice = 0;
+        for unit in &[self.input_unit, self.output_unit] {
+            if unit.is_null() {
+                continue;
+            }
+            let frames = get_max_frames_per_slice(*unit).map_err(|r| {
+                cubeb_log!(
+                    "AudioUnitGetProperty/kAudioUnitProperty_MaximumFramesPerSlice rv={}",
+                    r
+                );
+                Error::error()
+            })?;
+            self.max_frames_per_slice = cmp::max(self.max_frames_per_slice, frames);
+        }
+
+        // Nothing is allocated in the input callback.
+        let max_frames = self.max_frames_per_slice as usize;
+        self.input_mixer_buffer = match self.input_mixer.as_mut() {
+            Some(mixer) => {
+                mixer.update_buffer_size(max_frames);
+                vec![0; max_frames * self.input_desc.mBytesPerFrame as usize]
+            }
+            None => Vec::new(),
+        };
+
+        // The rate of the frames given to the data callback.
         let target_sample_rate = if self.has_input() {
             self.input_stream_params.rate()
         } else {
@@ -3019,28 +4371,71 @@ impl<'ctx> CoreStreamData<'ctx> {
             self.output_stream_params.rate()
         };
 
-        let resampler_input_params = if self.has_input() {
-            let mut params = unsafe { (*(self.input_stream_params.as_ptr())) };
-            params.rate = self.input_hw_rate as u32;
-            Some(params)
-        } else {
-            None
+        // The meters are kept across the setups, so the readers keep getting the levels.
+        let meter_window = stream.options.meter_window;
+        self.input_meter = match meter_window {
+            Some(window) if self.has_input() => Some(MeterAccumulator::new(
+                self.input_meter.take(),
+                &self.input_callback_description(),
+                window,
+            )),
+            _ => None,
         };
-        let resampler_output_params = if self.has_output() {
-            let params = unsafe { (*(self.output_stream_params.as_ptr())) };
-            Some(params)
-        } else {
-            None
+        // Measure what's given to the device, after the channels are mixed.
+        self.output_meter = match meter_window {
+            Some(window) if self.has_output() => Some(MeterAccumulator::new(
+                self.output_meter.take(),
+                &self.output_desc,
+                window,
+            )),
+            _ => None,
         };
 
-        self.resampler = Resampler::new(
-            self.stm_ptr as *mut ffi::cubeb_stream,
-            resampler_input_params,
-            resampler_output_params,
-            target_sample_rate,
-            stream.data_callback,
-            stream.user_ptr,
-        );
+        self.render_quantum = stream.options.render_quantum.map(|quantum| {
+            let input_frame_size = if self.has_input() {
+                self.input_desc.mBytesPerFrame as usize
+            } else {
+                0
+            };
+            // The output description may have been changed for the mixer. Use the format of
+            // the data callback.
+            let output_frame_size = if self.has_output() {
+                cubeb_sample_size(self.output_stream_params.format())
+                    * self.output_stream_params.channels() as usize
+            } else {
+                0
+            };
+            // The output unit converts to the stream rate itself, and the resampler converts the
+            // input slices from the device rate.
+            let rate_ratio = if self.has_input() {
+                f64::max(
+                    f64::from(target_sample_rate) / self.input_hw_rate.load(Ordering::SeqCst),
+                    1.0,
+                )
+            } else {
+                1.0
+            };
+            let max_adapter_frames =
+                (f64::from(self.max_frames_per_slice) * rate_ratio).ceil() as u32 + 1;
+            cubeb_log!(
+                "({:p}) Use render quantum of {} frames.",
+                self.stm_ptr,
+                quantum
+            );
+            Box::new(RenderQuantumAdapter::new(
+                self.stm_ptr as *mut ffi::cubeb_stream,
+                Some(audiounit_stream_data_callback),
+                self.stm_ptr as *mut c_void,
+                quantum,
+                input_frame_size,
+                output_frame_size,
+                max_adapter_frames,
+            ))
+        });
+        // The passthrough output calls the data callback directly.
+        if !self.passthrough {
+            self.create_resampler();
+        }
 
         if !self.input_unit.is_null() {
             let r = audio_unit_initialize(self.input_unit);
@@ -3050,58 +4445,311 @@ impl<'ctx> CoreStreamData<'ctx> {
             }
         }
 
-        if !self.output_unit.is_null() {
-            let r = audio_unit_initialize(self.output_unit);
-            if r != NO_ERR {
-                cubeb_log!("AudioUnitInitialize/output rv={}", r);
-                return Err(Error::error());
+        if !self.output_unit.is_null() {
+            let r = audio_unit_initialize(self.output_unit);
+            if r != NO_ERR {
+                cubeb_log!("AudioUnitInitialize/output rv={}", r);
+                return Err(Error::error());
+            }
+
+            let device_latency = audiounit_get_device_presentation_latency(
+                self.output_device.id,
+                kAudioDevicePropertyScopeOutput,
+            );
+            stream
+                .current_latency_frames
+                .store(device_latency, Ordering::SeqCst);
+            // The device latency is in frames at the device rate.
+            let hw_rate = self.output_hw_rate.load(Ordering::SeqCst);
+            let drain_latency = if hw_rate > 0.0 {
+                (f64::from(device_latency) * self.output_desc.mSampleRate / hw_rate) as u32
+            } else {
+                device_latency
+            };
+            stream
+                .drain_latency_frames
+                .store(drain_latency, Ordering::SeqCst);
+
+            let mut unit_s: f64 = 0.0;
+            let mut size = mem::size_of_val(&unit_s);
+            if audio_unit_get_property(
+                self.output_unit,
+                kAudioUnitProperty_Latency,
+                kAudioUnitScope_Global,
+                0,
+                &mut unit_s,
+                &mut size,
+            ) == NO_ERR
+            {
+                let unit_latency = (unit_s * self.output_desc.mSampleRate) as u32;
+                stream
+                    .current_latency_frames
+                    .fetch_add(unit_latency, Ordering::SeqCst);
+                stream
+                    .drain_latency_frames
+                    .fetch_add(unit_latency, Ordering::SeqCst);
+            }
+        } else {
+            stream.current_latency_frames.store(0, Ordering::SeqCst);
+            stream.drain_latency_frames.store(0, Ordering::SeqCst);
+        }
+
+        if let Some(adapter) = self.render_quantum.as_ref() {
+            stream
+                .current_latency_frames
+                .fetch_add(adapter.latency(), Ordering::SeqCst);
+            stream
+                .drain_latency_frames
+                .fetch_add(adapter.latency(), Ordering::SeqCst);
+        }
+
+        // The observer of the output tap reads the data in the format it got on attaching.
+        {
+            let mut tap = stream.output_tap.lock().unwrap();
+            if tap.as_ref().map_or(false, |sender| {
+                Some(sender.format()) != self.output_tap_format()
+            }) {
+                cubeb_log!(
+                    "({:p}) Close the output tap since the output format changed.",
+                    self.stm_ptr
+                );
+                *tap = None;
+            }
+        }
+
+        if let Err(r) = self.install_system_changed_callback() {
+            cubeb_log!(
+                "({:p}) Could not install the device change callback.",
+                self.stm_ptr
+            );
+            return Err(r);
+        }
+
+        if let Err(r) = self.install_device_changed_callback() {
+            cubeb_log!(
+                "({:p}) Could not install all device change callback.",
+                self.stm_ptr
+            );
+            return Err(r);
+        }
+
+        self.debug_capture = self.create_debug_capture();
+
+        Ok(())
+    }
+
+    // The most frames given to the callbacks of `unit`, which is the latency. The render quantum
+    // FIFOs are sized from it up front, so they also take the device buffer if it couldn't be
+    // changed to the latency.
+    fn callback_max_frames(&self, unit: AudioUnit, side: io_side) -> u32 {
+        let stream = unsafe { &(*self.stm_ptr) };
+        if stream.options.render_quantum.is_none() {
+            return stream.latency_frames;
+        }
+        cmp::max(
+            stream.latency_frames,
+            get_buffer_size(unit, side).unwrap_or(0),
+        )
+    }
+
+    fn hog_devices(&mut self) -> Result<()> {
+        let mut devices = Vec::new();
+        if self.has_input() {
+            devices.push(self.input_device.id);
+        }
+        if self.has_output() && !devices.contains(&self.output_device.id) {
+            devices.push(self.output_device.id);
+        }
+        for device in devices {
+            match HogMode::acquire(device) {
+                Ok(hog_mode) => self.hogged_devices.push(hog_mode),
+                Err(e) => {
+                    cubeb_log!(
+                        "({:p}) Could not take the exclusive access of device {}.",
+                        self.stm_ptr,
+                        device
+                    );
+                    // Release the devices already taken.
+                    self.hogged_devices.clear();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Run the devices at the rates of the stream parameters when they can, so the stream doesn't
+    // need to be resampled. Otherwise, the stream is resampled as usual.
+    fn set_device_sample_rates(&mut self) {
+        let mut devices = Vec::new();
+        if self.has_input() {
+            devices.push((self.input_device.id, self.input_stream_params.rate()));
+        }
+        if self.has_output() {
+            devices.push((self.output_device.id, self.output_stream_params.rate()));
+        }
+        for (device, rate) in devices {
+            let rate = f64::from(rate);
+            let original = match get_device_nominal_sample_rate(device) {
+                Ok(original) => original,
+                Err(_) => continue,
+            };
+            if original == rate {
+                continue;
             }
-
-            stream.current_latency_frames.store(
-                audiounit_get_device_presentation_latency(
-                    self.output_device.id,
-                    kAudioDevicePropertyScopeOutput,
-                ),
-                Ordering::SeqCst,
+            if set_device_nominal_sample_rate(device, rate).is_err() {
+                cubeb_log!(
+                    "({:p}) Could not set the rate of device {} to {}. Resample the stream.",
+                    self.stm_ptr,
+                    device,
+                    rate
+                );
+                continue;
+            }
+            cubeb_log!(
+                "({:p}) Set the rate of device {} from {} to {}.",
+                self.stm_ptr,
+                device,
+                original,
+                rate
             );
-
-            let mut unit_s: f64 = 0.0;
-            let mut size = mem::size_of_val(&unit_s);
-            if audio_unit_get_property(
-                self.output_unit,
-                kAudioUnitProperty_Latency,
-                kAudioUnitScope_Global,
-                0,
-                &mut unit_s,
-                &mut size,
-            ) == NO_ERR
+            // Only the rate before the first change is restored.
+            if !self
+                .original_device_rates
+                .iter()
+                .any(|(changed, _)| *changed == device)
             {
-                stream.current_latency_frames.fetch_add(
-                    (unit_s * self.output_desc.mSampleRate) as u32,
-                    Ordering::SeqCst,
+                self.original_device_rates.push((device, original));
+            }
+        }
+    }
+
+    fn restore_device_sample_rates(&mut self) {
+        for (device, rate) in self.original_device_rates.drain(..) {
+            if set_device_nominal_sample_rate(device, rate).is_err() {
+                cubeb_log!(
+                    "({:p}) Could not restore the rate of device {} to {}.",
+                    self.stm_ptr,
+                    device,
+                    rate
                 );
             }
         }
+    }
 
-        if let Err(r) = self.install_system_changed_callback() {
+    // Let the input AudioUnit pick the device channels in `map` for the channels of the stream.
+    fn set_input_channel_map(&self, map: &[u32], device_channels: u32) -> Result<()> {
+        assert_eq!(map.len(), self.input_desc.mChannelsPerFrame as usize);
+        if let Some(channel) = map.iter().find(|channel| **channel >= device_channels) {
             cubeb_log!(
-                "({:p}) Could not install the device change callback.",
-                self.stm_ptr
+                "({:p}) Input channel {} is out of the {} channels of the device.",
+                self.stm_ptr,
+                channel,
+                device_channels
             );
-            return Err(r);
+            return Err(Error::invalid_parameter());
         }
-
-        if let Err(r) = self.install_device_changed_callback() {
+        let map: Vec<i32> = map.iter().map(|channel| *channel as i32).collect();
+        let r = audio_unit_set_property(
+            self.input_unit,
+            kAudioOutputUnitProperty_ChannelMap,
+            kAudioUnitScope_Output,
+            AU_IN_BUS,
+            map.as_ptr(),
+            map.len() * mem::size_of::<i32>(),
+        );
+        if r != NO_ERR {
             cubeb_log!(
-                "({:p}) Could not install all device change callback.",
-                self.stm_ptr
+                "AudioUnitSetProperty/input/kAudioOutputUnitProperty_ChannelMap rv={}",
+                r
             );
-            return Err(r);
+            return Err(Error::error());
         }
+        cubeb_log!("({:p}) Input channel map: {:?}", self.stm_ptr, map);
+        Ok(())
+    }
 
+    // Let the output AudioUnit play the channels of the stream on the device channels in `map`.
+    fn set_output_channel_map(&self, map: &[u32], device_channels: u32) -> Result<()> {
+        assert_eq!(map.len(), self.output_desc.mChannelsPerFrame as usize);
+        // For each device channel, the stream channel played on it, or -1 for silence.
+        let mut device_map = vec![-1_i32; device_channels as usize];
+        for (stream_channel, device_channel) in map.iter().enumerate() {
+            match device_map.get_mut(*device_channel as usize) {
+                Some(channel) if *channel == -1 => *channel = stream_channel as i32,
+                _ => {
+                    cubeb_log!(
+                        "({:p}) Output channel {} is out of the {} channels of the device, or used twice.",
+                        self.stm_ptr,
+                        device_channel,
+                        device_channels
+                    );
+                    return Err(Error::invalid_parameter());
+                }
+            }
+        }
+        let r = audio_unit_set_property(
+            self.output_unit,
+            kAudioOutputUnitProperty_ChannelMap,
+            kAudioUnitScope_Output,
+            AU_OUT_BUS,
+            device_map.as_ptr(),
+            device_map.len() * mem::size_of::<i32>(),
+        );
+        if r != NO_ERR {
+            cubeb_log!(
+                "AudioUnitSetProperty/output/kAudioOutputUnitProperty_ChannelMap rv={}",
+                r
+            );
+            return Err(Error::error());
+        }
+        cubeb_log!("({:p}) Output channel map: {:?}", self.stm_ptr, device_map);
         Ok(())
     }
 
+    fn create_debug_capture(&self) -> Option<DebugCapture> {
+        assert!(!self.stm_ptr.is_null());
+        let stream = unsafe { &(*self.stm_ptr) };
+        let dir = stream.context.debug_capture_dir()?;
+        let count = stream.context.debug_captures.fetch_add(1, Ordering::SeqCst);
+        let prefix = format!("cubeb-{}-{:p}-{}", process::id(), self.stm_ptr, count);
+        let input = if self.has_input() {
+            WavFormat::from_description(&self.input_callback_description())
+        } else {
+            None
+        };
+        let (callback_output, device_output) = if self.has_output() {
+            (
+                create_stream_description(&self.output_stream_params)
+                    .ok()
+                    .and_then(|desc| WavFormat::from_description(&desc)),
+                WavFormat::from_description(&self.output_desc),
+            )
+        } else {
+            (None, None)
+        };
+        match DebugCapture::new(dir, &prefix, input, callback_output, device_output) {
+            Ok(capture) => {
+                cubeb_log!(
+                    "({:p}) Capture the audio into {}/{}-*.wav",
+                    self.stm_ptr,
+                    dir.display(),
+                    prefix
+                );
+                Some(capture)
+            }
+            Err(e) => {
+                cubeb_log!(
+                    "({:p}) Could not capture the audio into {}: {}",
+                    self.stm_ptr,
+                    dir.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
     fn close(&mut self) {
         if !self.input_unit.is_null() {
             audio_unit_uninitialize(self.input_unit);
@@ -3116,8 +4764,16 @@ impl<'ctx> CoreStreamData<'ctx> {
         }
 
         self.resampler.destroy();
+        self.passthrough = false;
+        self.render_quantum = None;
+        // Wait until the captured audio is written, after the callbacks are done.
+        self.debug_capture = None;
         self.mixer = None;
+        self.input_mixer = None;
         self.aggregate_device = AggregateDevice::default();
+        // Restore the physical format before the other processes can use the device again.
+        self.encoded_passthrough = None;
+        self.hogged_devices.clear();
 
         if self.uninstall_system_changed_callback().is_err() {
             cubeb_log!(
@@ -3156,6 +4812,19 @@ impl<'ctx> CoreStreamData<'ctx> {
                 cubeb_log!("AudioObjectAddPropertyListener/output/kAudioDevicePropertyDataSource rv={}, device id={}", rv, self.output_device.id);
                 return Err(Error::error());
             }
+
+            // Event to notify when the nominal sample rate of the device changes.
+            self.output_rate_listener = Some(device_property_listener::new(
+                self.output_device.id,
+                &NOMINAL_SAMPLE_RATE_PROPERTY_ADDRESS,
+                audiounit_sample_rate_listener_callback,
+            ));
+            let rv = stm.add_device_listener(self.output_rate_listener.as_ref().unwrap());
+            if rv != NO_ERR {
+                self.output_rate_listener = None;
+                cubeb_log!("AudioObjectAddPropertyListener/output/kAudioDevicePropertyNominalSampleRate rv={}, device id={}", rv, self.output_device.id);
+                return Err(Error::error());
+            }
         }
 
         if !self.input_unit.is_null() {
@@ -3187,6 +4856,22 @@ impl<'ctx> CoreStreamData<'ctx> {
                 cubeb_log!("AudioObjectAddPropertyListener/input/kAudioDevicePropertyDeviceIsAlive rv={}, device id ={}", rv, self.input_device.id);
                 return Err(Error::error());
             }
+
+            // The listener of the output side handles both sides of the same device.
+            if self.output_rate_listener.is_none() || self.input_device.id != self.output_device.id
+            {
+                self.input_rate_listener = Some(device_property_listener::new(
+                    self.input_device.id,
+                    &NOMINAL_SAMPLE_RATE_PROPERTY_ADDRESS,
+                    audiounit_sample_rate_listener_callback,
+                ));
+                let rv = stm.add_device_listener(self.input_rate_listener.as_ref().unwrap());
+                if rv != NO_ERR {
+                    self.input_rate_listener = None;
+                    cubeb_log!("AudioObjectAddPropertyListener/input/kAudioDevicePropertyNominalSampleRate rv={}, device id={}", rv, self.input_device.id);
+                    return Err(Error::error());
+                }
+            }
         }
 
         Ok(())
@@ -3238,6 +4923,8 @@ impl<'ctx> CoreStreamData<'ctx> {
                 self.output_source_listener.is_none()
                     && self.input_source_listener.is_none()
                     && self.input_alive_listener.is_none()
+                    && self.input_rate_listener.is_none()
+                    && self.output_rate_listener.is_none()
             );
             return Ok(());
         }
@@ -3274,6 +4961,24 @@ impl<'ctx> CoreStreamData<'ctx> {
             self.input_alive_listener = None;
         }
 
+        if self.output_rate_listener.is_some() {
+            let rv = stm.remove_device_listener(self.output_rate_listener.as_ref().unwrap());
+            if rv != NO_ERR {
+                cubeb_log!("AudioObjectRemovePropertyListener/output/kAudioDevicePropertyNominalSampleRate rv={}, device id={}", rv, self.output_device.id);
+                r = Err(Error::error());
+            }
+            self.output_rate_listener = None;
+        }
+
+        if self.input_rate_listener.is_some() {
+            let rv = stm.remove_device_listener(self.input_rate_listener.as_ref().unwrap());
+            if rv != NO_ERR {
+                cubeb_log!("AudioObjectRemovePropertyListener/input/kAudioDevicePropertyNominalSampleRate rv={}, device id={}", rv, self.input_device.id);
+                r = Err(Error::error());
+            }
+            self.input_rate_listener = None;
+        }
+
         r
     }
 
@@ -3314,21 +5019,35 @@ impl<'ctx> Drop for CoreStreamData<'ctx> {
     }
 }
 
+// What to do with the input data buffered, but not delivered to the data callback yet, when a
+// paused stream is resumed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BufferedInput {
+    // Deliver the buffered input before the input captured after resuming.
+    Keep,
+    // Drop the buffered input, which was captured before the stream was paused.
+    Flush,
+}
+
 // The fisrt two members of the Cubeb stream must be a pointer to its Cubeb context and a void user
 // defined pointer. The Cubeb interface use this assumption to operate the Cubeb APIs.
 // #[repr(C)] is used to prevent any padding from being added in the beginning of the AudioUnitStream.
 #[repr(C)]
 #[derive(Debug)]
-struct AudioUnitStream<'ctx> {
-    context: &'ctx mut AudioUnitContext,
+pub struct AudioUnitStream<'ctx> {
+    context: &'ctx AudioUnitContext,
     user_ptr: *mut c_void,
 
-    data_callback: ffi::cubeb_data_callback,
-    state_callback: ffi::cubeb_state_callback,
-    device_changed_callback: Mutex<ffi::cubeb_device_changed_callback>,
+    callbacks: StreamCallbacks,
+    device_changed_callback: Mutex<DeviceChangedCallbackSlot>,
+    sample_rate_changed_callback: Mutex<SampleRateChangedCallbackSlot>,
+    // Fed by the output callback, which never waits for this lock.
+    output_tap: Mutex<Option<OutputTapSender>>,
     // Frame counters
     frames_played: AtomicU64,
-    frames_queued: u64,
+    frames_queued: AtomicU64,
+    // The host time, in nanoseconds, when frames_played was updated.
+    frames_played_time: AtomicU64,
     // How many frames got read from the input since the stream started (includes
     // padded silence)
     frames_read: AtomicI64,
@@ -3336,11 +5055,23 @@ struct AudioUnitStream<'ctx> {
     frames_written: AtomicI64,
     shutdown: AtomicBool,
     draining: AtomicBool,
+    // The frames to be played by the device before the draining stream is drained.
+    drain_frames_remaining: AtomicI64,
+    // The host time when the data callback starts to be called, or 0 to start right away.
+    start_host_time: AtomicU64,
     reinit_pending: AtomicBool,
     destroy_pending: AtomicBool,
-    // Latency requested by the user.
+    // Latency decided by the context's LatencyController for the requested latency.
     latency_frames: u32,
+    latency_shared: bool,
+    // The latency asked when the stream was created, asked again after a device switch.
+    requested_latency_frames: u32,
+    // Devices registered in the context's LatencyController by this stream.
+    latency_devices: Vec<AudioDeviceID>,
+    options: StreamOptions,
     current_latency_frames: AtomicU32,
+    // The same latency in frames at the stream rate, counted down by the drain.
+    drain_latency_frames: AtomicU32,
     panning: atomic::Atomic<f32>,
     // This is true if a device change callback is currently running.
     switching_device: AtomicBool,
@@ -3349,28 +5080,35 @@ struct AudioUnitStream<'ctx> {
 
 impl<'ctx> AudioUnitStream<'ctx> {
     fn new(
-        context: &'ctx mut AudioUnitContext,
+        context: &'ctx AudioUnitContext,
         user_ptr: *mut c_void,
-        data_callback: ffi::cubeb_data_callback,
-        state_callback: ffi::cubeb_state_callback,
-        latency_frames: u32,
+        latency: LatencyDecision,
     ) -> Self {
         AudioUnitStream {
             context,
             user_ptr,
-            data_callback,
-            state_callback,
-            device_changed_callback: Mutex::new(None),
+            callbacks: StreamCallbacks::default(),
+            device_changed_callback: Mutex::new(DeviceChangedCallbackSlot::default()),
+            sample_rate_changed_callback: Mutex::new(SampleRateChangedCallbackSlot::default()),
+            output_tap: Mutex::new(None),
             frames_played: AtomicU64::new(0),
-            frames_queued: 0,
+            frames_queued: AtomicU64::new(0),
+            frames_played_time: AtomicU64::new(0),
             frames_read: AtomicI64::new(0),
             frames_written: AtomicI64::new(0),
             shutdown: AtomicBool::new(true),
             draining: AtomicBool::new(false),
+            drain_frames_remaining: AtomicI64::new(0),
+            start_host_time: AtomicU64::new(0),
             reinit_pending: AtomicBool::new(false),
             destroy_pending: AtomicBool::new(false),
-            latency_frames,
+            latency_frames: latency.latency,
+            latency_shared: latency.shared,
+            requested_latency_frames: latency.latency,
+            latency_devices: Vec::new(),
+            options: StreamOptions::default(),
             current_latency_frames: AtomicU32::new(0),
+            drain_latency_frames: AtomicU32::new(0),
             panning: atomic::Atomic::new(0.0_f32),
             switching_device: AtomicBool::new(false),
             core_stream_data: CoreStreamData::default(),
@@ -3396,19 +5134,184 @@ impl<'ctx> AudioUnitStream<'ctx> {
     }
 
     fn notify_state_changed(&mut self, state: State) {
-        if self.state_callback.is_none() {
-            return;
+        (self.callbacks.state)(state);
+    }
+
+    // Register the callback called when the device of the stream changes, or unregister the
+    // current one if `callback` is None.
+    pub fn set_device_changed_callback(
+        &self,
+        callback: Option<Box<DeviceChangedCallback>>,
+    ) -> Result<()> {
+        self.device_changed_callback
+            .lock()
+            .unwrap()
+            .register(callback)
+    }
+
+    // Register the callback called when the nominal sample rate of a device of the stream
+    // changes, or unregister the current one if `callback` is None. The stream keeps running at
+    // the rate of its parameters.
+    pub fn set_sample_rate_changed_callback(
+        &self,
+        callback: Option<Box<SampleRateChangedCallback>>,
+    ) -> Result<()> {
+        self.sample_rate_changed_callback
+            .lock()
+            .unwrap()
+            .register(callback)
+    }
+
+    // Stop the stream without losing its position. The stream can be resumed by `resume`.
+    // Unlike StreamOps::stop, the position doesn't jump when the stream is resumed.
+    pub fn pause(&mut self) -> Result<()> {
+        *self.shutdown.get_mut() = true;
+
+        self.core_stream_data.stop_audiounits();
+
+        // The frames queued to the device but not played yet are dropped when the audiounits
+        // stop, so the position is frozen at the played frames. It will resume from there.
+        let frames_played = self.frames_played.load(Ordering::SeqCst);
+        self.frames_queued.store(frames_played, Ordering::SeqCst);
+        self.frames_played_time.store(0, Ordering::SeqCst);
+
+        self.notify_stopped();
+        Ok(())
+    }
+
+    fn notify_stopped(&mut self) {
+        self.notify_state_changed(State::Stopped);
+
+        cubeb_log!(
+            "Cubeb stream ({:p}) stopped successfully.",
+            self as *const AudioUnitStream
+        );
+    }
+
+    // Start or resume the stream. The input data buffered before the stream was paused is kept
+    // or flushed according to `input`.
+    pub fn resume(&mut self, input: BufferedInput) -> Result<()> {
+        self.resume_at(input, 0)
+    }
+
+    // Start the stream, but only call the data callback with the frames from `host_time`, as
+    // given by AudioGetCurrentHostTime, at the exact frame. The frames before are silent. Streams
+    // started at the same host time start in sync, even on different devices.
+    pub fn start_at(&mut self, host_time: u64) -> Result<()> {
+        if host_time == 0 {
+            return Err(Error::invalid_parameter());
         }
-        let callback = self.state_callback.unwrap();
-        unsafe {
-            callback(
-                self as *const AudioUnitStream as *mut ffi::cubeb_stream,
-                self.user_ptr,
-                state.into(),
-            );
+        self.resume_at(BufferedInput::Flush, host_time)
+    }
+
+    fn resume_at(&mut self, input: BufferedInput, start_host_time: u64) -> Result<()> {
+        // The buffered input is only touched when the callbacks are not running.
+        if self.shutdown.load(Ordering::SeqCst) {
+            self.reprime_input(input);
+        }
+        self.start_audiounits_at(start_host_time)
+    }
+
+    // Start the audiounits. The data callback is called from `start_host_time`, or right away if
+    // it's 0.
+    fn start_audiounits_at(&mut self, start_host_time: u64) -> Result<()> {
+        *self.shutdown.get_mut() = false;
+        *self.draining.get_mut() = false;
+        *self.drain_frames_remaining.get_mut() = 0;
+        *self.start_host_time.get_mut() = start_host_time;
+
+        self.core_stream_data.start_audiounits()?;
+
+        self.notify_state_changed(State::Started);
+
+        cubeb_log!(
+            "Cubeb stream ({:p}) started successfully.",
+            self as *const AudioUnitStream
+        );
+        Ok(())
+    }
+
+    // Attach a tap receiving a copy of the final output, holding up to `capacity_frames` frames.
+    // The output is dropped when the tap is full. A tap attached before replaces the old one.
+    pub fn attach_output_tap(&self, capacity_frames: u32) -> Result<OutputTap> {
+        let format = self
+            .core_stream_data
+            .output_tap_format()
+            .ok_or_else(Error::invalid_parameter)?;
+        if capacity_frames == 0 {
+            return Err(Error::invalid_parameter());
+        }
+        let (sender, tap) = output_tap(capacity_frames as usize, format);
+        *self.output_tap.lock().unwrap() = Some(sender);
+        cubeb_log!(
+            "({:p}) Output tap attached: {:?}, {} frames.",
+            self as *const AudioUnitStream,
+            format,
+            capacity_frames
+        );
+        Ok(tap)
+    }
+
+    pub fn detach_output_tap(&self) {
+        *self.output_tap.lock().unwrap() = None;
+    }
+
+    // True if the output rendered by the data callback is given to the device as it is.
+    pub fn passes_through(&self) -> bool {
+        self.core_stream_data.passthrough
+    }
+
+    // The latency the stream runs with, which may differ from the requested one if it's clamped
+    // into the context's bounds or shared with another stream on the same device.
+    pub fn latency_decision(&self) -> LatencyDecision {
+        LatencyDecision {
+            latency: self.latency_frames,
+            shared: self.latency_shared,
         }
     }
 
+    // The levels of the input, if the stream has an input and its options set a meter window. The
+    // meter is replaced if the number of input channels changes after a device change.
+    pub fn input_level_meter(&self) -> Option<LevelMeter> {
+        self.core_stream_data
+            .input_meter
+            .as_ref()
+            .map(|meter| meter.meter().clone())
+    }
+
+    // The levels of the output given to the device, after the channels are mixed.
+    pub fn output_level_meter(&self) -> Option<LevelMeter> {
+        self.core_stream_data
+            .output_meter
+            .as_ref()
+            .map(|meter| meter.meter().clone())
+    }
+
+    // Restart the alignment between the input and output sides from the buffered input frames,
+    // so the output callback pads silence only for the input that is really missing.
+    fn reprime_input(&mut self, input: BufferedInput) {
+        let channels = self.core_stream_data.input_desc.mChannelsPerFrame as usize;
+        let buffered_frames = match self.core_stream_data.input_linear_buffer.as_mut() {
+            None => return,
+            Some(buffer) => {
+                if input == BufferedInput::Flush {
+                    buffer.clear();
+                }
+                assert_ne!(channels, 0);
+                buffer.elements() / channels
+            }
+        };
+        cubeb_log!(
+            "({:p}) Resume with {} frames of buffered input ({:?}).",
+            self as *const AudioUnitStream,
+            buffered_frames,
+            input
+        );
+        self.frames_read
+            .store(buffered_frames as i64, Ordering::SeqCst);
+        self.frames_written.store(0, Ordering::SeqCst);
+    }
+
     fn reinit(&mut self) -> Result<()> {
         // Call stop_audiounits to avoid potential data race. If there is a running data callback,
         // which locks a mutex inside CoreAudio framework, then this call will block the current
@@ -3466,6 +5369,8 @@ impl<'ctx> AudioUnitStream<'ctx> {
             e
         })?;
 
+        self.update_latency_devices();
+
         if self.core_stream_data.setup().is_err() {
             cubeb_log!(
                 "({:p}) Stream reinit failed.",
@@ -3482,6 +5387,7 @@ impl<'ctx> AudioUnitStream<'ctx> {
                     self.core_stream_data.close();
                     e
                 })?;
+                self.update_latency_devices();
                 self.core_stream_data.setup().map_err(|e| {
                     cubeb_log!(
                         "({:p}) Second stream reinit failed.",
@@ -3512,6 +5418,43 @@ impl<'ctx> AudioUnitStream<'ctx> {
         Ok(())
     }
 
+    // Move the stream's registration in the LatencyController to the devices the stream is
+    // about to use, when they are different from the registered ones after a device switch.
+    fn update_latency_devices(&mut self) {
+        let devices = get_latency_devices(
+            if self.core_stream_data.has_input() {
+                Some(&self.core_stream_data.input_device)
+            } else {
+                None
+            },
+            if self.core_stream_data.has_output() {
+                Some(&self.core_stream_data.output_device)
+            } else {
+                None
+            },
+        );
+        if devices == self.latency_devices {
+            return;
+        }
+        self.context
+            .update_latency_by_removing_stream(&self.latency_devices);
+        let latency = self
+            .context
+            .update_latency_by_adding_stream(&devices, self.requested_latency_frames);
+        cubeb_log!(
+            "({:p}) Latency devices change from {:?} to {:?}, latency {} -> {} (shared: {}).",
+            self as *const AudioUnitStream,
+            self.latency_devices,
+            devices,
+            self.latency_frames,
+            latency.latency,
+            latency.shared
+        );
+        self.latency_frames = latency.latency;
+        self.latency_shared = latency.shared;
+        self.latency_devices = devices;
+    }
+
     fn reinit_async(&mut self) {
         if self.reinit_pending.swap(true, Ordering::SeqCst) {
             // A reinit task is already pending, nothing more to do.
@@ -3550,10 +5493,58 @@ impl<'ctx> AudioUnitStream<'ctx> {
         });
     }
 
+    fn update_input_rate_async(&mut self, rate: f64) {
+        let queue = self.context.serial_queue;
+        let mutexed_stm = Arc::new(Mutex::new(self));
+        let also_mutexed_stm = Arc::clone(&mutexed_stm);
+        async_dispatch(queue, move || {
+            let mut stm_guard = also_mutexed_stm.lock().unwrap();
+            let stm_ptr = *stm_guard as *const AudioUnitStream;
+            // A pending reinit or destroy sets up or closes the input unit by itself.
+            if stm_guard.destroy_pending.load(Ordering::SeqCst)
+                || stm_guard.reinit_pending.load(Ordering::SeqCst)
+                || stm_guard.core_stream_data.input_unit.is_null()
+                || stm_guard
+                    .core_stream_data
+                    .input_hw_rate
+                    .load(Ordering::SeqCst)
+                    == rate
+            {
+                return;
+            }
+
+            let running = !stm_guard.shutdown.load(Ordering::SeqCst);
+            if running {
+                stm_guard.core_stream_data.stop_audiounits();
+            }
+            let result = stm_guard.core_stream_data.update_input_rate(rate);
+            if result.is_ok() {
+                cubeb_log!("({:p}) Input rate updated to {}.", stm_ptr, rate);
+            }
+            let result = result.and_then(|_| {
+                if running {
+                    stm_guard.core_stream_data.start_audiounits()
+                } else {
+                    Ok(())
+                }
+            });
+            if result.is_err() {
+                cubeb_log!(
+                    "({:p}) Could not update the input rate to {}.",
+                    stm_ptr,
+                    rate
+                );
+                stm_guard.notify_state_changed(State::Error);
+            }
+        });
+    }
+
     fn destroy_internal(&mut self) {
         self.core_stream_data.close();
+        self.core_stream_data.restore_device_sample_rates();
         assert!(self.context.active_streams() >= 1);
-        self.context.update_latency_by_removing_stream();
+        self.context
+            .update_latency_by_removing_stream(&self.latency_devices);
     }
 
     fn destroy(&mut self) {
@@ -3592,30 +5583,14 @@ impl<'ctx> Drop for AudioUnitStream<'ctx> {
 
 impl<'ctx> StreamOps for AudioUnitStream<'ctx> {
     fn start(&mut self) -> Result<()> {
-        *self.shutdown.get_mut() = false;
-        *self.draining.get_mut() = false;
-
-        self.core_stream_data.start_audiounits()?;
-
-        self.notify_state_changed(State::Started);
-
-        cubeb_log!(
-            "Cubeb stream ({:p}) started successfully.",
-            self as *const AudioUnitStream
-        );
-        Ok(())
+        self.start_audiounits_at(0)
     }
     fn stop(&mut self) -> Result<()> {
         *self.shutdown.get_mut() = true;
 
         self.core_stream_data.stop_audiounits();
 
-        self.notify_state_changed(State::Stopped);
-
-        cubeb_log!(
-            "Cubeb stream ({:p}) stopped successfully.",
-            self as *const AudioUnitStream
-        );
+        self.notify_stopped();
         Ok(())
     }
     fn reset_default_device(&mut self) -> Result<()> {
@@ -3623,7 +5598,18 @@ impl<'ctx> StreamOps for AudioUnitStream<'ctx> {
     }
     fn position(&mut self) -> Result<u64> {
         let current_latency_frames = u64::from(self.current_latency_frames.load(Ordering::SeqCst));
-        let frames_played = self.frames_played.load(Ordering::SeqCst);
+        let frames_played =
+            if self.shutdown.load(Ordering::SeqCst) || self.draining.load(Ordering::SeqCst) {
+                self.frames_played.load(Ordering::SeqCst)
+            } else {
+                interpolate_frames_played(
+                    self.frames_played.load(Ordering::SeqCst),
+                    self.frames_queued.load(Ordering::SeqCst),
+                    self.frames_played_time.load(Ordering::SeqCst),
+                    convert_host_time_to_nanos(get_current_host_time()),
+                    self.core_stream_data.output_stream_params.rate(),
+                )
+            };
         let position = if current_latency_frames > frames_played {
             0
         } else {
@@ -3640,9 +5626,15 @@ impl<'ctx> StreamOps for AudioUnitStream<'ctx> {
         Ok(self.current_latency_frames.load(Ordering::SeqCst))
     }
     fn set_volume(&mut self, volume: f32) -> Result<()> {
+        if self.options.passes_through() {
+            return Err(Error::not_supported());
+        }
         set_volume(self.core_stream_data.output_unit, volume)
     }
     fn set_panning(&mut self, panning: f32) -> Result<()> {
+        if self.options.passes_through() {
+            return Err(Error::not_supported());
+        }
         if self.core_stream_data.output_desc.mChannelsPerFrame > 2 {
             return Err(Error::invalid_format());
         }
@@ -3690,15 +5682,10 @@ impl<'ctx> StreamOps for AudioUnitStream<'ctx> {
         &mut self,
         device_changed_callback: ffi::cubeb_device_changed_callback,
     ) -> Result<()> {
-        let mut callback = self.device_changed_callback.lock().unwrap();
-        // Note: second register without unregister first causes 'nope' error.
-        // Current implementation requires unregister before register a new cb.
-        if device_changed_callback.is_some() && callback.is_some() {
-            Err(Error::invalid_parameter())
-        } else {
-            *callback = device_changed_callback;
-            Ok(())
-        }
+        self.set_device_changed_callback(device_changed_callback_from_capi(
+            device_changed_callback,
+            self.user_ptr,
+        ))
     }
 }
 
@@ -0,0 +1,179 @@
+use super::blocking_stream::Sample;
+use super::ring_buffer::*;
+use super::utils::cubeb_sample_size;
+use cubeb_backend::{Error, Result, SampleFormat};
+use std::mem;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+struct TapStates {
+    // Frames dropped because the tap is full.
+    dropped_frames: AtomicUsize,
+    // The stream doesn't feed the tap anymore.
+    closed: AtomicBool,
+}
+
+// The format of the data delivered by an output tap, which is the format of the output device
+// buffers, after the channels are mixed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TapFormat {
+    pub format: SampleFormat,
+    pub channels: u32,
+    pub rate: u32,
+}
+
+impl TapFormat {
+    fn frame_size(&self) -> usize {
+        cubeb_sample_size(self.format) * self.channels as usize
+    }
+}
+
+// Create a tap holding up to `capacity_frames` frames. The sender is fed on the output callback
+// thread and the receiving tap is read by the observer.
+pub fn output_tap(capacity_frames: usize, format: TapFormat) -> (OutputTapSender, OutputTap) {
+    assert!(capacity_frames > 0);
+    let (producer, consumer) = ring_buffer::<u8>(capacity_frames * format.frame_size());
+    let states = Arc::new(TapStates::default());
+    (
+        OutputTapSender {
+            producer,
+            states: states.clone(),
+            format,
+        },
+        OutputTap {
+            consumer,
+            states,
+            format,
+        },
+    )
+}
+
+#[derive(Debug)]
+pub struct OutputTapSender {
+    producer: Producer<u8>,
+    states: Arc<TapStates>,
+    format: TapFormat,
+}
+
+impl OutputTapSender {
+    pub fn format(&self) -> TapFormat {
+        self.format
+    }
+
+    // Copy the whole `data` into the tap, or drop it if the tap is full. This never blocks.
+    pub fn send(&mut self, data: &[u8]) {
+        let frame_size = self.format.frame_size();
+        assert_eq!(data.len() % frame_size, 0);
+        if self.producer.available() < data.len() {
+            self.states
+                .dropped_frames
+                .fetch_add(data.len() / frame_size, Ordering::SeqCst);
+            return;
+        }
+        let pushed = self.producer.push(data);
+        assert_eq!(pushed, data.len());
+    }
+}
+
+impl Drop for OutputTapSender {
+    fn drop(&mut self) {
+        self.states.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+// A copy of the final output of a stream, for monitoring, metering or recording what is played.
+#[derive(Debug)]
+pub struct OutputTap {
+    consumer: Consumer<u8>,
+    states: Arc<TapStates>,
+    format: TapFormat,
+}
+
+impl OutputTap {
+    pub fn format(&self) -> TapFormat {
+        self.format
+    }
+
+    // The number of the frames that can be read now.
+    pub fn available_frames(&self) -> usize {
+        self.consumer.available() / self.format.frame_size()
+    }
+
+    // The number of the frames dropped since the tap was attached, because it was full.
+    pub fn dropped_frames(&self) -> usize {
+        self.states.dropped_frames.load(Ordering::SeqCst)
+    }
+
+    // The tap is closed when it's detached from the stream, when the stream is destroyed, or when
+    // the output format of the stream changes. The frames already in the tap can still be read.
+    pub fn is_closed(&self) -> bool {
+        self.states.closed.load(Ordering::SeqCst)
+    }
+
+    // Read the interleaved frames into `data` without waiting and return the number of the
+    // read frames.
+    pub fn read<T: Sample>(&mut self, data: &mut [T]) -> Result<usize> {
+        if !T::is_compatible(self.format.format) {
+            return Err(Error::invalid_format());
+        }
+        if data.len() % self.format.channels as usize != 0 {
+            return Err(Error::invalid_parameter());
+        }
+        let frame_size = self.format.frame_size();
+        let bytes = unsafe {
+            slice::from_raw_parts_mut(
+                data.as_mut_ptr() as *mut u8,
+                data.len() * mem::size_of::<T>(),
+            )
+        };
+        let available = self.consumer.available() / frame_size * frame_size;
+        let len = available.min(bytes.len());
+        let read = self.consumer.pop(&mut bytes[..len]);
+        Ok(read / frame_size)
+    }
+}
+
+#[test]
+fn test_output_tap() {
+    const CHANNELS: u32 = 2;
+    let (mut sender, mut tap) = output_tap(
+        4,
+        TapFormat {
+            format: SampleFormat::Float32NE,
+            channels: CHANNELS,
+            rate: 48_000,
+        },
+    );
+    assert_eq!(tap.available_frames(), 0);
+    assert!(!tap.is_closed());
+
+    let frames: Vec<f32> = (0..6).map(|i| i as f32).collect();
+    let bytes = unsafe { slice::from_raw_parts(frames.as_ptr() as *const u8, frames.len() * 4) };
+    sender.send(bytes);
+    assert_eq!(tap.available_frames(), 3);
+    // Not enough space for another 3 frames. Drop them all.
+    sender.send(bytes);
+    assert_eq!(tap.available_frames(), 3);
+    assert_eq!(tap.dropped_frames(), 3);
+
+    // Only f32 can be read from a Float32NE tap.
+    assert_eq!(
+        tap.read(&mut [0_i16; 2]).unwrap_err(),
+        Error::invalid_format()
+    );
+    // Only whole frames can be read.
+    assert_eq!(
+        tap.read(&mut [0_f32; 3]).unwrap_err(),
+        Error::invalid_parameter()
+    );
+
+    let mut output = [0_f32; 8];
+    assert_eq!(tap.read(&mut output).unwrap(), 3);
+    assert_eq!(&output[..6], frames.as_slice());
+    assert_eq!(tap.read(&mut output).unwrap(), 0);
+
+    drop(sender);
+    assert!(tap.is_closed());
+}
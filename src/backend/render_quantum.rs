@@ -0,0 +1,347 @@
+use super::ring_buffer::*;
+use cubeb_backend::ffi;
+use std::cmp;
+use std::os::raw::{c_long, c_void};
+use std::ptr;
+use std::slice;
+
+// Adapt the variable number of frames asked by the device callbacks into a fixed number of
+// frames, the render quantum, for every call of the user's data callback. The frames rendered
+// ahead and the input not consumed yet are kept in the FIFOs until the next device callback.
+// The FIFOs are allocated up front, so nothing is allocated on the callback thread.
+#[derive(Debug)]
+pub struct RenderQuantumAdapter {
+    stream: *mut ffi::cubeb_stream,
+    data_callback: ffi::cubeb_data_callback,
+    user_ptr: *mut c_void,
+    quantum: usize,
+    // Bytes per frame of the data callback's buffers. 0 if there is no such side.
+    input_frame_size: usize,
+    output_frame_size: usize,
+    // Only accessed from callback thread.
+    input_fifo: (Producer<u8>, Consumer<u8>),
+    output_fifo: (Producer<u8>, Consumer<u8>),
+    input_quantum: Vec<u8>,
+    output_quantum: Vec<u8>,
+    drained: bool,
+}
+
+impl RenderQuantumAdapter {
+    // `max_device_frames` is the most frames asked by a device callback. The input beyond it is
+    // dropped.
+    pub fn new(
+        stream: *mut ffi::cubeb_stream,
+        data_callback: ffi::cubeb_data_callback,
+        user_ptr: *mut c_void,
+        quantum: u32,
+        input_frame_size: usize,
+        output_frame_size: usize,
+        max_device_frames: u32,
+    ) -> Self {
+        assert!(data_callback.is_some());
+        assert_ne!(quantum, 0);
+        assert!(input_frame_size > 0 || output_frame_size > 0);
+
+        let quantum = quantum as usize;
+        let capacity = quantum + max_device_frames as usize;
+
+        // Prime the input with a quantum of silence, so there is always a whole quantum of
+        // input available when the device delivers the input in smaller chunks.
+        let input_quantum = vec![0; quantum * input_frame_size];
+        let mut input_fifo = ring_buffer(cmp::max(capacity * input_frame_size, 1));
+        input_fifo.0.push(&input_quantum);
+
+        Self {
+            stream,
+            data_callback,
+            user_ptr,
+            quantum,
+            input_frame_size,
+            output_frame_size,
+            input_fifo,
+            output_fifo: ring_buffer(cmp::max(capacity * output_frame_size, 1)),
+            input_quantum,
+            output_quantum: vec![0; quantum * output_frame_size],
+            drained: false,
+        }
+    }
+
+    // The maximal latency, in frames, added by the adapter.
+    pub fn latency(&self) -> u32 {
+        self.quantum as u32
+    }
+
+    pub fn process(
+        &mut self,
+        input_buffer: *const c_void,
+        output_buffer: *mut c_void,
+        nframes: c_long,
+    ) -> c_long {
+        assert!(nframes >= 0);
+        let frames = nframes as usize;
+
+        if !input_buffer.is_null() {
+            assert_ne!(self.input_frame_size, 0);
+            let input = unsafe {
+                slice::from_raw_parts(input_buffer as *const u8, frames * self.input_frame_size)
+            };
+            self.input_fifo.0.push(input);
+        }
+
+        // Input only.
+        if output_buffer.is_null() {
+            while !self.drained
+                && self.input_fifo.1.available() >= self.quantum * self.input_frame_size
+            {
+                let rendered = self.render_quantum();
+                if rendered < 0 || rendered as usize > self.quantum {
+                    return rendered;
+                }
+                self.drained = (rendered as usize) < self.quantum;
+            }
+            return if self.drained { 0 } else { nframes };
+        }
+
+        assert_ne!(self.output_frame_size, 0);
+        let output = unsafe {
+            slice::from_raw_parts_mut(output_buffer as *mut u8, frames * self.output_frame_size)
+        };
+        // Render a quantum whenever the FIFO runs out, so a quantum always fits in the FIFO.
+        let mut bytes = self.output_fifo.1.pop(output);
+        while !self.drained && bytes < output.len() {
+            let rendered = self.render_quantum();
+            if rendered < 0 || rendered as usize > self.quantum {
+                return rendered;
+            }
+            let rendered = rendered as usize;
+            self.drained = rendered < self.quantum;
+            self.output_fifo
+                .0
+                .push(&self.output_quantum[..rendered * self.output_frame_size]);
+            bytes += self.output_fifo.1.pop(&mut output[bytes..]);
+        }
+        (bytes / self.output_frame_size) as c_long
+    }
+
+    fn render_quantum(&mut self) -> c_long {
+        let input_buffer = if self.input_frame_size > 0 {
+            // Pad silence if the input is running behind.
+            let read = self.input_fifo.1.pop(&mut self.input_quantum);
+            for byte in &mut self.input_quantum[read..] {
+                *byte = 0;
+            }
+            self.input_quantum.as_ptr() as *const c_void
+        } else {
+            ptr::null()
+        };
+        let output_buffer = if self.output_frame_size > 0 {
+            self.output_quantum.as_mut_ptr() as *mut c_void
+        } else {
+            ptr::null_mut()
+        };
+
+        unsafe {
+            self.data_callback.unwrap()(
+                self.stream,
+                self.user_ptr,
+                input_buffer,
+                output_buffer,
+                self.quantum as c_long,
+            )
+        }
+    }
+}
+
+// The data callback given to the resampler when the stream runs with a render quantum.
+// `user_ptr` is the RenderQuantumAdapter of the stream.
+pub extern "C" fn render_quantum_data_callback(
+    _stream: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input_buffer: *const c_void,
+    output_buffer: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    assert!(!user_ptr.is_null());
+    let adapter = unsafe { &mut *(user_ptr as *mut RenderQuantumAdapter) };
+    adapter.process(input_buffer, output_buffer, nframes)
+}
+
+#[cfg(test)]
+struct TestCallbackData {
+    calls: Vec<c_long>,
+    position: i16,
+    frames_before_drained: i64,
+}
+
+#[cfg(test)]
+extern "C" fn test_data_callback(
+    _stream: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input_buffer: *const c_void,
+    output_buffer: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    let data = unsafe { &mut *(user_ptr as *mut TestCallbackData) };
+    data.calls.push(nframes);
+    let frames = cmp::min(nframes, data.frames_before_drained);
+    data.frames_before_drained -= frames;
+    if !output_buffer.is_null() {
+        let output =
+            unsafe { slice::from_raw_parts_mut(output_buffer as *mut i16, frames as usize) };
+        for sample in output.iter_mut() {
+            *sample = data.position;
+            data.position += 1;
+        }
+    }
+    if !input_buffer.is_null() {
+        let input = unsafe { slice::from_raw_parts(input_buffer as *const i16, nframes as usize) };
+        assert_eq!(input.len(), nframes as usize);
+    }
+    frames
+}
+
+#[test]
+fn test_render_quantum_output() {
+    const QUANTUM: u32 = 128;
+    let mut data = TestCallbackData {
+        calls: Vec::new(),
+        position: 0,
+        frames_before_drained: i64::max_value(),
+    };
+    let mut adapter = RenderQuantumAdapter::new(
+        ptr::null_mut(),
+        Some(test_data_callback),
+        &mut data as *mut TestCallbackData as *mut c_void,
+        QUANTUM,
+        0,
+        2, // Mono i16.
+        512,
+    );
+
+    let mut expected: i16 = 0;
+    for &frames in &[100, 441, 128, 1, 512, 77] {
+        let mut output = vec![-1_i16; frames];
+        let rendered = render_quantum_data_callback(
+            ptr::null_mut(),
+            &mut adapter as *mut RenderQuantumAdapter as *mut c_void,
+            ptr::null(),
+            output.as_mut_ptr() as *mut c_void,
+            frames as c_long,
+        );
+        assert_eq!(rendered, frames as c_long);
+        // The output is continuous across the device callbacks.
+        for sample in output {
+            assert_eq!(sample, expected);
+            expected += 1;
+        }
+    }
+    assert!(data.calls.iter().all(|&frames| frames == QUANTUM as c_long));
+}
+
+#[test]
+fn test_render_quantum_output_drained() {
+    const QUANTUM: u32 = 128;
+    let mut data = TestCallbackData {
+        calls: Vec::new(),
+        position: 0,
+        frames_before_drained: 300,
+    };
+    let mut adapter = RenderQuantumAdapter::new(
+        ptr::null_mut(),
+        Some(test_data_callback),
+        &mut data as *mut TestCallbackData as *mut c_void,
+        QUANTUM,
+        0,
+        2, // Mono i16.
+        512,
+    );
+
+    let mut output = vec![0_i16; 256];
+    assert_eq!(
+        adapter.process(ptr::null(), output.as_mut_ptr() as *mut c_void, 256),
+        256
+    );
+    // The remaining 44 frames are delivered before the stream is drained.
+    assert_eq!(
+        adapter.process(ptr::null(), output.as_mut_ptr() as *mut c_void, 256),
+        44
+    );
+    assert_eq!(
+        adapter.process(ptr::null(), output.as_mut_ptr() as *mut c_void, 256),
+        0
+    );
+    assert_eq!(data.calls.len(), 3);
+}
+
+#[test]
+fn test_render_quantum_input() {
+    const QUANTUM: u32 = 128;
+    let mut data = TestCallbackData {
+        calls: Vec::new(),
+        position: 0,
+        frames_before_drained: i64::max_value(),
+    };
+    let mut adapter = RenderQuantumAdapter::new(
+        ptr::null_mut(),
+        Some(test_data_callback),
+        &mut data as *mut TestCallbackData as *mut c_void,
+        QUANTUM,
+        4, // Stereo i16.
+        0,
+        512,
+    );
+    assert_eq!(adapter.latency(), QUANTUM);
+
+    let input = vec![0_i16; 2 * 512];
+    let mut total_frames = 0;
+    for &frames in &[100, 441, 128, 1, 512] {
+        assert_eq!(
+            adapter.process(input.as_ptr() as *const c_void, ptr::null_mut(), frames),
+            frames
+        );
+        total_frames += frames;
+    }
+    // The input is primed with a quantum of silence.
+    assert_eq!(
+        data.calls.len() as c_long,
+        (total_frames + QUANTUM as c_long) / QUANTUM as c_long
+    );
+    assert!(data.calls.iter().all(|&frames| frames == QUANTUM as c_long));
+}
+
+#[test]
+fn test_render_quantum_output_larger_than_max_device_frames() {
+    const QUANTUM: u32 = 128;
+    let mut data = TestCallbackData {
+        calls: Vec::new(),
+        position: 0,
+        frames_before_drained: i64::max_value(),
+    };
+    let mut adapter = RenderQuantumAdapter::new(
+        ptr::null_mut(),
+        Some(test_data_callback),
+        &mut data as *mut TestCallbackData as *mut c_void,
+        QUANTUM,
+        0,
+        2, // Mono i16.
+        64,
+    );
+
+    // The FIFO never holds more than a quantum, so the device can ask for any number of frames.
+    let mut expected: i16 = 0;
+    for &frames in &[1000, 1, 4096] {
+        let mut output = vec![-1_i16; frames];
+        assert_eq!(
+            adapter.process(
+                ptr::null(),
+                output.as_mut_ptr() as *mut c_void,
+                frames as c_long
+            ),
+            frames as c_long
+        );
+        for sample in output {
+            assert_eq!(sample, expected);
+            expected += 1;
+        }
+    }
+}
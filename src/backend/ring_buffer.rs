@@ -0,0 +1,213 @@
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// A lock-free and wait-free single-producer single-consumer ring buffer. The buffer is split
+// into a Producer and a Consumer so each side can be moved to the thread using it, e.g., the
+// audio callback thread on one side and the application thread on the other side.
+pub fn ring_buffer<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0);
+    let storage = Arc::new(Storage {
+        // One slot is kept empty to tell a full buffer from an empty one.
+        data: UnsafeCell::new(vec![T::default(); capacity + 1]),
+        read_index: AtomicUsize::new(0),
+        write_index: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            storage: storage.clone(),
+        },
+        Consumer { storage },
+    )
+}
+
+struct Storage<T> {
+    data: UnsafeCell<Vec<T>>,
+    // Only modified by the consumer.
+    read_index: AtomicUsize,
+    // Only modified by the producer.
+    write_index: AtomicUsize,
+}
+
+impl<T> Storage<T> {
+    fn size(&self) -> usize {
+        unsafe { (*self.data.get()).len() }
+    }
+
+    fn available_read(&self, read_index: usize, write_index: usize) -> usize {
+        (write_index + self.size() - read_index) % self.size()
+    }
+
+    fn available_write(&self, read_index: usize, write_index: usize) -> usize {
+        self.size() - 1 - self.available_read(read_index, write_index)
+    }
+}
+
+// The slots are either owned by the producer or by the consumer, depending on the indices.
+unsafe impl<T: Send> Sync for Storage<T> {}
+
+#[derive(Debug)]
+pub struct Producer<T> {
+    storage: Arc<Storage<T>>,
+}
+
+impl<T: Copy> Producer<T> {
+    pub fn capacity(&self) -> usize {
+        self.storage.size() - 1
+    }
+
+    // The number of elements that can be pushed now.
+    pub fn available(&self) -> usize {
+        self.storage.available_write(
+            self.storage.read_index.load(Ordering::Acquire),
+            self.storage.write_index.load(Ordering::Relaxed),
+        )
+    }
+
+    // Push as many elements of `data` as possible and return the number of the pushed elements.
+    pub fn push(&mut self, data: &[T]) -> usize {
+        let read_index = self.storage.read_index.load(Ordering::Acquire);
+        let write_index = self.storage.write_index.load(Ordering::Relaxed);
+        let count = cmp::min(
+            data.len(),
+            self.storage.available_write(read_index, write_index),
+        );
+        let size = self.storage.size();
+        let buffer = unsafe { &mut *self.storage.data.get() };
+        let first = cmp::min(count, size - write_index);
+        buffer[write_index..write_index + first].copy_from_slice(&data[..first]);
+        buffer[..count - first].copy_from_slice(&data[first..count]);
+        self.storage
+            .write_index
+            .store((write_index + count) % size, Ordering::Release);
+        count
+    }
+}
+
+#[derive(Debug)]
+pub struct Consumer<T> {
+    storage: Arc<Storage<T>>,
+}
+
+impl<T: Copy> Consumer<T> {
+    pub fn capacity(&self) -> usize {
+        self.storage.size() - 1
+    }
+
+    // The number of elements that can be popped now.
+    pub fn available(&self) -> usize {
+        self.storage.available_read(
+            self.storage.read_index.load(Ordering::Relaxed),
+            self.storage.write_index.load(Ordering::Acquire),
+        )
+    }
+
+    // Pop as many elements as possible into `data` and return the number of the popped elements.
+    pub fn pop(&mut self, data: &mut [T]) -> usize {
+        let read_index = self.storage.read_index.load(Ordering::Relaxed);
+        let write_index = self.storage.write_index.load(Ordering::Acquire);
+        let count = cmp::min(
+            data.len(),
+            self.storage.available_read(read_index, write_index),
+        );
+        let size = self.storage.size();
+        let buffer = unsafe { &*self.storage.data.get() };
+        let first = cmp::min(count, size - read_index);
+        data[..first].copy_from_slice(&buffer[read_index..read_index + first]);
+        data[first..count].copy_from_slice(&buffer[..count - first]);
+        self.storage
+            .read_index
+            .store((read_index + count) % size, Ordering::Release);
+        count
+    }
+
+    // Drop up to `count` elements and return the number of the dropped elements.
+    pub fn discard(&mut self, count: usize) -> usize {
+        let read_index = self.storage.read_index.load(Ordering::Relaxed);
+        let write_index = self.storage.write_index.load(Ordering::Acquire);
+        let count = cmp::min(count, self.storage.available_read(read_index, write_index));
+        self.storage.read_index.store(
+            (read_index + count) % self.storage.size(),
+            Ordering::Release,
+        );
+        count
+    }
+}
+
+impl<T> std::fmt::Debug for Storage<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Storage")
+            .field("size", &self.size())
+            .field("read_index", &self.read_index)
+            .field("write_index", &self.write_index)
+            .finish()
+    }
+}
+
+#[test]
+fn test_ring_buffer_push_and_pop() {
+    let (mut producer, mut consumer) = ring_buffer::<u32>(8);
+    assert_eq!(producer.capacity(), 8);
+    assert_eq!(consumer.capacity(), 8);
+    assert_eq!(producer.available(), 8);
+    assert_eq!(consumer.available(), 0);
+
+    let mut output = [0_u32; 8];
+    let mut next = 0;
+    let mut expected = 0;
+    // Wrap around the end of the storage several times.
+    for _ in 0..10 {
+        let input: Vec<u32> = (next..next + 5).collect();
+        assert_eq!(producer.push(&input), 5);
+        next += 5;
+        assert_eq!(consumer.available(), 5);
+        assert_eq!(consumer.pop(&mut output[..3]), 3);
+        assert_eq!(consumer.pop(&mut output[3..]), 2);
+        for value in &output[..5] {
+            assert_eq!(*value, expected);
+            expected += 1;
+        }
+    }
+}
+
+#[test]
+fn test_ring_buffer_full_and_empty() {
+    let (mut producer, mut consumer) = ring_buffer::<u8>(4);
+    assert_eq!(producer.push(&[1, 2, 3, 4, 5, 6]), 4);
+    assert_eq!(producer.available(), 0);
+    assert_eq!(producer.push(&[7]), 0);
+
+    assert_eq!(consumer.discard(1), 1);
+    let mut output = [0_u8; 6];
+    assert_eq!(consumer.pop(&mut output), 3);
+    assert_eq!(&output[..3], &[2, 3, 4]);
+    assert_eq!(consumer.pop(&mut output), 0);
+    assert_eq!(consumer.discard(1), 0);
+}
+
+#[test]
+fn test_ring_buffer_across_threads() {
+    use std::thread;
+
+    const COUNT: u32 = 100_000;
+    let (mut producer, mut consumer) = ring_buffer::<u32>(64);
+    let handle = thread::spawn(move || {
+        let mut next = 0;
+        while next < COUNT {
+            let data: Vec<u32> = (next..cmp::min(next + 16, COUNT)).collect();
+            next += producer.push(&data) as u32;
+        }
+    });
+
+    let mut expected = 0;
+    let mut output = [0_u32; 16];
+    while expected < COUNT {
+        let count = consumer.pop(&mut output);
+        for value in &output[..count] {
+            assert_eq!(*value, expected);
+            expected += 1;
+        }
+    }
+    handle.join().unwrap();
+}
@@ -205,6 +205,7 @@ fn test_cubeb_channel_layout_to_channel_label_with_unknown_channel() {
 fn test_increase_and_decrease_context_streams() {
     use std::thread;
     const STREAMS: u32 = 10;
+    const DEVICE: AudioDeviceID = 100;
 
     let context = AudioUnitContext::new();
     let context_ptr_value = &context as *const AudioUnitContext as usize;
@@ -213,8 +214,8 @@ fn test_increase_and_decrease_context_streams() {
     for i in 0..STREAMS {
         join_handles.push(thread::spawn(move || {
             let context = unsafe { &*(context_ptr_value as *const AudioUnitContext) };
-            let global_latency = context.update_latency_by_adding_stream(i);
-            global_latency
+            let latency = context.update_latency_by_adding_stream(&[DEVICE], i);
+            latency
         }));
     }
     let mut latencies = vec![];
@@ -224,16 +225,18 @@ fn test_increase_and_decrease_context_streams() {
     assert_eq!(context.active_streams(), STREAMS);
     check_streams(&context, STREAMS);
 
-    check_latency(&context, latencies[0]);
+    // All the streams on the same device share the latency of the first one.
+    check_latency(&context, DEVICE, Some(latencies[0].latency));
+    assert_eq!(latencies.iter().filter(|l| !l.shared).count(), 1);
     for i in 0..latencies.len() - 1 {
-        assert_eq!(latencies[i], latencies[i + 1]);
+        assert_eq!(latencies[i].latency, latencies[i + 1].latency);
     }
 
     let mut join_handles = vec![];
     for _ in 0..STREAMS {
         join_handles.push(thread::spawn(move || {
             let context = unsafe { &*(context_ptr_value as *const AudioUnitContext) };
-            context.update_latency_by_removing_stream();
+            context.update_latency_by_removing_stream(&[DEVICE]);
         }));
     }
     for handle in join_handles {
@@ -241,7 +244,127 @@ fn test_increase_and_decrease_context_streams() {
     }
     check_streams(&context, 0);
 
-    check_latency(&context, None);
+    check_latency(&context, DEVICE, None);
+}
+
+#[test]
+fn test_context_streams_on_different_devices() {
+    const DEVICE_1: AudioDeviceID = 100;
+    const DEVICE_2: AudioDeviceID = 200;
+    const DEVICE_3: AudioDeviceID = 300;
+    let low = SAFE_MIN_LATENCY_FRAMES;
+    let high = SAFE_MAX_LATENCY_FRAMES;
+
+    let context = AudioUnitContext::new();
+
+    // Streams on different devices get their own latency.
+    let first = context.update_latency_by_adding_stream(&[DEVICE_1], low);
+    assert_eq!(
+        first,
+        LatencyDecision {
+            latency: low,
+            shared: false
+        }
+    );
+    let second = context.update_latency_by_adding_stream(&[DEVICE_2], high);
+    assert_eq!(
+        second,
+        LatencyDecision {
+            latency: high,
+            shared: false
+        }
+    );
+
+    // A stream using one of the running devices follows that device's latency.
+    let third = context.update_latency_by_adding_stream(&[DEVICE_3, DEVICE_2], low);
+    assert_eq!(
+        third,
+        LatencyDecision {
+            latency: high,
+            shared: true
+        }
+    );
+    check_streams(&context, 3);
+    check_latency(&context, DEVICE_1, Some(low));
+    check_latency(&context, DEVICE_2, Some(high));
+    check_latency(&context, DEVICE_3, Some(high));
+
+    // The latency of a device is released once all its streams are gone.
+    context.update_latency_by_removing_stream(&[DEVICE_2]);
+    context.update_latency_by_removing_stream(&[DEVICE_3, DEVICE_2]);
+    check_latency(&context, DEVICE_2, None);
+    check_latency(&context, DEVICE_3, None);
+    let fourth = context.update_latency_by_adding_stream(&[DEVICE_2], low);
+    assert_eq!(
+        fourth,
+        LatencyDecision {
+            latency: low,
+            shared: false
+        }
+    );
+
+    context.update_latency_by_removing_stream(&[DEVICE_2]);
+    context.update_latency_by_removing_stream(&[DEVICE_1]);
+    check_streams(&context, 0);
+}
+
+#[test]
+fn test_stream_latency_after_device_switch() {
+    const DEVICE_1: AudioDeviceID = 100;
+    const DEVICE_2: AudioDeviceID = 200;
+    let low = SAFE_MIN_LATENCY_FRAMES;
+    let high = SAFE_MAX_LATENCY_FRAMES;
+
+    test_get_default_raw_stream(|stream| {
+        assert_eq!(
+            stream.latency_decision(),
+            LatencyDecision {
+                latency: low,
+                shared: false
+            }
+        );
+        stream.requested_latency_frames = high;
+
+        // Another stream is running on the first device.
+        stream
+            .context
+            .update_latency_by_adding_stream(&[DEVICE_1], low);
+        let mut raw = ffi::cubeb_stream_params::default();
+        raw.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+        raw.rate = 48_000;
+        raw.channels = 2;
+        let output_settings = |id| {
+            let mut device = device_info::default();
+            device.id = id;
+            Some((StreamParams::from(raw), device))
+        };
+
+        stream.core_stream_data = CoreStreamData::new(stream, None, output_settings(DEVICE_1));
+        stream.update_latency_devices();
+        assert_eq!(
+            stream.latency_decision(),
+            LatencyDecision {
+                latency: low,
+                shared: true
+            }
+        );
+
+        // The requested latency is used again on a device of its own.
+        stream.core_stream_data = CoreStreamData::new(stream, None, output_settings(DEVICE_2));
+        stream.update_latency_devices();
+        assert_eq!(
+            stream.latency_decision(),
+            LatencyDecision {
+                latency: high,
+                shared: false
+            }
+        );
+
+        stream
+            .context
+            .update_latency_by_removing_stream(&[DEVICE_1]);
+        stream.core_stream_data = CoreStreamData::new(stream, None, None);
+    });
 }
 
 fn check_streams(context: &AudioUnitContext, number: u32) {
@@ -249,9 +372,9 @@ fn check_streams(context: &AudioUnitContext, number: u32) {
     assert_eq!(guard.streams, number);
 }
 
-fn check_latency(context: &AudioUnitContext, latency: Option<u32>) {
+fn check_latency(context: &AudioUnitContext, device: AudioDeviceID, latency: Option<u32>) {
     let guard = context.latency_controller.lock().unwrap();
-    assert_eq!(guard.latency, latency);
+    assert_eq!(guard.devices.get(&device).map(|d| d.latency), latency);
 }
 
 // make_silent
@@ -944,6 +1067,39 @@ fn test_create_stream_description() {
     }
 }
 
+// get_device_format
+// ------------------------------------
+#[test]
+fn test_get_device_format() {
+    for (format, device_format) in [
+        (ffi::CUBEB_SAMPLE_S16LE, ffi::CUBEB_DEVICE_FMT_S16LE),
+        (ffi::CUBEB_SAMPLE_S16BE, ffi::CUBEB_DEVICE_FMT_S16BE),
+        (ffi::CUBEB_SAMPLE_FLOAT32LE, ffi::CUBEB_DEVICE_FMT_F32LE),
+        (ffi::CUBEB_SAMPLE_FLOAT32BE, ffi::CUBEB_DEVICE_FMT_F32BE),
+    ]
+    .iter()
+    {
+        let mut raw = ffi::cubeb_stream_params::default();
+        raw.format = *format;
+        raw.rate = 48_000;
+        raw.channels = 2;
+        raw.layout = ffi::CUBEB_LAYOUT_UNDEFINED;
+        raw.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+        let description = create_stream_description(&StreamParams::from(raw)).unwrap();
+        assert_eq!(get_device_format(&description), Some(*device_format));
+    }
+
+    // Neither the 24-bit samples nor the encoded formats have a cubeb sample format.
+    let mut description = AudioStreamBasicDescription::default();
+    description.mFormatID = kAudioFormatLinearPCM;
+    description.mFormatFlags = kAudioFormatFlagIsSignedInteger | kLinearPCMFormatFlagIsPacked;
+    description.mBitsPerChannel = 24;
+    assert_eq!(get_device_format(&description), None);
+    description.mFormatID = kAudioFormat60958AC3;
+    description.mBitsPerChannel = 16;
+    assert_eq!(get_device_format(&description), None);
+}
+
 // set_channel_layout
 // ------------------------------------
 #[test]
@@ -1372,10 +1528,487 @@ fn test_clamp_latency() {
     assert!(range.start < SAFE_MIN_LATENCY_FRAMES);
     // assert!(range.end < SAFE_MAX_LATENCY_FRAMES);
     for latency_frames in range {
-        let clamp = clamp_latency(latency_frames);
+        let clamp = clamp_latency(
+            latency_frames,
+            SAFE_MIN_LATENCY_FRAMES,
+            SAFE_MAX_LATENCY_FRAMES,
+        );
         assert!(clamp >= SAFE_MIN_LATENCY_FRAMES);
         assert!(clamp <= SAFE_MAX_LATENCY_FRAMES);
     }
+
+    assert_eq!(clamp_latency(32, 64, 2048), 64);
+    assert_eq!(clamp_latency(64, 64, 2048), 64);
+    assert_eq!(clamp_latency(2048, 64, 2048), 2048);
+    assert_eq!(clamp_latency(4096, 64, 2048), 2048);
+}
+
+#[test]
+#[should_panic]
+fn test_clamp_latency_with_invalid_bounds() {
+    clamp_latency(256, 512, 256);
+}
+
+// set_latency_bounds
+// ------------------------------------
+#[test]
+fn test_set_latency_bounds() {
+    let context = AudioUnitContext::new();
+    assert_eq!(
+        context.latency_bounds(),
+        (SAFE_MIN_LATENCY_FRAMES, SAFE_MAX_LATENCY_FRAMES)
+    );
+
+    assert_eq!(
+        context.set_latency_bounds(0, 512).unwrap_err(),
+        Error::invalid_parameter()
+    );
+    assert_eq!(
+        context.set_latency_bounds(512, 256).unwrap_err(),
+        Error::invalid_parameter()
+    );
+
+    if let Ok(range) = audiounit_get_acceptable_latency_range() {
+        let min = range.mMinimum as u32;
+        let max = range.mMaximum as u32;
+        assert!(context.set_latency_bounds(min, max).is_ok());
+        assert_eq!(context.latency_bounds(), (min, max));
+
+        let latency = context.update_latency_by_adding_stream(&[], max + 1);
+        assert_eq!(latency.latency, max);
+        context.update_latency_by_removing_stream(&[]);
+
+        assert_eq!(
+            context.set_latency_bounds(min, max + 1).unwrap_err(),
+            Error::invalid_parameter()
+        );
+        assert_eq!(context.latency_bounds(), (min, max));
+    } else {
+        println!("No output device to validate the latency bounds.");
+    }
+}
+
+// interpolate_frames_played
+// ------------------------------------
+#[test]
+fn test_interpolate_frames_played() {
+    const RATE: u32 = 48_000;
+    const MS: u64 = 1_000_000;
+    let played = 4096;
+    let queued = played + 2048;
+    let time = 10 * MS;
+
+    // No callback has been fired yet.
+    assert_eq!(interpolate_frames_played(0, 0, 0, time, RATE), 0);
+    // No time passed since the last callback.
+    assert_eq!(
+        interpolate_frames_played(played, queued, time, time, RATE),
+        played
+    );
+    // 10 ms later, 480 frames played.
+    assert_eq!(
+        interpolate_frames_played(played, queued, time, time + 10 * MS, RATE),
+        played + 480
+    );
+    // The estimation never exceeds the queued frames.
+    assert_eq!(
+        interpolate_frames_played(played, queued, time, time + 1000 * MS, RATE),
+        queued
+    );
+}
+
+// frames_before_start
+// ------------------------------------
+#[test]
+fn test_frames_before_start() {
+    const RATE: f64 = 48_000.0;
+    let now = get_current_host_time();
+    let mut tstamp = AudioTimeStamp::default();
+    tstamp.mFlags = kAudioTimeStampHostTimeValid;
+    tstamp.mHostTime = now;
+
+    // The start time is reached.
+    assert_eq!(frames_before_start(now, &tstamp, RATE), 0);
+    let before = convert_nanos_to_host_time(convert_host_time_to_nanos(now) - 1_000_000);
+    assert_eq!(frames_before_start(before, &tstamp, RATE), 0);
+
+    // 10 ms later, 480 frames.
+    let after = convert_nanos_to_host_time(convert_host_time_to_nanos(now) + 10_000_000);
+    let frames = frames_before_start(after, &tstamp, RATE);
+    // Allow a frame of error from the conversions between the host time and the nanoseconds.
+    assert!(frames >= 479 && frames <= 481);
+}
+
+// StreamOptions
+// ------------------------------------
+#[test]
+fn test_validate_stream_options() {
+    assert!(StreamOptions::default().validate().is_ok());
+    let options = StreamOptions {
+        render_quantum: Some(128),
+        ..Default::default()
+    };
+    assert!(options.validate().is_ok());
+    let options = StreamOptions {
+        render_quantum: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(options.validate().unwrap_err(), Error::invalid_parameter());
+    let options = StreamOptions {
+        meter_window: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    assert!(options.validate().is_ok());
+    let options = StreamOptions {
+        meter_window: Some(Duration::from_secs(0)),
+        ..Default::default()
+    };
+    assert_eq!(options.validate().unwrap_err(), Error::invalid_parameter());
+    let options = StreamOptions {
+        bit_perfect: true,
+        ..Default::default()
+    };
+    assert!(options.validate().is_ok());
+    let options = StreamOptions {
+        bit_perfect: true,
+        render_quantum: Some(128),
+        ..Default::default()
+    };
+    assert_eq!(options.validate().unwrap_err(), Error::invalid_parameter());
+    let options = StreamOptions {
+        bit_perfect: true,
+        output_channel_map: Some(vec![0]),
+        ..Default::default()
+    };
+    assert_eq!(options.validate().unwrap_err(), Error::invalid_parameter());
+}
+
+// stream_init_with_callbacks
+// ------------------------------------
+#[test]
+fn test_stream_init_with_callbacks_validates_options() {
+    let mut input_params = ffi::cubeb_stream_params::default();
+    input_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    input_params.rate = 48_000;
+    input_params.channels = 1;
+    input_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    input_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+    let input_params = StreamParams::from(input_params);
+
+    let context = AudioUnitContext::new();
+    let result = context.stream_init_with_callbacks(
+        ptr::null_mut(),
+        Some(&input_params),
+        ptr::null_mut(),
+        None,
+        4096,
+        StreamCallbacks::default(),
+        StreamOptions {
+            input_channel_map: Some(vec![0, 1]),
+            ..Default::default()
+        },
+    );
+    assert_eq!(result.unwrap_err(), Error::invalid_parameter());
+}
+
+#[test]
+fn test_stream_init_with_callbacks_shares_context() {
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device to create the streams.");
+        return;
+    }
+
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = 48_000;
+    output_params.channels = 2;
+    output_params.layout = ffi::CUBEB_LAYOUT_STEREO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+    let output_params = StreamParams::from(output_params);
+
+    // The streams borrow the context, so they can live at the same time.
+    let context = AudioUnitContext::default();
+    let init = || {
+        context
+            .stream_init_with_callbacks(
+                ptr::null_mut(),
+                None,
+                ptr::null_mut(),
+                Some(&output_params),
+                4096,
+                StreamCallbacks::default(),
+                StreamOptions::default(),
+            )
+            .unwrap()
+    };
+    let first = init();
+    let second = init();
+    // The second stream runs on the same device, with the latency of the first one.
+    assert_eq!(
+        second.latency_decision().latency,
+        first.latency_decision().latency
+    );
+    assert!(second.latency_decision().shared);
+}
+
+// input_channel_map
+// ------------------------------------
+#[test]
+fn test_input_channel_map() {
+    let mut input_params = ffi::cubeb_stream_params::default();
+    input_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    input_params.rate = 48_000;
+    input_params.channels = 1;
+    input_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    input_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+    let input_params = StreamParams::from(input_params);
+
+    let mut context = AudioUnitContext::new();
+    let mut init = |map: Vec<u32>| {
+        context.stream_init_with_options(
+            None,
+            ptr::null_mut(),
+            Some(&input_params),
+            ptr::null_mut(),
+            None,
+            4096,
+            None,
+            None,
+            ptr::null_mut(),
+            StreamOptions {
+                input_channel_map: Some(map),
+                ..Default::default()
+            },
+        )
+    };
+
+    // One device channel for each input channel.
+    assert_eq!(init(vec![0, 1]).unwrap_err(), Error::invalid_parameter());
+    assert_eq!(init(vec![]).unwrap_err(), Error::invalid_parameter());
+
+    let device = match test_get_default_device(Scope::Input) {
+        Some(device) => device,
+        None => {
+            println!("No input device to validate the input channel map.");
+            return;
+        }
+    };
+    let channels = audiounit_get_channel_count(device, kAudioDevicePropertyScopeInput);
+    assert!(channels > 0);
+    assert_eq!(
+        init(vec![channels]).unwrap_err(),
+        Error::invalid_parameter()
+    );
+    assert!(init(vec![channels - 1]).is_ok());
+}
+
+// input layout
+// ------------------------------------
+#[test]
+fn test_input_stream_with_layouts() {
+    if test_get_default_device(Scope::Input).is_none() {
+        println!("No input device to mix the input channels.");
+        return;
+    }
+
+    let mut context = AudioUnitContext::new();
+    // Mix the device channels to each layout, whatever the device layout is.
+    for (layout, channels) in &[
+        (ffi::CUBEB_LAYOUT_MONO, 1),
+        (ffi::CUBEB_LAYOUT_STEREO, 2),
+        (ffi::CUBEB_LAYOUT_QUAD, 4),
+    ] {
+        let mut input_params = ffi::cubeb_stream_params::default();
+        input_params.format = ffi::CUBEB_SAMPLE_S16NE;
+        input_params.rate = 48_000;
+        input_params.channels = *channels;
+        input_params.layout = *layout;
+        input_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+        let input_params = StreamParams::from(input_params);
+        let mut stream = StreamBuilder::<i16>::new()
+            .input(ptr::null_mut(), input_params)
+            .latency(4096)
+            .data_callback(|input, _| input.len())
+            .init(&mut context)
+            .unwrap();
+        assert!(stream.start().is_ok());
+        assert!(stream.stop().is_ok());
+    }
+}
+
+// output_channel_map
+// ------------------------------------
+#[test]
+fn test_output_channel_map() {
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = 48_000;
+    output_params.channels = 1;
+    output_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+    let output_params = StreamParams::from(output_params);
+
+    let mut context = AudioUnitContext::new();
+    let mut init = |map: Vec<u32>| {
+        context.stream_init_with_options(
+            None,
+            ptr::null_mut(),
+            None,
+            ptr::null_mut(),
+            Some(&output_params),
+            4096,
+            None,
+            None,
+            ptr::null_mut(),
+            StreamOptions {
+                output_channel_map: Some(map),
+                ..Default::default()
+            },
+        )
+    };
+
+    // One device channel for each output channel.
+    assert_eq!(init(vec![0, 1]).unwrap_err(), Error::invalid_parameter());
+    assert_eq!(init(vec![]).unwrap_err(), Error::invalid_parameter());
+
+    let device = match test_get_default_device(Scope::Output) {
+        Some(device) => device,
+        None => {
+            println!("No output device to validate the output channel map.");
+            return;
+        }
+    };
+    let channels = audiounit_get_channel_count(device, kAudioDevicePropertyScopeOutput);
+    assert!(channels > 0);
+    assert_eq!(
+        init(vec![channels]).unwrap_err(),
+        Error::invalid_parameter()
+    );
+    assert!(init(vec![channels - 1]).is_ok());
+}
+
+// encoded_format
+// ------------------------------------
+#[test]
+fn test_encoded_format() {
+    let mut context = AudioUnitContext::new();
+    let mut init = |format: ffi::cubeb_sample_format, channels: u32, duplex: bool| {
+        let mut params = ffi::cubeb_stream_params::default();
+        params.format = format;
+        params.rate = 48_000;
+        params.channels = channels;
+        params.layout = ffi::CUBEB_LAYOUT_UNDEFINED;
+        params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+        let params = StreamParams::from(params);
+        context.stream_init_with_options(
+            None,
+            ptr::null_mut(),
+            if duplex { Some(&params) } else { None },
+            ptr::null_mut(),
+            Some(&params),
+            4096,
+            None,
+            None,
+            ptr::null_mut(),
+            StreamOptions {
+                encoded_format: Some(EncodedFormat::Ac3),
+                ..Default::default()
+            },
+        )
+    };
+
+    // The bursts are carried in 16-bit stereo frames of an output only stream.
+    assert_eq!(
+        init(ffi::CUBEB_SAMPLE_FLOAT32NE, 2, false).unwrap_err(),
+        Error::invalid_format()
+    );
+    assert_eq!(
+        init(ffi::CUBEB_SAMPLE_S16NE, 1, false).unwrap_err(),
+        Error::invalid_format()
+    );
+    assert_eq!(
+        init(ffi::CUBEB_SAMPLE_S16NE, 2, true).unwrap_err(),
+        Error::invalid_parameter()
+    );
+
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device to pass AC-3 through.");
+        return;
+    }
+    // Most of the devices, like the built-in speakers, can't take AC-3.
+    match init(ffi::CUBEB_SAMPLE_S16NE, 2, false) {
+        Ok(_) => {}
+        Err(e) => assert!(e == Error::not_supported() || e == Error::device_unavailable()),
+    }
+}
+
+// device sample rates
+// ------------------------------------
+#[test]
+fn test_set_device_nominal_sample_rate() {
+    let device = match test_get_default_device(Scope::Output) {
+        Some(device) => device,
+        None => {
+            println!("No output device to set the rate.");
+            return;
+        }
+    };
+
+    let ranges = get_device_sample_rate_ranges(device).unwrap();
+    assert!(!ranges.is_empty());
+    let rate = get_device_nominal_sample_rate(device).unwrap();
+    assert!(ranges
+        .iter()
+        .any(|range| range.mMinimum <= rate && rate <= range.mMaximum));
+
+    // Setting the current rate changes nothing.
+    assert!(set_device_nominal_sample_rate(device, rate).is_ok());
+    assert_eq!(get_device_nominal_sample_rate(device).unwrap(), rate);
+    assert_eq!(
+        set_device_nominal_sample_rate(device, 1.0).unwrap_err(),
+        Error::invalid_format()
+    );
+}
+
+// HogMode
+// ------------------------------------
+#[test]
+fn test_hog_mode() {
+    let device = match test_get_default_device(Scope::Output) {
+        Some(device) => device,
+        None => {
+            println!("No output device to hog.");
+            return;
+        }
+    };
+    let get_owner = || {
+        let mut owner: libc::pid_t = 0;
+        let mut size = mem::size_of::<libc::pid_t>();
+        assert_eq!(
+            audio_object_get_property_data(
+                device,
+                &HOG_MODE_PROPERTY_ADDRESS,
+                &mut size,
+                &mut owner
+            ),
+            NO_ERR
+        );
+        owner
+    };
+    if get_owner() != -1 {
+        println!("The output device is already hogged.");
+        return;
+    }
+
+    let hog_mode = HogMode::acquire(device).unwrap();
+    assert_eq!(get_owner(), std::process::id() as libc::pid_t);
+    // The device can't be taken twice.
+    assert_eq!(
+        HogMode::acquire(device).unwrap_err(),
+        Error::device_unavailable()
+    );
+    drop(hog_mode);
+    assert_eq!(get_owner(), -1);
 }
 
 // set_buffer_size_sync
@@ -1454,6 +2087,90 @@ fn test_set_buffer_size_sync_by_scope_with_null_unit(scope: Scope) {
 // ------------------------------------
 // TODO
 
+// pause, resume
+// ------------------------------------
+#[test]
+fn test_stream_stop_keeps_frame_counters() {
+    test_get_default_raw_stream(|stream| {
+        assert!(stream.start().is_ok());
+        stream.frames_played.store(1024, Ordering::SeqCst);
+        stream.frames_queued.store(1536, Ordering::SeqCst);
+        // Unlike pause, stop leaves the counters as they are.
+        assert!(stream.stop().is_ok());
+        assert_eq!(stream.frames_played.load(Ordering::SeqCst), 1024);
+        assert_eq!(stream.frames_queued.load(Ordering::SeqCst), 1536);
+        assert!(stream.pause().is_ok());
+        assert_eq!(stream.frames_queued.load(Ordering::SeqCst), 1024);
+    });
+}
+
+#[test]
+fn test_stream_pause_and_resume_keep_position() {
+    test_get_default_raw_stream(|stream| {
+        assert!(stream.resume(BufferedInput::Flush).is_ok());
+        stream.frames_played.store(1024, Ordering::SeqCst);
+        stream.frames_queued.store(1536, Ordering::SeqCst);
+        stream.frames_played_time.store(1, Ordering::SeqCst);
+
+        assert!(stream.pause().is_ok());
+        assert!(stream.shutdown.load(Ordering::SeqCst));
+        // The unplayed queued frames are dropped, so the position is frozen.
+        assert_eq!(stream.frames_queued.load(Ordering::SeqCst), 1024);
+        assert_eq!(stream.frames_played_time.load(Ordering::SeqCst), 0);
+        let position = stream.position().unwrap();
+
+        assert!(stream.resume(BufferedInput::Keep).is_ok());
+        assert!(!stream.shutdown.load(Ordering::SeqCst));
+        assert_eq!(stream.position().unwrap(), position);
+        assert!(stream.pause().is_ok());
+    });
+}
+
+#[test]
+fn test_stream_resume_with_buffered_input() {
+    const CHANNELS: u32 = 2;
+    const FRAMES: usize = 100;
+    let samples = [0.5_f32; FRAMES * CHANNELS as usize];
+
+    for input in &[BufferedInput::Keep, BufferedInput::Flush] {
+        test_get_default_raw_stream(|stream| {
+            let mut desc = AudioStreamBasicDescription::default();
+            desc.mFormatFlags |= kAudioFormatFlagIsFloat;
+            desc.mChannelsPerFrame = CHANNELS;
+            stream.core_stream_data.input_desc = desc;
+            let mut buffer = create_auto_array(desc, FRAMES as u32, 1).unwrap();
+            buffer.push(samples.as_ptr() as *const c_void, samples.len());
+            stream.core_stream_data.input_linear_buffer = Some(buffer);
+            stream.frames_read.store(12345, Ordering::SeqCst);
+            stream.frames_written.store(67890, Ordering::SeqCst);
+
+            assert!(stream.resume(*input).is_ok());
+
+            let expected_frames = if *input == BufferedInput::Keep {
+                FRAMES
+            } else {
+                0
+            };
+            assert_eq!(
+                stream
+                    .core_stream_data
+                    .input_linear_buffer
+                    .as_ref()
+                    .unwrap()
+                    .elements(),
+                expected_frames * CHANNELS as usize
+            );
+            // The duplex alignment restarts from the buffered input.
+            assert_eq!(
+                stream.frames_read.load(Ordering::SeqCst),
+                expected_frames as i64
+            );
+            assert_eq!(stream.frames_written.load(Ordering::SeqCst), 0);
+            assert!(stream.pause().is_ok());
+        });
+    }
+}
+
 // get_volume, set_volume
 // ------------------------------------
 #[test]
@@ -1547,67 +2264,114 @@ fn test_get_channel_count() {
     }
 }
 
-// get_available_samplerate
+// get_device_sample_rates
 // ------------------------------------
 #[test]
-fn test_get_available_samplerate() {
-    let samplerates = test_get_available_samplerate_of_device(kAudioObjectUnknown);
-    for rates in samplerates {
-        check_samplerates_are_zeros(rates);
+fn test_get_device_sample_rates() {
+    const SCOPES: [AudioObjectPropertyScope; 3] = [
+        kAudioObjectPropertyScopeGlobal,
+        kAudioDevicePropertyScopeInput,
+        kAudioDevicePropertyScopeOutput,
+    ];
+    for scope in SCOPES.iter() {
+        // Hit the kAudioHardwareBadObjectError actually.
+        assert!(get_device_sample_rates(kAudioObjectUnknown, *scope).is_err());
     }
 
-    test_get_available_samplerate_in_scope(Scope::Input);
-    test_get_available_samplerate_in_scope(Scope::Output);
+    test_get_device_sample_rates_in_scope(Scope::Input);
+    test_get_device_sample_rates_in_scope(Scope::Output);
 
-    fn test_get_available_samplerate_in_scope(scope: Scope) {
+    fn test_get_device_sample_rates_in_scope(scope: Scope) {
         if let Some(device) = test_get_default_device(scope.clone()) {
-            let samplerates = test_get_available_samplerate_of_device(device);
-            for rates in samplerates {
+            for scope in SCOPES.iter() {
                 // Surprisingly, we can get the input/output samplerates from a non-input/non-output device.
-                check_samplerates(rates);
+                let rates = get_device_sample_rates(device, *scope).unwrap();
+                assert!(rates.default > 0);
+                assert!(!rates.ranges.is_empty());
+                for range in &rates.ranges {
+                    assert!(range.min > 0);
+                    assert!(range.min <= range.max);
+                }
+                assert!(rates
+                    .ranges
+                    .iter()
+                    .any(|range| range.contains(rates.default)));
             }
         } else {
             println!("No device for {:?}.", scope);
         }
     }
+}
 
-    fn test_get_available_samplerate_of_device(id: AudioObjectID) -> Vec<(u32, u32, u32)> {
-        let scopes = [
-            kAudioObjectPropertyScopeGlobal,
-            kAudioDevicePropertyScopeInput,
-            kAudioDevicePropertyScopeOutput,
-        ];
-        let mut samplerates = Vec::new();
-        for scope in scopes.iter() {
-            samplerates.push(test_get_available_samplerate_of_device_in_scope(id, *scope));
+// get_device_formats
+// ------------------------------------
+#[test]
+fn test_get_device_formats() {
+    assert!(get_device_formats(kAudioObjectUnknown, kAudioDevicePropertyScopeInput).is_err());
+    assert!(get_device_formats(kAudioObjectUnknown, kAudioDevicePropertyScopeOutput).is_err());
+
+    test_get_device_formats_in_scope(Scope::Input);
+    test_get_device_formats_in_scope(Scope::Output);
+
+    fn test_get_device_formats_in_scope(scope: Scope) {
+        let property_scope = match scope {
+            Scope::Input => kAudioDevicePropertyScopeInput,
+            Scope::Output => kAudioDevicePropertyScopeOutput,
+        };
+        if let Some(device) = test_get_default_device(scope.clone()) {
+            let formats = get_device_formats(device, property_scope).unwrap();
+            assert_ne!(formats.supported, 0);
+            assert_eq!(formats.supported & !ffi::CUBEB_DEVICE_FMT_ALL, 0);
+            // The default is one of the supported formats.
+            assert_eq!(formats.default.count_ones(), 1);
+            assert_ne!(formats.supported & formats.default, 0);
+        } else {
+            println!("No device for {:?}.", scope);
         }
-        samplerates
     }
+}
 
-    fn test_get_available_samplerate_of_device_in_scope(
-        id: AudioObjectID,
-        scope: AudioObjectPropertyScope,
-    ) -> (u32, u32, u32) {
-        let mut default = 0;
-        let mut min = 0;
-        let mut max = 0;
-        audiounit_get_available_samplerate(id, scope, &mut min, &mut max, &mut default);
-        (min, max, default)
-    }
+// get_device_uid, get_device_label, get_device_manufacturer, get_device_latency_range
+// ------------------------------------
+#[test]
+fn test_get_device_properties() {
+    let bad_object = kAudioHardwareBadObjectError as OSStatus;
+    assert_eq!(get_device_uid(kAudioObjectUnknown).unwrap_err(), bad_object);
+    assert_eq!(
+        get_device_label(kAudioObjectUnknown, kAudioDevicePropertyScopeOutput).unwrap_err(),
+        bad_object
+    );
+    assert_eq!(
+        get_device_manufacturer(kAudioObjectUnknown).unwrap_err(),
+        bad_object
+    );
+    assert_eq!(
+        get_device_latency_range(kAudioObjectUnknown, kAudioDevicePropertyScopeOutput).unwrap_err(),
+        bad_object
+    );
+    assert_eq!(
+        get_channel_count(kAudioObjectUnknown, kAudioDevicePropertyScopeOutput).unwrap_err(),
+        bad_object
+    );
 
-    fn check_samplerates((min, max, default): (u32, u32, u32)) {
-        assert!(default > 0);
-        assert!(min > 0);
-        assert!(max > 0);
-        assert!(min <= max);
-        assert!(min <= default);
-        assert!(default <= max);
-    }
+    test_get_device_properties_in_scope(Scope::Input);
+    test_get_device_properties_in_scope(Scope::Output);
 
-    fn check_samplerates_are_zeros((min, max, default): (u32, u32, u32)) {
-        assert_eq!(min, 0);
-        assert_eq!(max, 0);
-        assert_eq!(default, 0);
+    fn test_get_device_properties_in_scope(scope: Scope) {
+        let property_scope = match scope {
+            Scope::Input => kAudioDevicePropertyScopeInput,
+            Scope::Output => kAudioDevicePropertyScopeOutput,
+        };
+        if let Some(device) = test_get_default_device(scope.clone()) {
+            assert!(!get_device_uid(device).unwrap().is_empty());
+            assert!(!get_device_label(device, property_scope).unwrap().is_empty());
+            let (lo, hi) = get_device_latency_range(device, property_scope).unwrap();
+            assert!(lo > 0);
+            assert!(lo <= hi);
+            assert!(get_channel_count(device, property_scope).unwrap() > 0);
+        } else {
+            println!("No device for {:?}.", scope);
+        }
     }
 }
 
@@ -1695,80 +2459,80 @@ fn test_create_device_from_hwdev() {
 
     fn test_create_device_from_hwdev_by_device(
         id: AudioObjectID,
-    ) -> VecDeque<std::result::Result<ffi::cubeb_device_info, Error>> {
+    ) -> VecDeque<std::result::Result<DeviceInfo, Error>> {
         let dev_types = [DeviceType::INPUT, DeviceType::OUTPUT];
         let mut results = VecDeque::new();
         for dev_type in dev_types.iter() {
-            let mut info = ffi::cubeb_device_info::default();
-            let result = audiounit_create_device_from_hwdev(&mut info, id, *dev_type);
-            results.push_back(if result.is_ok() {
-                Ok(info)
-            } else {
-                Err(result.unwrap_err())
-            });
+            results.push_back(audiounit_create_device_from_hwdev(id, *dev_type));
         }
         results
     }
 
-    fn check_device_info_by_device(info: ffi::cubeb_device_info, id: AudioObjectID, scope: Scope) {
-        assert!(!info.devid.is_null());
-        assert!(mem::size_of_val(&info.devid) >= mem::size_of::<AudioObjectID>());
-        assert_eq!(info.devid as AudioObjectID, id);
-        assert!(!info.device_id.is_null());
-        assert!(!info.friendly_name.is_null());
-        assert_eq!(info.group_id, info.device_id);
+    fn check_device_info_by_device(info: DeviceInfo, id: AudioObjectID, scope: Scope) {
+        assert_eq!(info.id, id);
+        assert!(info.uid.is_some());
+        assert_eq!(info.group_id, info.uid);
         // TODO: Hit a kAudioHardwareUnknownPropertyError for AirPods
-        // assert!(!info.vendor_name.is_null());
-
-        // FIXIT: The device is defined to input-only or output-only, but some device is in-out!
-        assert_eq!(info.device_type, DeviceType::from(scope.clone()).bits());
-        assert_eq!(info.state, ffi::CUBEB_DEVICE_STATE_ENABLED);
-        // TODO: The preference is set when the device is default input/output device if the device
-        //       info is created from input/output scope. Should the preference be set if the
-        //       device is a default input/output device if the device info is created from
-        //       output/input scope ? The device may be a in-out device!
-        assert_eq!(info.preferred, get_cubeb_device_pref(id, scope));
-
-        assert_eq!(info.format, ffi::CUBEB_DEVICE_FMT_ALL);
-        assert_eq!(info.default_format, ffi::CUBEB_DEVICE_FMT_F32NE);
-        assert!(info.max_channels > 0);
-        assert!(info.min_rate <= info.max_rate);
-        assert!(info.min_rate <= info.default_rate);
-        assert!(info.default_rate <= info.max_rate);
+        // assert!(info.vendor_name.is_some());
+
+        // An in-out device has an entry of each type. See test_enumerate_in_out_devices for the
+        // combined entry.
+        assert_eq!(info.device_type, DeviceType::from(scope.clone()));
+        // Only the preference of the scope the entry is created from is set.
+        let preferred = test_get_default_device(scope.clone()) == Some(id);
+        match scope {
+            Scope::Input => assert_eq!(
+                (info.preferred_input, info.preferred_output),
+                (preferred, false)
+            ),
+            Scope::Output => assert_eq!(
+                (info.preferred_input, info.preferred_output),
+                (false, preferred)
+            ),
+        }
+        assert_eq!(info.is_preferred(), preferred);
+
+        match scope {
+            Scope::Input => assert!(info.input_channels > 0 && info.output_channels == 0),
+            Scope::Output => assert!(info.output_channels > 0 && info.input_channels == 0),
+        }
+        assert!(info.min_rate() <= info.max_rate());
+        assert!(info.supports_sample_rate(info.default_rate));
+        let rates = info.sample_rates();
+        assert!(rates.iter().all(|rate| info.supports_sample_rate(*rate)));
+        let (min_rate, max_rate) = (info.min_rate(), info.max_rate());
 
         assert!(info.latency_lo > 0);
         assert!(info.latency_hi > 0);
         assert!(info.latency_lo <= info.latency_hi);
 
-        fn get_cubeb_device_pref(id: AudioObjectID, scope: Scope) -> ffi::cubeb_device_pref {
-            let default_device = test_get_default_device(scope);
-            if default_device.is_some() && default_device.unwrap() == id {
-                ffi::CUBEB_DEVICE_PREF_ALL
-            } else {
-                ffi::CUBEB_DEVICE_PREF_NONE
-            }
-        }
+        let device = ffi::cubeb_device_info::from(info);
+        assert_eq!(device.devid as AudioObjectID, id);
+        assert_eq!((device.min_rate, device.max_rate), (min_rate, max_rate));
+        assert_eq!(device.state, ffi::CUBEB_DEVICE_STATE_ENABLED);
+        assert_ne!(device.format, 0);
+        assert_eq!(device.format & !ffi::CUBEB_DEVICE_FMT_ALL, 0);
+        assert_ne!(device.format & device.default_format, 0);
+        assert!(!device.device_id.is_null());
+        assert!(!device.friendly_name.is_null());
+        // The group id is a copy of the device id, not an alias.
+        assert!(!device.group_id.is_null());
+        assert_ne!(device.group_id, device.device_id);
+        let mut device = device;
+        audiounit_device_destroy(&mut device);
     }
 }
 
 #[test]
 #[should_panic]
 fn test_create_device_from_hwdev_unknown_type() {
-    let mut info = ffi::cubeb_device_info::default();
-    assert!(audiounit_create_device_from_hwdev(
-        &mut info,
-        kAudioObjectUnknown,
-        DeviceType::UNKNOWN
-    )
-    .is_err());
+    assert!(audiounit_create_device_from_hwdev(kAudioObjectUnknown, DeviceType::UNKNOWN).is_err());
 }
 
 #[test]
 #[should_panic]
 fn test_create_device_from_hwdev_inout_type() {
-    let mut info = ffi::cubeb_device_info::default();
     assert!(audiounit_create_device_from_hwdev(
-        &mut info,
         kAudioObjectUnknown,
         DeviceType::INPUT | DeviceType::OUTPUT
     )
@@ -1781,14 +2545,12 @@ fn test_create_device_from_hwdev_inout_type() {
 fn test_is_aggregate_device() {
     let mut aggregate_name = String::from(PRIVATE_AGGREGATE_DEVICE_NAME);
     aggregate_name.push_str("_something");
-    let aggregate_name_cstring = CString::new(aggregate_name).unwrap();
 
-    let mut info = ffi::cubeb_device_info::default();
-    info.friendly_name = aggregate_name_cstring.as_ptr();
+    let mut info = test_device_info();
+    info.friendly_name = aggregate_name;
     assert!(is_aggregate_device(&info));
 
-    let non_aggregate_name_cstring = CString::new("Hello World!").unwrap();
-    info.friendly_name = non_aggregate_name_cstring.as_ptr();
+    info.friendly_name = String::from("Hello World!");
     assert!(!is_aggregate_device(&info));
 }
 
@@ -1796,17 +2558,11 @@ fn test_is_aggregate_device() {
 // ------------------------------------
 #[test]
 fn test_device_destroy() {
-    let mut device = ffi::cubeb_device_info::default();
-
-    let device_id = CString::new("test: device id").unwrap();
-    let friendly_name = CString::new("test: friendly name").unwrap();
-    let vendor_name = CString::new("test: vendor name").unwrap();
-
-    device.device_id = device_id.into_raw();
-    // The group_id is a mirror to device_id in our implementation, so we could skip it.
-    device.group_id = device.device_id;
-    device.friendly_name = friendly_name.into_raw();
-    device.vendor_name = vendor_name.into_raw();
+    let mut device = ffi::cubeb_device_info::from(test_device_info());
+    assert!(!device.device_id.is_null());
+    assert!(!device.group_id.is_null());
+    assert!(!device.friendly_name.is_null());
+    assert!(!device.vendor_name.is_null());
 
     audiounit_device_destroy(&mut device);
 
@@ -1814,30 +2570,25 @@ fn test_device_destroy() {
     assert!(device.group_id.is_null());
     assert!(device.friendly_name.is_null());
     assert!(device.vendor_name.is_null());
-}
-
-#[test]
-#[should_panic]
-fn test_device_destroy_with_different_device_id_and_group_id() {
-    let mut device = ffi::cubeb_device_info::default();
-
-    let device_id = CString::new("test: device id").unwrap();
-    let group_id = CString::new("test: group id").unwrap();
-    let friendly_name = CString::new("test: friendly name").unwrap();
-    let vendor_name = CString::new("test: vendor name").unwrap();
-
-    device.device_id = device_id.into_raw();
-    device.group_id = group_id.into_raw();
-    device.friendly_name = friendly_name.into_raw();
-    device.vendor_name = vendor_name.into_raw();
 
+    // Nothing is released twice.
     audiounit_device_destroy(&mut device);
-    // Hit the assertion above, so we will leak some memory allocated for the above cstring.
+}
 
+#[test]
+fn test_device_destroy_without_optional_strings() {
+    let mut info = test_device_info();
+    info.uid = None;
+    info.group_id = None;
+    info.vendor_name = None;
+    let mut device = ffi::cubeb_device_info::from(info);
     assert!(device.device_id.is_null());
     assert!(device.group_id.is_null());
-    assert!(device.friendly_name.is_null());
+    assert!(!device.friendly_name.is_null());
     assert!(device.vendor_name.is_null());
+
+    audiounit_device_destroy(&mut device);
+    assert!(device.friendly_name.is_null());
 }
 
 #[test]
@@ -1857,6 +2608,108 @@ fn test_device_destroy_empty_device() {
     assert!(device.vendor_name.is_null());
 }
 
+// enumerate_devices_with_options
+// ------------------------------------
+#[test]
+fn test_enumerate_in_out_devices() {
+    let mut context = AudioUnitContext::new();
+    let separate = enumerate(&mut context, false);
+    let combined = enumerate(&mut context, true);
+
+    // Each device has one entry, with the types of all its entries in the separate enumeration.
+    for (devid, device_type, group_id) in &combined {
+        let entries: Vec<_> = separate
+            .iter()
+            .filter(|(separate_devid, _, _)| separate_devid == devid)
+            .collect();
+        assert!(!entries.is_empty());
+        let types = entries
+            .iter()
+            .fold(0, |types, (_, device_type, _)| types | device_type);
+        assert_eq!(*device_type, types);
+        // The halves of an in-out device share a stable group.
+        for (_, _, separate_group_id) in entries {
+            assert_eq!(separate_group_id, group_id);
+        }
+    }
+    assert!(combined.len() <= separate.len());
+
+    fn enumerate(
+        context: &mut AudioUnitContext,
+        combine: bool,
+    ) -> Vec<(ffi::cubeb_devid, ffi::cubeb_device_type, Option<String>)> {
+        let mut coll = ffi::cubeb_device_collection {
+            device: ptr::null_mut(),
+            count: 0,
+        };
+        let options = EnumerateOptions {
+            combine_in_out_devices: combine,
+        };
+        assert!(context
+            .enumerate_devices_with_options(
+                DeviceType::INPUT | DeviceType::OUTPUT,
+                unsafe { DeviceCollectionRef::from_ptr(&mut coll) },
+                options,
+            )
+            .is_ok());
+        let devices = if coll.device.is_null() {
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(coll.device, coll.count) }
+        };
+        let entries = devices
+            .iter()
+            .map(|device| {
+                let group_id = if device.group_id.is_null() {
+                    None
+                } else {
+                    let group_id = unsafe { CStr::from_ptr(device.group_id) };
+                    Some(group_id.to_string_lossy().into_owned())
+                };
+                (device.devid, device.device_type, group_id)
+            })
+            .collect();
+        assert!(context
+            .device_collection_destroy(unsafe { DeviceCollectionRef::from_ptr_mut(&mut coll) })
+            .is_ok());
+        entries
+    }
+}
+
+// device_infos
+// ------------------------------------
+#[test]
+fn test_device_infos() {
+    let context = AudioUnitContext::new();
+    for devtype in &[DeviceType::INPUT, DeviceType::OUTPUT] {
+        for info in context
+            .device_infos(*devtype, EnumerateOptions::default())
+            .unwrap()
+        {
+            assert_eq!(info.device_type, *devtype);
+            let rates = info.sample_rates();
+            assert!(rates.windows(2).all(|pair| pair[0] < pair[1]));
+            // Every discrete rate of the device is listed.
+            for range in &info.sample_rate_ranges {
+                if range.min == range.max {
+                    assert!(rates.contains(&range.min));
+                }
+            }
+        }
+    }
+
+    // The in-out devices can only be combined when both types are enumerated.
+    let options = EnumerateOptions {
+        combine_in_out_devices: true,
+    };
+    for devtype in &[DeviceType::INPUT, DeviceType::OUTPUT] {
+        assert_eq!(
+            context.device_infos(*devtype, options.clone()).unwrap_err(),
+            Error::invalid_parameter()
+        );
+    }
+}
+
 // get_devices_of_type
 // ------------------------------------
 #[test]
@@ -1867,7 +2720,11 @@ fn test_get_devices_of_type() {
     let input_devices = audiounit_get_devices_of_type(DeviceType::INPUT);
     let output_devices = audiounit_get_devices_of_type(DeviceType::OUTPUT);
 
+    // The devices without any channels are left out.
     let mut expected_all = test_get_all_devices();
+    expected_all.retain(|device| {
+        test_device_in_scope(*device, Scope::Input) || test_device_in_scope(*device, Scope::Output)
+    });
     expected_all.sort();
     assert_eq!(all_devices, expected_all);
     for device in all_devices.iter() {
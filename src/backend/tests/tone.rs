@@ -88,3 +88,604 @@ fn test_dial_tone() {
         (x * f32::from(i16::max_value())) as i16
     }
 }
+
+#[test]
+fn test_drain_tone_tail() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const SAMPLE_FREQUENCY: u32 = 48_000;
+    const TONE_FRAMES: i64 = SAMPLE_FREQUENCY as i64 / 5; // 200 ms.
+
+    // Do nothing if there is no available output device.
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device.");
+        return;
+    }
+
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = SAMPLE_FREQUENCY;
+    output_params.channels = 1;
+    output_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+
+    struct Data {
+        position: i64,
+        tone_ended: Option<Instant>,
+        drained: AtomicBool,
+        drained_after: Option<Duration>,
+    }
+
+    let mut data = Data {
+        position: 0,
+        tone_ended: None,
+        drained: AtomicBool::new(false),
+        drained_after: None,
+    };
+
+    test_ops_stream_operation(
+        "stream: drain tone tail",
+        ptr::null_mut(), // Use default input device.
+        ptr::null_mut(), // No input parameters.
+        ptr::null_mut(), // Use default output device.
+        &mut output_params,
+        4096,
+        Some(data_callback),
+        Some(state_callback),
+        &mut data as *mut Data as *mut c_void,
+        |stream| {
+            assert_eq!(unsafe { OPS.stream_start.unwrap()(stream) }, ffi::CUBEB_OK);
+            let mut waited = Duration::from_millis(0);
+            while !data.drained.load(Ordering::SeqCst) && waited < Duration::from_secs(2) {
+                thread::sleep(Duration::from_millis(10));
+                waited += Duration::from_millis(10);
+            }
+            assert!(data.drained.load(Ordering::SeqCst));
+
+            // The drained state is notified after the tail goes through the device latency. The
+            // callbacks don't run at exact times, so allow a quarter of the latency earlier.
+            let mut latency: u32 = 0;
+            assert_eq!(
+                unsafe { OPS.stream_get_latency.unwrap()(stream, &mut latency) },
+                ffi::CUBEB_OK
+            );
+            let latency_duration =
+                Duration::from_millis(u64::from(latency) * 1000 / u64::from(SAMPLE_FREQUENCY));
+            let tolerance = latency_duration / 4;
+            assert!(data.drained_after.unwrap() + tolerance >= latency_duration);
+
+            assert_eq!(unsafe { OPS.stream_stop.unwrap()(stream) }, ffi::CUBEB_OK);
+        },
+    );
+
+    extern "C" fn state_callback(
+        stream: *mut ffi::cubeb_stream,
+        user_ptr: *mut c_void,
+        state: ffi::cubeb_state,
+    ) {
+        assert!(!stream.is_null());
+        assert!(!user_ptr.is_null());
+        assert_ne!(state, ffi::CUBEB_STATE_ERROR);
+        let data = unsafe { &mut *(user_ptr as *mut Data) };
+        if state == ffi::CUBEB_STATE_DRAINED {
+            data.drained_after = data.tone_ended.map(|ended| ended.elapsed());
+            data.drained.store(true, Ordering::SeqCst);
+        }
+    }
+
+    extern "C" fn data_callback(
+        stream: *mut ffi::cubeb_stream,
+        user_ptr: *mut c_void,
+        _input_buffer: *const c_void,
+        output_buffer: *mut c_void,
+        nframes: i64,
+    ) -> i64 {
+        assert!(!stream.is_null());
+        assert!(!user_ptr.is_null());
+        assert!(!output_buffer.is_null());
+
+        let data = unsafe { &mut *(user_ptr as *mut Data) };
+        let frames = cmp::min(nframes, TONE_FRAMES - data.position);
+        let buffer = unsafe {
+            let ptr = output_buffer as *mut f32;
+            let len = frames as usize;
+            slice::from_raw_parts_mut(ptr, len)
+        };
+
+        for sample in buffer.iter_mut() {
+            *sample = 0.5
+                * (2.0 * std::f32::consts::PI * 440.0 * data.position as f32
+                    / SAMPLE_FREQUENCY as f32)
+                    .sin();
+            data.position += 1;
+        }
+
+        if frames < nframes && data.tone_ended.is_none() {
+            data.tone_ended = Some(Instant::now());
+        }
+
+        frames
+    }
+}
+
+#[test]
+fn test_blocking_write_tone() {
+    use std::time::Duration;
+
+    const SAMPLE_FREQUENCY: u32 = 48_000;
+    const FRAMES: usize = 256;
+
+    // Do nothing if there is no available output device.
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device.");
+        return;
+    }
+
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = SAMPLE_FREQUENCY;
+    output_params.channels = 1;
+    output_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+
+    let context = AudioUnitContext::new();
+    let mut stream = context
+        .blocking_stream_init(
+            ptr::null_mut(),
+            None,
+            ptr::null_mut(),
+            Some(unsafe { StreamParamsRef::from_ptr(&mut output_params) }),
+            512,
+            BlockingOptions {
+                buffer_frames: 4 * FRAMES as u32,
+                timeout: Some(Duration::from_secs(1)),
+            },
+        )
+        .unwrap();
+
+    // The samples must match the format of the stream.
+    assert_eq!(
+        stream.write(&[0_i16; FRAMES]).unwrap_err(),
+        Error::invalid_format()
+    );
+    // There is no input to read.
+    assert_eq!(
+        stream.read(&mut [0_f32; FRAMES]).unwrap_err(),
+        Error::invalid_parameter()
+    );
+
+    // Pre-fill the buffer before starting. The write returns once the buffer is full.
+    let mut position = 0;
+    let mut buffer = [0_f32; FRAMES];
+    let mut render = |buffer: &mut [f32]| {
+        for sample in buffer.iter_mut() {
+            *sample = 0.5
+                * (2.0 * std::f32::consts::PI * 440.0 * position as f32 / SAMPLE_FREQUENCY as f32)
+                    .sin();
+            position += 1;
+        }
+    };
+    render(&mut buffer);
+    assert_eq!(stream.write(&buffer).unwrap(), FRAMES);
+    assert_eq!(stream.write_available(), 3 * FRAMES);
+
+    assert!(stream.start().is_ok());
+    // Write 500 ms of the tone. Every write blocks until the device consumes enough frames.
+    let mut written = FRAMES;
+    while written < SAMPLE_FREQUENCY as usize / 2 {
+        render(&mut buffer);
+        assert_eq!(stream.write(&buffer).unwrap(), FRAMES);
+        written += FRAMES;
+    }
+    assert!(stream.stop().is_ok());
+}
+
+#[test]
+fn test_typed_stream_tone() {
+    use std::time::Duration;
+
+    const SAMPLE_FREQUENCY: u32 = 48_000;
+    const TONE_FRAMES: usize = SAMPLE_FREQUENCY as usize / 5;
+
+    // Do nothing if there is no available output device.
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device.");
+        return;
+    }
+
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = SAMPLE_FREQUENCY;
+    output_params.channels = 1;
+    output_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+
+    let context = AudioUnitContext::new();
+
+    // The closure must take the samples in the format of the stream.
+    assert_eq!(
+        StreamBuilder::<i16>::new()
+            .output(ptr::null_mut(), StreamParams::from(output_params))
+            .data_callback(|_, output| output.len())
+            .init(&context)
+            .err(),
+        Some(Error::invalid_format())
+    );
+
+    let mut position = 0;
+    let mut stream = StreamBuilder::<f32>::new()
+        .output(ptr::null_mut(), StreamParams::from(output_params))
+        .latency(512)
+        .data_callback(move |input, output| {
+            assert!(input.is_empty());
+            let frames = cmp::min(output.len(), TONE_FRAMES - position);
+            for sample in output[..frames].iter_mut() {
+                *sample = 0.5
+                    * (2.0 * std::f32::consts::PI * 440.0 * position as f32
+                        / SAMPLE_FREQUENCY as f32)
+                        .sin();
+                position += 1;
+            }
+            frames
+        })
+        .init(&context)
+        .unwrap();
+
+    let events = stream.events();
+    assert!(stream.start().is_ok());
+    assert_eq!(
+        events.wait(Duration::from_secs(1)),
+        Some(StreamEvent::State(State::Started))
+    );
+    // The tone lasts 200 ms, then the stream drains.
+    let mut drained = false;
+    while let Some(event) = events.wait(Duration::from_secs(1)) {
+        if event == StreamEvent::State(State::Drained) {
+            drained = true;
+            break;
+        }
+    }
+    assert!(drained);
+    assert!(stream.stop().is_ok());
+}
+
+#[test]
+fn test_output_tap_tone() {
+    use std::thread;
+    use std::time::Duration;
+
+    const SAMPLE_FREQUENCY: u32 = 48_000;
+
+    // Do nothing if there is no available output device.
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device.");
+        return;
+    }
+
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = SAMPLE_FREQUENCY;
+    output_params.channels = 1;
+    output_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+
+    let context = AudioUnitContext::new();
+    let mut position = 0;
+    let mut stream = StreamBuilder::<f32>::new()
+        .output(ptr::null_mut(), StreamParams::from(output_params))
+        .latency(512)
+        .data_callback(move |_, output| {
+            for sample in output.iter_mut() {
+                *sample = 0.5
+                    * (2.0 * std::f32::consts::PI * 440.0 * position as f32
+                        / SAMPLE_FREQUENCY as f32)
+                        .sin();
+                position += 1;
+            }
+            output.len()
+        })
+        .init(&context)
+        .unwrap();
+
+    let mut tap = stream.stream().attach_output_tap(SAMPLE_FREQUENCY).unwrap();
+    let format = tap.format();
+    assert_eq!(format.format, SampleFormat::Float32NE);
+    assert_ne!(format.channels, 0);
+
+    assert!(stream.start().is_ok());
+    thread::sleep(Duration::from_millis(200));
+    assert!(stream.stop().is_ok());
+
+    // The tap got the tone, after being mixed to the channels of the device.
+    let frames = tap.available_frames();
+    assert!(frames > 0);
+    let mut buffer = vec![0.0_f32; frames * format.channels as usize];
+    assert_eq!(tap.read(&mut buffer).unwrap(), frames);
+    assert!(buffer.iter().any(|sample| *sample != 0.0));
+    assert_eq!(tap.dropped_frames(), 0);
+
+    stream.stream().detach_output_tap();
+    assert!(tap.is_closed());
+}
+
+#[test]
+fn test_debug_capture_tone() {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    const SAMPLE_FREQUENCY: u32 = 48_000;
+
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device.");
+        return;
+    }
+
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_S16NE;
+    output_params.rate = SAMPLE_FREQUENCY;
+    output_params.channels = 1;
+    output_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+
+    let dir = std::env::temp_dir().join(format!("cubeb-capture-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut context = AudioUnitContext::new();
+    context.set_debug_capture_dir(Some(dir.clone()));
+    assert_eq!(context.debug_capture_dir(), Some(dir.as_path()));
+    {
+        let mut stream = StreamBuilder::<i16>::new()
+            .output(ptr::null_mut(), StreamParams::from(output_params))
+            .latency(512)
+            .data_callback(|_, output| {
+                for sample in output.iter_mut() {
+                    *sample = 1000;
+                }
+                output.len()
+            })
+            .init(&context)
+            .unwrap();
+        assert!(stream.start().is_ok());
+        thread::sleep(Duration::from_millis(200));
+        assert!(stream.stop().is_ok());
+    }
+
+    // The files are finished once the stream is destroyed. There is no input file for an
+    // output-only stream.
+    let mut names: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 2);
+    assert!(names[0].ends_with("-callback-output.wav"));
+    assert!(names[1].ends_with("-device-output.wav"));
+
+    let content = fs::read(dir.join(&names[0])).unwrap();
+    assert!(content.len() > 44);
+    assert_eq!(&content[0..4], b"RIFF");
+    assert_eq!(&content[44..46], &1000_i16.to_le_bytes());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_output_level_meter_tone() {
+    use std::thread;
+    use std::time::Duration;
+
+    const SAMPLE_FREQUENCY: u32 = 48_000;
+
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device.");
+        return;
+    }
+
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = SAMPLE_FREQUENCY;
+    output_params.channels = 1;
+    output_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+
+    let context = AudioUnitContext::new();
+    let mut stream = StreamBuilder::<f32>::new()
+        .output(ptr::null_mut(), StreamParams::from(output_params))
+        .latency(512)
+        .options(StreamOptions {
+            meter_window: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .data_callback(|_, output| {
+            for sample in output.iter_mut() {
+                *sample = 0.25;
+            }
+            output.len()
+        })
+        .init(&context)
+        .unwrap();
+
+    // No input to measure.
+    assert!(stream.stream().input_level_meter().is_none());
+    let meter = stream.stream().output_level_meter().unwrap();
+    assert_ne!(meter.channels(), 0);
+
+    assert!(stream.start().is_ok());
+    thread::sleep(Duration::from_millis(200));
+    assert!(stream.stop().is_ok());
+
+    // The tone may be mixed to the channels of the device, but at least one channel plays it.
+    let levels = meter.levels();
+    assert!(levels
+        .iter()
+        .any(|level| level.peak > 0.0 && level.rms > 0.0));
+    assert!(levels.iter().all(|level| level.clipped_samples == 0));
+}
+
+#[test]
+fn test_start_at_tone() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const SAMPLE_FREQUENCY: u32 = 48_000;
+
+    if test_get_default_device(Scope::Output).is_none() {
+        println!("No output device.");
+        return;
+    }
+
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = SAMPLE_FREQUENCY;
+    output_params.channels = 1;
+    output_params.layout = ffi::CUBEB_LAYOUT_MONO;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+
+    let rendered_frames = Arc::new(AtomicUsize::new(0));
+    let rendered = rendered_frames.clone();
+    let context = AudioUnitContext::new();
+    let mut stream = StreamBuilder::<f32>::new()
+        .output(ptr::null_mut(), StreamParams::from(output_params))
+        .latency(512)
+        .data_callback(move |_, output| {
+            for sample in output.iter_mut() {
+                *sample = 0.1;
+            }
+            rendered.fetch_add(output.len(), Ordering::SeqCst);
+            output.len()
+        })
+        .init(&context)
+        .unwrap();
+
+    assert_eq!(
+        stream.stream().start_at(0).unwrap_err(),
+        Error::invalid_parameter()
+    );
+
+    let now_ns = convert_host_time_to_nanos(get_current_host_time());
+    let start = convert_nanos_to_host_time(now_ns + 300_000_000);
+    assert!(stream.stream().start_at(start).is_ok());
+    thread::sleep(Duration::from_millis(100));
+    // The data callback isn't called before the start time.
+    assert_eq!(rendered_frames.load(Ordering::SeqCst), 0);
+    thread::sleep(Duration::from_millis(400));
+    assert!(stream.stop().is_ok());
+    assert!(rendered_frames.load(Ordering::SeqCst) > 0);
+}
+
+#[test]
+fn test_bit_perfect_tone() {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    let device = match test_get_default_device(Scope::Output) {
+        Some(device) => device,
+        None => {
+            println!("No output device.");
+            return;
+        }
+    };
+
+    // The stream must have the rate and the channels of the device.
+    let mut output_params = ffi::cubeb_stream_params::default();
+    output_params.format = ffi::CUBEB_SAMPLE_FLOAT32NE;
+    output_params.rate = get_device_nominal_sample_rate(device).unwrap() as u32;
+    output_params.channels = audiounit_get_channel_count(device, kAudioDevicePropertyScopeOutput);
+    output_params.layout = ffi::CUBEB_LAYOUT_UNDEFINED;
+    output_params.prefs = ffi::CUBEB_STREAM_PREF_NONE;
+
+    fn init(
+        context: &AudioUnitContext,
+        params: ffi::cubeb_stream_params,
+        rendered: Arc<Mutex<Vec<f32>>>,
+    ) -> Result<TypedStream<f32>> {
+        StreamBuilder::<f32>::new()
+            .output(ptr::null_mut(), StreamParams::from(params))
+            .latency(512)
+            .options(StreamOptions {
+                bit_perfect: true,
+                meter_window: Some(Duration::from_millis(20)),
+                ..Default::default()
+            })
+            .data_callback(move |_, output| {
+                for (i, sample) in output.iter_mut().enumerate() {
+                    *sample = if i % 2 == 0 { 0.125 } else { -0.125 };
+                }
+                if let Ok(mut rendered) = rendered.try_lock() {
+                    rendered.clear();
+                    rendered.extend_from_slice(output);
+                }
+                output.len()
+            })
+            .init(context)
+    }
+
+    // Another rate would need the resampler.
+    let mut other_params = output_params;
+    other_params.rate = output_params.rate / 2;
+    let context = AudioUnitContext::new();
+    assert_eq!(
+        init(&context, other_params, Arc::new(Mutex::new(Vec::new()))).unwrap_err(),
+        Error::invalid_format()
+    );
+
+    let rendered = Arc::new(Mutex::new(Vec::new()));
+    let mut stream = match init(&context, output_params, rendered.clone()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            // The device may not run in float samples.
+            assert_eq!(e, Error::invalid_format());
+            println!("The output device doesn't take the stream format as it is.");
+            return;
+        }
+    };
+    assert_eq!(
+        stream.stream().set_volume(0.5).unwrap_err(),
+        Error::not_supported()
+    );
+    assert_eq!(
+        stream.stream().set_panning(0.5).unwrap_err(),
+        Error::not_supported()
+    );
+    assert!(stream.stream().passes_through());
+
+    let meter = stream.stream().output_level_meter().unwrap();
+    assert!(stream.start().is_ok());
+    thread::sleep(Duration::from_millis(200));
+    assert!(stream.stop().is_ok());
+
+    // The device gets exactly the rendered samples.
+    assert!(!rendered.lock().unwrap().is_empty());
+    for level in meter.levels() {
+        assert_eq!(level.peak, 0.125);
+        assert_eq!(level.rms, 0.125);
+    }
+    drop(stream);
+
+    // The stream matching the device passes through without asking for it, and keeps the
+    // panning.
+    let mut stream = StreamBuilder::<f32>::new()
+        .output(ptr::null_mut(), StreamParams::from(output_params))
+        .latency(512)
+        .data_callback(|_, output| output.len())
+        .init(&context)
+        .unwrap();
+    assert!(stream.stream().passes_through());
+    if output_params.channels == 2 {
+        assert!(stream.stream().set_panning(0.5).is_ok());
+    }
+    drop(stream);
+    let mut stream = StreamBuilder::<f32>::new()
+        .output(ptr::null_mut(), StreamParams::from(other_params))
+        .latency(512)
+        .data_callback(|_, output| output.len())
+        .init(&context)
+        .unwrap();
+    assert!(!stream.stream().passes_through());
+}
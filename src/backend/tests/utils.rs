@@ -965,13 +965,12 @@ pub fn test_get_default_raw_stream<F>(operation: F)
 where
     F: FnOnce(&mut AudioUnitStream),
 {
-    test_get_raw_stream(ptr::null_mut(), None, None, 0, operation);
+    test_get_raw_stream(ptr::null_mut(), StreamCallbacks::default(), 0, operation);
 }
 
 fn test_get_raw_stream<F>(
     user_ptr: *mut c_void,
-    data_callback: ffi::cubeb_data_callback,
-    state_callback: ffi::cubeb_state_callback,
+    callbacks: StreamCallbacks,
     latency_frames: u32,
     operation: F,
 ) where
@@ -981,15 +980,10 @@ fn test_get_raw_stream<F>(
 
     // Add a stream to the context since we are about to create one.
     // AudioUnitStream::drop() will check the context has at least one stream.
-    let global_latency_frames = context.update_latency_by_adding_stream(latency_frames);
-
-    let mut stream = AudioUnitStream::new(
-        &mut context,
-        user_ptr,
-        data_callback,
-        state_callback,
-        global_latency_frames.unwrap(),
-    );
+    let latency = context.update_latency_by_adding_stream(&[], latency_frames);
+
+    let mut stream = AudioUnitStream::new(&mut context, user_ptr, latency);
+    stream.callbacks = callbacks;
     stream.core_stream_data = CoreStreamData::new(&stream, None, None);
 
     operation(&mut stream);
@@ -0,0 +1,572 @@
+use super::blocking_stream::Sample;
+use super::coreaudio_sys_utils::sys::{dispatch_async_f, dispatch_queue_t};
+use super::ring_buffer::*;
+use super::{AudioUnitContext, AudioUnitStream, StreamCallbacks, StreamOptions};
+use cubeb_backend::{DeviceId, DeviceType, Error, Result, State, StreamOps, StreamParams};
+use std::cmp;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::c_void;
+use std::pin::Pin;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{self, Poll, Waker};
+use std::time::Duration;
+
+// Get the interleaved input frames and fill the interleaved output frames. Return the number of
+// the output frames rendered. Returning less than the frames asked drains the stream.
+pub type DataCallback<T> = dyn FnMut(&[T], &mut [T]) -> usize + Send;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamEvent {
+    State(State),
+    DeviceChanged,
+    // The nominal sample rate of the device of this side changed to this rate.
+    SampleRateChanged(DeviceType, u32),
+}
+
+// The state events pushed by the audio thread before the readers pick them up.
+const STATE_EVENTS_CAPACITY: usize = 16;
+
+#[derive(Debug)]
+struct EventQueue {
+    queue: Mutex<PendingEvents>,
+    condvar: Condvar,
+    // Set when the state events are pushed without the lock. The waiters are woken by whoever
+    // takes the lock next, or by the serial queue.
+    wake_pending: AtomicBool,
+    next_waker_id: AtomicUsize,
+    // The serial queue of the context, waking the waiters for the state callback.
+    serial_queue: dispatch_queue_t,
+    wake_scheduled: AtomicBool,
+}
+
+// The serial queue can be used from any thread.
+unsafe impl Send for EventQueue {}
+unsafe impl Sync for EventQueue {}
+
+#[derive(Debug)]
+struct PendingEvents {
+    events: VecDeque<StreamEvent>,
+    state_events: Consumer<Option<StreamEvent>>,
+    // One waker per pending NextEvent.
+    wakers: Vec<(usize, Waker)>,
+}
+
+impl PendingEvents {
+    fn pop_front(&mut self) -> Option<StreamEvent> {
+        let mut event = [None];
+        while self.state_events.pop(&mut event) > 0 {
+            self.events.extend(event[0].take());
+        }
+        self.events.pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.events.is_empty() && self.state_events.available() == 0
+    }
+}
+
+// Push the state events from the state callback, which may run on the audio thread. It neither
+// blocks nor allocates, and drops the events if the readers are too far behind. The waiters may
+// run any code when they are woken, so they are woken from the serial queue instead.
+struct StateEventSender {
+    queue: Arc<EventQueue>,
+    producer: Producer<Option<StreamEvent>>,
+}
+
+impl StateEventSender {
+    fn push(&mut self, state: State) {
+        if self.producer.push(&[Some(StreamEvent::State(state))]) == 0 {
+            return;
+        }
+        self.queue.wake_pending.store(true, Ordering::SeqCst);
+        if self.queue.wake_scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Hand a reference to the serial queue without boxing a closure.
+        let queue = Arc::into_raw(self.queue.clone()) as *mut c_void;
+        unsafe {
+            dispatch_async_f(self.queue.serial_queue, queue, Some(wake_on_serial_queue));
+        }
+    }
+}
+
+extern "C" fn wake_on_serial_queue(queue: *mut c_void) {
+    let event_queue = unsafe { Arc::from_raw(queue as *const EventQueue) };
+    event_queue.wake_scheduled.store(false, Ordering::SeqCst);
+    let mut queue = event_queue.queue.lock().unwrap();
+    event_queue.wake(&mut queue);
+}
+
+impl EventQueue {
+    fn new(serial_queue: dispatch_queue_t) -> (Arc<Self>, StateEventSender) {
+        let (producer, consumer) = ring_buffer(STATE_EVENTS_CAPACITY);
+        let queue = Arc::new(Self {
+            queue: Mutex::new(PendingEvents {
+                events: VecDeque::new(),
+                state_events: consumer,
+                wakers: Vec::new(),
+            }),
+            condvar: Condvar::new(),
+            wake_pending: AtomicBool::new(false),
+            next_waker_id: AtomicUsize::new(0),
+            serial_queue,
+            wake_scheduled: AtomicBool::new(false),
+        });
+        let sender = StateEventSender {
+            queue: queue.clone(),
+            producer,
+        };
+        (queue, sender)
+    }
+
+    fn push(&self, event: StreamEvent) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.events.push_back(event);
+        self.wake_pending.store(true, Ordering::SeqCst);
+        self.wake(&mut queue);
+    }
+
+    fn wake(&self, queue: &mut PendingEvents) {
+        if self.wake_pending.swap(false, Ordering::SeqCst) {
+            for (_, waker) in queue.wakers.drain(..) {
+                waker.wake();
+            }
+            self.condvar.notify_all();
+        }
+    }
+
+    // Unlock the queue, and wake the waiters if a state event was pushed while it was locked.
+    fn release(&self, queue: MutexGuard<PendingEvents>) {
+        drop(queue);
+        if self.wake_pending.load(Ordering::SeqCst) {
+            let mut queue = self.queue.lock().unwrap();
+            self.wake(&mut queue);
+        }
+    }
+}
+
+// The events of a stream. They can be awaited by any executor, or waited from a plain thread.
+// Every event is only delivered once, even if there are several Events of the same stream. The
+// state events may be delivered out of order with the other events.
+#[derive(Clone, Debug)]
+pub struct Events {
+    queue: Arc<EventQueue>,
+}
+
+impl Events {
+    pub fn try_next(&self) -> Option<StreamEvent> {
+        let mut queue = self.queue.queue.lock().unwrap();
+        let event = queue.pop_front();
+        self.queue.release(queue);
+        event
+    }
+
+    // Block the current thread until the next event, or return None if the timeout expires.
+    pub fn wait(&self, timeout: Duration) -> Option<StreamEvent> {
+        let queue = self.queue.queue.lock().unwrap();
+        let (mut queue, _) = self
+            .queue
+            .condvar
+            .wait_timeout_while(queue, timeout, |queue| queue.is_empty())
+            .unwrap();
+        let event = queue.pop_front();
+        self.queue.release(queue);
+        event
+    }
+
+    pub fn next(&self) -> NextEvent {
+        NextEvent {
+            events: self,
+            waker_id: self.queue.next_waker_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NextEvent<'a> {
+    events: &'a Events,
+    waker_id: usize,
+}
+
+impl<'a> Future for NextEvent<'a> {
+    type Output = StreamEvent;
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<StreamEvent> {
+        let event_queue = &self.events.queue;
+        let mut queue = event_queue.queue.lock().unwrap();
+        let result = match queue.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                // Keep the latest waker only.
+                match queue.wakers.iter_mut().find(|(id, _)| *id == self.waker_id) {
+                    Some((_, waker)) => {
+                        if !waker.will_wake(cx.waker()) {
+                            *waker = cx.waker().clone();
+                        }
+                    }
+                    None => queue.wakers.push((self.waker_id, cx.waker().clone())),
+                }
+                Poll::Pending
+            }
+        };
+        event_queue.release(queue);
+        result
+    }
+}
+
+impl<'a> Drop for NextEvent<'a> {
+    fn drop(&mut self) {
+        let mut queue = self.events.queue.queue.lock().unwrap();
+        let waker_id = self.waker_id;
+        queue.wakers.retain(|(id, _)| *id != waker_id);
+        self.events.queue.release(queue);
+    }
+}
+
+// Build a stream calling back a closure with the typed samples, instead of the C callbacks.
+pub struct StreamBuilder<T: Sample> {
+    input: Option<(DeviceId, StreamParams)>,
+    output: Option<(DeviceId, StreamParams)>,
+    latency_frames: u32,
+    options: StreamOptions,
+    data_callback: Option<Box<DataCallback<T>>>,
+}
+
+impl<T: Sample> Default for StreamBuilder<T> {
+    fn default() -> Self {
+        Self {
+            input: None,
+            output: None,
+            latency_frames: 0,
+            options: StreamOptions::default(),
+            data_callback: None,
+        }
+    }
+}
+
+impl<T: Sample> StreamBuilder<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Use the default input device if `device` is null.
+    pub fn input(mut self, device: DeviceId, params: StreamParams) -> Self {
+        self.input = Some((device, params));
+        self
+    }
+
+    // Use the default output device if `device` is null.
+    pub fn output(mut self, device: DeviceId, params: StreamParams) -> Self {
+        self.output = Some((device, params));
+        self
+    }
+
+    pub fn latency(mut self, latency_frames: u32) -> Self {
+        self.latency_frames = latency_frames;
+        self
+    }
+
+    pub fn options(mut self, options: StreamOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn data_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&[T], &mut [T]) -> usize + Send + 'static,
+    {
+        self.data_callback = Some(Box::new(callback));
+        self
+    }
+
+    pub fn init(self, context: &AudioUnitContext) -> Result<TypedStream<T>> {
+        let data_callback = self.data_callback.ok_or_else(|| {
+            cubeb_log!("A data callback is required to create a stream.");
+            Error::invalid_parameter()
+        })?;
+        if self.input.is_none() && self.output.is_none() {
+            return Err(Error::invalid_parameter());
+        }
+        for (_, params) in self.input.iter().chain(self.output.iter()) {
+            if !T::is_compatible(params.format()) {
+                cubeb_log!(
+                    "The sample type doesn't match the format {:?}.",
+                    params.format()
+                );
+                return Err(Error::invalid_format());
+            }
+        }
+
+        let (events, state_events) = EventQueue::new(context.serial_queue);
+        let callback_data = TypedCallbackData {
+            data_callback,
+            input_channels: self
+                .input
+                .as_ref()
+                .map_or(0, |(_, params)| params.channels() as usize),
+            output_channels: self
+                .output
+                .as_ref()
+                .map_or(0, |(_, params)| params.channels() as usize),
+        };
+
+        let stream = context.create_stream(
+            self.input
+                .as_ref()
+                .map_or(ptr::null_mut(), |(device, _)| *device),
+            self.input.as_ref().map(|(_, params)| &**params),
+            self.output
+                .as_ref()
+                .map_or(ptr::null_mut(), |(device, _)| *device),
+            self.output.as_ref().map(|(_, params)| &**params),
+            self.latency_frames,
+            |_| callback_data.into_callbacks(state_events),
+            ptr::null_mut(),
+            self.options,
+        )?;
+        let device_events = events.clone();
+        stream.set_device_changed_callback(Some(Box::new(move || {
+            device_events.push(StreamEvent::DeviceChanged)
+        })))?;
+        let rate_events = events.clone();
+        stream.set_sample_rate_changed_callback(Some(Box::new(move |side, rate| {
+            rate_events.push(StreamEvent::SampleRateChanged(side, rate))
+        })))?;
+
+        Ok(TypedStream {
+            stream,
+            events: Events { queue: events },
+            sample: PhantomData,
+        })
+    }
+}
+
+// Owned by the callbacks of the stream.
+struct TypedCallbackData<T: Sample> {
+    data_callback: Box<DataCallback<T>>,
+    input_channels: usize,
+    output_channels: usize,
+}
+
+impl<T: Sample> TypedCallbackData<T> {
+    fn into_callbacks(mut self, mut events: StateEventSender) -> StreamCallbacks {
+        StreamCallbacks::new(
+            move |input, output, frames| Ok(self.process(input, output, frames)),
+            move |state| events.push(state),
+        )
+    }
+
+    fn process(&mut self, input: &[u8], output: &mut [u8], frames: usize) -> usize {
+        // The sample type has been checked against the stream format.
+        let input: &[T] = if input.is_empty() {
+            &[]
+        } else {
+            assert_eq!(
+                input.len(),
+                frames * self.input_channels * mem::size_of::<T>()
+            );
+            assert_eq!(input.as_ptr() as usize % mem::align_of::<T>(), 0);
+            unsafe {
+                slice::from_raw_parts(input.as_ptr() as *const T, frames * self.input_channels)
+            }
+        };
+        let output: &mut [T] = if output.is_empty() {
+            &mut []
+        } else {
+            assert_eq!(
+                output.len(),
+                frames * self.output_channels * mem::size_of::<T>()
+            );
+            assert_eq!(output.as_ptr() as usize % mem::align_of::<T>(), 0);
+            unsafe {
+                slice::from_raw_parts_mut(
+                    output.as_mut_ptr() as *mut T,
+                    frames * self.output_channels,
+                )
+            }
+        };
+        cmp::min((self.data_callback)(input, output), frames)
+    }
+}
+
+// A stream exchanging the typed samples with a closure and reporting its events by Events.
+pub struct TypedStream<'ctx, T: Sample> {
+    stream: Box<AudioUnitStream<'ctx>>,
+    events: Events,
+    // The samples are only accessed by the data callback, owned by the stream.
+    sample: PhantomData<T>,
+}
+
+impl<'ctx, T: Sample> TypedStream<'ctx, T> {
+    pub fn stream(&mut self) -> &mut AudioUnitStream<'ctx> {
+        &mut self.stream
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        self.stream.start()
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        self.stream.stop()
+    }
+
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+}
+
+#[cfg(test)]
+fn test_create_counting_waker(counter: &Arc<AtomicUsize>) -> Waker {
+    use std::task::{RawWaker, RawWakerVTable};
+
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, release);
+    unsafe fn clone(ptr: *const ()) -> RawWaker {
+        Arc::increment_strong_count(ptr as *const AtomicUsize);
+        RawWaker::new(ptr, &VTABLE)
+    }
+    unsafe fn wake(ptr: *const ()) {
+        wake_by_ref(ptr);
+        release(ptr);
+    }
+    unsafe fn wake_by_ref(ptr: *const ()) {
+        (*(ptr as *const AtomicUsize)).fetch_add(1, Ordering::SeqCst);
+    }
+    unsafe fn release(ptr: *const ()) {
+        Arc::decrement_strong_count(ptr as *const AtomicUsize);
+    }
+
+    let ptr = Arc::into_raw(counter.clone()) as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(ptr, &VTABLE)) }
+}
+
+#[cfg(test)]
+fn test_with_serial_queue<F>(operation: F)
+where
+    F: FnOnce(dispatch_queue_t),
+{
+    use super::coreaudio_sys_utils::dispatch::{
+        create_dispatch_queue, release_dispatch_queue, DISPATCH_QUEUE_SERIAL,
+    };
+
+    let serial_queue = create_dispatch_queue("typed_stream.tests", DISPATCH_QUEUE_SERIAL);
+    operation(serial_queue);
+    release_dispatch_queue(serial_queue);
+}
+
+#[test]
+fn test_events_future() {
+    test_with_serial_queue(test_events_future_on);
+}
+
+#[cfg(test)]
+fn test_events_future_on(serial_queue: dispatch_queue_t) {
+    use super::coreaudio_sys_utils::dispatch::{async_dispatch, sync_dispatch};
+    use std::sync::mpsc::channel;
+
+    let (queue, mut state_events) = EventQueue::new(serial_queue);
+    let events = Events { queue };
+    let woken = Arc::new(AtomicUsize::new(0));
+    let waker = test_create_counting_waker(&woken);
+    let mut cx = task::Context::from_waker(&waker);
+
+    let mut next = events.next();
+    // Polling again keeps one waker for the future.
+    for _ in 0..3 {
+        assert_eq!(Pin::new(&mut next).poll(&mut cx), Poll::Pending);
+    }
+    assert_eq!(events.queue.queue.lock().unwrap().wakers.len(), 1);
+    assert_eq!(woken.load(Ordering::SeqCst), 0);
+
+    // The waker isn't called on the pushing thread, but later on the serial queue.
+    let (unblock, blocked) = channel::<()>();
+    async_dispatch(serial_queue, move || blocked.recv().unwrap());
+    state_events.push(State::Started);
+    assert_eq!(woken.load(Ordering::SeqCst), 0);
+    unblock.send(()).unwrap();
+    sync_dispatch(serial_queue, || {});
+    assert_eq!(woken.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        Pin::new(&mut next).poll(&mut cx),
+        Poll::Ready(StreamEvent::State(State::Started))
+    );
+    assert!(events.try_next().is_none());
+}
+
+#[test]
+fn test_events_wait() {
+    test_with_serial_queue(test_events_wait_on);
+}
+
+#[cfg(test)]
+fn test_events_wait_on(serial_queue: dispatch_queue_t) {
+    use std::thread;
+
+    let (queue, _) = EventQueue::new(serial_queue);
+    let events = Events { queue };
+    assert!(events.wait(Duration::from_millis(10)).is_none());
+
+    let queue = events.queue.clone();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        queue.push(StreamEvent::DeviceChanged);
+    });
+    assert_eq!(
+        events.wait(Duration::from_secs(10)),
+        Some(StreamEvent::DeviceChanged)
+    );
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_typed_callback_data() {
+    test_with_serial_queue(test_typed_callback_data_on);
+}
+
+#[cfg(test)]
+fn test_typed_callback_data_on(serial_queue: dispatch_queue_t) {
+    let (events, state_events) = EventQueue::new(serial_queue);
+    let data = TypedCallbackData::<f32> {
+        // Copy the stereo input into the output, until 6 frames are rendered.
+        data_callback: Box::new({
+            let mut rendered = 0;
+            move |input: &[f32], output: &mut [f32]| {
+                assert_eq!(input.len(), output.len());
+                let frames = cmp::min(output.len() / 2, 6 - rendered);
+                output[..2 * frames].copy_from_slice(&input[..2 * frames]);
+                rendered += frames;
+                frames
+            }
+        }),
+        input_channels: 2,
+        output_channels: 2,
+    };
+    let mut callbacks = data.into_callbacks(state_events);
+
+    let input: Vec<f32> = (0..8).map(|i| i as f32).collect();
+    let mut output = vec![0.0_f32; 8];
+    let as_bytes = |samples: &[f32]| unsafe {
+        slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 4)
+    };
+    let as_bytes_mut = |samples: &mut [f32]| unsafe {
+        slice::from_raw_parts_mut(samples.as_mut_ptr() as *mut u8, samples.len() * 4)
+    };
+    assert_eq!(
+        (callbacks.data)(as_bytes(&input), as_bytes_mut(&mut output), 4),
+        Ok(4)
+    );
+    assert_eq!(output, input);
+    assert_eq!(
+        (callbacks.data)(as_bytes(&input), as_bytes_mut(&mut output), 4),
+        Ok(2)
+    );
+
+    (callbacks.state)(State::Drained);
+    let events = Events { queue: events };
+    assert_eq!(events.try_next(), Some(StreamEvent::State(State::Drained)));
+    assert!(events.try_next().is_none());
+}
@@ -0,0 +1,377 @@
+use super::coreaudio_sys_utils::sys::*;
+use super::ring_buffer::*;
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// The directory where the streams' audio is captured, if the capture isn't set on the context.
+pub const DEBUG_CAPTURE_DIR_ENV: &str = "CUBEB_COREAUDIO_CAPTURE_DIR";
+
+// The seconds of audio buffered for the writing thread in each track.
+const TRACK_BUFFER_SECONDS: usize = 2;
+const WRITE_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WavFormat {
+    float: bool,
+    bits_per_sample: u16,
+    channels: u16,
+    rate: u32,
+}
+
+impl WavFormat {
+    // Only the little-endian packed linear PCM can be written into a WAV file.
+    pub fn from_description(desc: &AudioStreamBasicDescription) -> Option<Self> {
+        if desc.mFormatID != kAudioFormatLinearPCM
+            || desc.mFormatFlags & kAudioFormatFlagIsBigEndian != 0
+            || desc.mFormatFlags & kLinearPCMFormatFlagIsPacked == 0
+            || desc.mBitsPerChannel == 0
+            || desc.mBitsPerChannel % 8 != 0
+            || desc.mChannelsPerFrame == 0
+            || desc.mSampleRate < 1.0
+        {
+            return None;
+        }
+        Some(Self {
+            float: desc.mFormatFlags & kAudioFormatFlagIsFloat != 0,
+            bits_per_sample: desc.mBitsPerChannel as u16,
+            channels: desc.mChannelsPerFrame as u16,
+            rate: desc.mSampleRate as u32,
+        })
+    }
+
+    fn frame_size(&self) -> usize {
+        usize::from(self.bits_per_sample / 8) * usize::from(self.channels)
+    }
+}
+
+struct WavWriter {
+    file: BufWriter<File>,
+    frame_size: u32,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    const HEADER_SIZE: u32 = 44;
+    // The RIFF sizes are 32-bit, so the data beyond this is dropped.
+    const MAX_DATA_BYTES: u32 = u32::max_value() - (Self::HEADER_SIZE - 8);
+
+    fn create(path: &Path, format: WavFormat) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = format.frame_size() as u16;
+        file.write_all(b"RIFF")?;
+        // The sizes are updated once all the data is written.
+        file.write_all(&(Self::HEADER_SIZE - 8).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16_u32.to_le_bytes())?;
+        // WAVE_FORMAT_IEEE_FLOAT or WAVE_FORMAT_PCM.
+        file.write_all(&(if format.float { 3_u16 } else { 1_u16 }).to_le_bytes())?;
+        file.write_all(&format.channels.to_le_bytes())?;
+        file.write_all(&format.rate.to_le_bytes())?;
+        file.write_all(&(format.rate * u32::from(block_align)).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&format.bits_per_sample.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0_u32.to_le_bytes())?;
+        Ok(Self {
+            file,
+            frame_size: u32::from(block_align),
+            data_bytes: 0,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        // Only whole frames fit in the file.
+        let available = Self::MAX_DATA_BYTES - self.data_bytes;
+        let available = available - available % self.frame_size;
+        let count = cmp::min(data.len(), available as usize);
+        self.file.write_all(&data[..count])?;
+        self.data_bytes += count as u32;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(Self::HEADER_SIZE - 8 + self.data_bytes).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_bytes.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+// The audio fed on the callback threads for one of the captured files.
+struct CaptureTrack {
+    producer: Producer<u8>,
+    frame_size: usize,
+    dropped_frames: Arc<AtomicUsize>,
+}
+
+impl CaptureTrack {
+    fn push(&mut self, data: &[u8]) {
+        // Drop the whole buffer if the writing thread is running behind, so the file only
+        // contains whole callbacks.
+        if self.producer.available() < data.len() {
+            self.dropped_frames
+                .fetch_add(data.len() / self.frame_size, Ordering::Relaxed);
+            return;
+        }
+        self.producer.push(data);
+    }
+}
+
+// The audio written by the writing thread into one of the captured files.
+struct CaptureFile {
+    consumer: Consumer<u8>,
+    writer: WavWriter,
+    path: PathBuf,
+    dropped_frames: Arc<AtomicUsize>,
+}
+
+impl CaptureFile {
+    // Move all the available data into the file.
+    fn write_available(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        loop {
+            let count = self.consumer.pop(buffer);
+            if count == 0 {
+                return Ok(());
+            }
+            self.writer.write(&buffer[..count])?;
+        }
+    }
+}
+
+// Write the raw input, the output rendered by the data callback and the output given to the
+// device of a stream into WAV files, for debugging. The data is copied into the ring buffers
+// on the callback threads and written into the files on a background thread.
+pub struct DebugCapture {
+    input: Option<CaptureTrack>,
+    callback_output: Option<CaptureTrack>,
+    device_output: Option<CaptureTrack>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DebugCapture {
+    // Create the files `<prefix>-input.wav`, `<prefix>-callback-output.wav` and
+    // `<prefix>-device-output.wav` in `dir`, for the sides having a format.
+    pub fn new(
+        dir: &Path,
+        prefix: &str,
+        input: Option<WavFormat>,
+        callback_output: Option<WavFormat>,
+        device_output: Option<WavFormat>,
+    ) -> io::Result<Self> {
+        let mut files = Vec::new();
+        let mut create_track =
+            |name: &str, format: Option<WavFormat>| -> io::Result<Option<CaptureTrack>> {
+                let format = match format {
+                    Some(format) => format,
+                    None => return Ok(None),
+                };
+                let path = dir.join(format!("{}-{}.wav", prefix, name));
+                let writer = WavWriter::create(&path, format)?;
+                let capacity = format.rate as usize * TRACK_BUFFER_SECONDS * format.frame_size();
+                let (producer, consumer) = ring_buffer::<u8>(capacity);
+                let dropped_frames = Arc::new(AtomicUsize::new(0));
+                files.push(CaptureFile {
+                    consumer,
+                    writer,
+                    path,
+                    dropped_frames: dropped_frames.clone(),
+                });
+                Ok(Some(CaptureTrack {
+                    producer,
+                    frame_size: format.frame_size(),
+                    dropped_frames,
+                }))
+            };
+        let input = create_track("input", input)?;
+        let callback_output = create_track("callback-output", callback_output)?;
+        let device_output = create_track("device-output", device_output)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_writing = stop.clone();
+        let thread = thread::Builder::new()
+            .name(format!("{} capture", prefix))
+            .spawn(move || write_capture_files(files, stop_writing))?;
+
+        Ok(Self {
+            input,
+            callback_output,
+            device_output,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn write_input(&mut self, data: &[u8]) {
+        if let Some(track) = self.input.as_mut() {
+            track.push(data);
+        }
+    }
+
+    pub fn write_callback_output(&mut self, data: &[u8]) {
+        if let Some(track) = self.callback_output.as_mut() {
+            track.push(data);
+        }
+    }
+
+    pub fn write_device_output(&mut self, data: &[u8]) {
+        if let Some(track) = self.device_output.as_mut() {
+            track.push(data);
+        }
+    }
+}
+
+impl Drop for DebugCapture {
+    // Wait until all the captured data is written.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl std::fmt::Debug for DebugCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DebugCapture")
+            .field("input", &self.input.is_some())
+            .field("callback_output", &self.callback_output.is_some())
+            .field("device_output", &self.device_output.is_some())
+            .finish()
+    }
+}
+
+fn write_capture_files(mut files: Vec<CaptureFile>, stop: Arc<AtomicBool>) {
+    let mut buffer = vec![0_u8; 64 * 1024];
+    let mut failed = vec![false; files.len()];
+    loop {
+        // Read the flag before writing, so the data pushed before stopping is all written.
+        let stopping = stop.load(Ordering::SeqCst);
+        for (file, failed) in files.iter_mut().zip(failed.iter_mut()) {
+            if *failed {
+                continue;
+            }
+            if let Err(e) = file.write_available(&mut buffer) {
+                cubeb_log!("Fail to write {}: {}", file.path.display(), e);
+                *failed = true;
+            }
+        }
+        if stopping {
+            break;
+        }
+        thread::sleep(WRITE_INTERVAL);
+    }
+    for file in files {
+        let dropped_frames = file.dropped_frames.load(Ordering::Relaxed);
+        if dropped_frames > 0 {
+            cubeb_log!(
+                "{} frames are missing in {}.",
+                dropped_frames,
+                file.path.display()
+            );
+        }
+        let path = file.path;
+        if let Err(e) = file.writer.finish() {
+            cubeb_log!("Fail to finish {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[test]
+fn test_debug_capture() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir();
+    let prefix = format!("cubeb-test-{}", std::process::id());
+    let format = WavFormat {
+        float: false,
+        bits_per_sample: 16,
+        channels: 2,
+        rate: 48_000,
+    };
+
+    let samples: Vec<i16> = (0..256).collect();
+    let bytes: Vec<u8> = samples
+        .iter()
+        .flat_map(|s| s.to_le_bytes().to_vec())
+        .collect();
+    {
+        let mut capture = DebugCapture::new(&dir, &prefix, Some(format), None, None).unwrap();
+        capture.write_input(&bytes);
+        capture.write_input(&bytes);
+        // No such track.
+        capture.write_device_output(&bytes);
+    }
+
+    let path = dir.join(format!("{}-input.wav", prefix));
+    let content = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(!dir.join(format!("{}-device-output.wav", prefix)).exists());
+
+    assert_eq!(content.len(), 44 + 2 * bytes.len());
+    assert_eq!(&content[0..4], b"RIFF");
+    assert_eq!(&content[4..8], &(36 + 2 * bytes.len() as u32).to_le_bytes());
+    assert_eq!(&content[8..16], b"WAVEfmt ");
+    // PCM, stereo, 48kHz, 16 bits.
+    assert_eq!(&content[20..22], &1_u16.to_le_bytes());
+    assert_eq!(&content[22..24], &2_u16.to_le_bytes());
+    assert_eq!(&content[24..28], &48_000_u32.to_le_bytes());
+    assert_eq!(&content[34..36], &16_u16.to_le_bytes());
+    assert_eq!(&content[36..40], b"data");
+    assert_eq!(&content[40..44], &(2 * bytes.len() as u32).to_le_bytes());
+    assert_eq!(&content[44..44 + bytes.len()], bytes.as_slice());
+}
+
+#[test]
+fn test_wav_format_from_description() {
+    let mut desc = AudioStreamBasicDescription::default();
+    desc.mFormatID = kAudioFormatLinearPCM;
+    desc.mFormatFlags = kAudioFormatFlagIsFloat | kLinearPCMFormatFlagIsPacked;
+    desc.mBitsPerChannel = 32;
+    desc.mChannelsPerFrame = 2;
+    desc.mSampleRate = 44_100.0;
+    assert_eq!(
+        WavFormat::from_description(&desc),
+        Some(WavFormat {
+            float: true,
+            bits_per_sample: 32,
+            channels: 2,
+            rate: 44_100,
+        })
+    );
+
+    desc.mFormatFlags |= kAudioFormatFlagIsBigEndian;
+    assert!(WavFormat::from_description(&desc).is_none());
+}
+
+#[test]
+fn test_wav_writer_size_limit() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("cubeb-test-{}-limit.wav", std::process::id()));
+    let format = WavFormat {
+        float: false,
+        bits_per_sample: 16,
+        channels: 2,
+        rate: 48_000,
+    };
+    let mut writer = WavWriter::create(&path, format).unwrap();
+    // Pretend the file is almost full, instead of writing 4 GiB.
+    writer.data_bytes = WavWriter::MAX_DATA_BYTES - 6;
+    writer.write(&[0; 8]).unwrap();
+    assert_eq!(writer.data_bytes, WavWriter::MAX_DATA_BYTES - 2);
+    writer.write(&[0; 8]).unwrap();
+    assert_eq!(writer.data_bytes, WavWriter::MAX_DATA_BYTES - 2);
+    drop(writer);
+    fs::remove_file(&path).unwrap();
+}
@@ -12,4 +12,11 @@ extern crate cubeb_backend;
 mod backend;
 mod capi;
 
+pub use crate::backend::{
+    AudioUnitContext, AudioUnitStream, BlockingOptions, BlockingStream, BufferedInput,
+    ChannelLevel, DataCallback, DeviceChangedCallback, DeviceInfo, EncodedFormat, EnumerateOptions,
+    Events, LevelMeter, NextEvent, OutputTap, Sample, SampleRateChangedCallback, SampleRateRange,
+    StreamBuilder, StreamCallbacks, StreamDataCallback, StreamEvent, StreamOptions,
+    StreamStateCallback, TapFormat, TypedStream,
+};
 pub use crate::capi::audiounit_rust_init;